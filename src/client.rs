@@ -0,0 +1,381 @@
+//! A typed client for the collection endpoints, sharing its request and response types with the server.
+//! It works against the mock or the real service alike.
+use std::fmt;
+use reqwest::{header::{self, HeaderMap, HeaderValue}, Method, RequestBuilder, StatusCode};
+use serde::{de::DeserializeOwned, Serialize};
+use crate::{
+    fixture::models::{CollectionKind, CollectionResponse, CollectionsResponse},
+    handlers::{identity::USER_ID_HEADER, requests::{CreateCollectionRequest, RecipeIdsRequest, RenameCollectionRequest}, responses::{CollectionChangesResponse, CollectionContentResponse, GenericResponse, ProblemDetails, UpdateResponse}},
+};
+
+/// Everything a call can fail with. Error responses are recognised in both the problem+json and the legacy format.
+#[derive(Debug)]
+pub enum ClientError {
+    /// The request never got an answer
+    Transport(reqwest::Error),
+    /// A successful response whose body wasn't what was expected
+    Decode(serde_json::Error),
+    NotFound(String),
+    BadRequest(String),
+    Conflict(String),
+    /// The collection has changed since the ETag given as `if_match`
+    PreconditionFailed,
+    /// The point asked for changes since is older than the server's history, so the whole collection has to be fetched again
+    HistoryExpired,
+    /// Any other error response
+    Other{ status: StatusCode, detail: String },
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Transport(e)=>write!(f, "request failed: {}", e),
+            ClientError::Decode(e)=>write!(f, "could not understand the response: {}", e),
+            ClientError::NotFound(detail)=>write!(f, "not found: {}", detail),
+            ClientError::BadRequest(detail)=>write!(f, "bad request: {}", detail),
+            ClientError::Conflict(detail)=>write!(f, "conflict: {}", detail),
+            ClientError::PreconditionFailed=>write!(f, "the collection has been modified since the given ETag"),
+            ClientError::HistoryExpired=>write!(f, "the server no longer has the changes asked for"),
+            ClientError::Other{ status, detail }=>write!(f, "{}: {}", status, detail),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(e:reqwest::Error) -> ClientError {
+        ClientError::Transport(e)
+    }
+}
+
+impl ClientError {
+    fn from_response(status:StatusCode, body:&[u8]) -> ClientError {
+        match status {
+            StatusCode::PRECONDITION_FAILED=>return ClientError::PreconditionFailed,
+            StatusCode::GONE=>return ClientError::HistoryExpired,
+            _=>{},
+        }
+
+        if let Ok(problem) = serde_json::from_slice::<ProblemDetails>(body) {
+            // Only the last part of the type is looked at, so a server with a different prefix is understood too
+            let detail = problem.detail.unwrap_or(problem.title);
+            return match problem.problem_type.rsplit(':').next() {
+                Some("collection-not-found" | "route-not-found")=>ClientError::NotFound(detail),
+                Some("bad-request")=>ClientError::BadRequest(detail),
+                Some("conflict")=>ClientError::Conflict(detail),
+                _=>ClientError::Other{ status, detail },
+            }
+        }
+
+        let detail = match serde_json::from_slice::<GenericResponse>(body) {
+            Ok(legacy)=>legacy.detail.unwrap_or(legacy.status),
+            Err(_)=>String::from_utf8_lossy(body).into_owned(),
+        };
+        match status {
+            StatusCode::NOT_FOUND=>ClientError::NotFound(detail),
+            StatusCode::BAD_REQUEST=>ClientError::BadRequest(detail),
+            StatusCode::CONFLICT=>ClientError::Conflict(detail),
+            _=>ClientError::Other{ status, detail },
+        }
+    }
+}
+
+/// Makes a GET conditional, using the validators from an earlier response
+#[derive(Debug, Clone, Default)]
+pub struct Conditions {
+    pub if_none_match: Option<String>,
+    /// An HTTP date, as found in Last-Modified
+    pub if_modified_since: Option<String>,
+}
+
+impl Conditions {
+    /// Only fetch the resource again if it has changed since `earlier`
+    pub fn since<T>(earlier:&Versioned<T>) -> Conditions {
+        Conditions{
+            if_none_match: earlier.etag.clone(),
+            if_modified_since: earlier.last_modified.clone(),
+        }
+    }
+}
+
+/// A response body along with the validators that came with it
+#[derive(Debug)]
+pub struct Versioned<T> {
+    pub body: T,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+/// The outcome of a conditional GET
+#[derive(Debug)]
+pub enum Fetched<T> {
+    Modified(Versioned<T>),
+    /// 304: the copy the conditions came from is still current
+    NotModified,
+}
+
+/// Which page of a collection's contents to get. The default is the server's default-sized first page.
+#[derive(Debug, Clone, Default)]
+pub struct PageQuery {
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+    /// `nextCursor` or `prevCursor` from an earlier page. Can't be combined with `offset`.
+    pub cursor: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Client {
+    /// Without a trailing slash
+    base: String,
+    http: reqwest::Client,
+    user_id: Option<String>,
+}
+
+fn header_string(headers:&HeaderMap, name:header::HeaderName) -> Option<String> {
+    headers.get(name).and_then(|v| v.to_str().ok()).map(|v| v.to_owned())
+}
+
+impl Client {
+    /// `base_url` is where the endpoints live, e.g. `http://localhost:9000`
+    pub fn new(base_url:&str) -> Client {
+        Client{
+            base: base_url.trim_end_matches('/').to_owned(),
+            http: reqwest::Client::new(),
+            user_id: None,
+        }
+    }
+
+    /// Makes every request as this user, with X-User-Id
+    pub fn as_user(mut self, user_id:&str) -> Client {
+        self.user_id = Some(user_id.to_owned());
+        self
+    }
+
+    fn request(&self, method:Method, path:&str) -> RequestBuilder {
+        let request = self.http.request(method, format!("{}{}", self.base, path));
+        match &self.user_id {
+            Some(user_id)=>request.header(USER_ID_HEADER, user_id),
+            None=>request,
+        }
+    }
+
+    fn with_json<B:Serialize>(request:RequestBuilder, body:&B) -> Result<RequestBuilder, ClientError> {
+        let body = serde_json::to_vec(body).map_err(ClientError::Decode)?;
+        Ok(request.header(header::CONTENT_TYPE, HeaderValue::from_static("application/json")).body(body))
+    }
+
+    /// Sends the request, turning error statuses into `ClientError`s. 304 is not treated as an error.
+    async fn send(request:RequestBuilder) -> Result<(StatusCode, HeaderMap, Vec<u8>), ClientError> {
+        let response = request.send().await?;
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = response.bytes().await?.to_vec();
+
+        if status.is_success() || status==StatusCode::NOT_MODIFIED {
+            Ok((status, headers, body))
+        } else {
+            Err(ClientError::from_response(status, &body))
+        }
+    }
+
+    async fn fetch<T:DeserializeOwned>(&self, request:RequestBuilder, conditions:&Conditions) -> Result<Fetched<T>, ClientError> {
+        let mut request = request;
+        if let Some(etag) = &conditions.if_none_match {
+            request = request.header(header::IF_NONE_MATCH, etag);
+        }
+        if let Some(date) = &conditions.if_modified_since {
+            request = request.header(header::IF_MODIFIED_SINCE, date);
+        }
+
+        let (status, headers, body) = Client::send(request).await?;
+        if status==StatusCode::NOT_MODIFIED {
+            return Ok(Fetched::NotModified)
+        }
+
+        Ok(Fetched::Modified(Versioned{
+            body: serde_json::from_slice(&body).map_err(ClientError::Decode)?,
+            etag: header_string(&headers, header::ETAG),
+            last_modified: header_string(&headers, header::LAST_MODIFIED),
+        }))
+    }
+
+    pub async fn collections(&self, conditions:&Conditions) -> Result<Fetched<CollectionsResponse>, ClientError> {
+        self.fetch(self.request(Method::GET, "/collection"), conditions).await
+    }
+
+    pub async fn create_collection(&self, kind:CollectionKind, name:Option<&str>) -> Result<CollectionResponse, ClientError> {
+        let request = Client::with_json(self.request(Method::POST, "/collection"), &CreateCollectionRequest{
+            kind,
+            name: name.map(|n| n.to_owned()),
+        })?;
+        let (_, _, body) = Client::send(request).await?;
+        serde_json::from_slice(&body).map_err(ClientError::Decode)
+    }
+
+    /// A name of `None` removes the collection's name
+    pub async fn rename_collection(&self, collection_id:&str, name:Option<&str>) -> Result<CollectionResponse, ClientError> {
+        let request = Client::with_json(self.request(Method::PATCH, &format!("/collection/{}", collection_id)), &RenameCollectionRequest{
+            name: name.map(|n| n.to_owned()),
+        })?;
+        let (_, _, body) = Client::send(request).await?;
+        serde_json::from_slice(&body).map_err(ClientError::Decode)
+    }
+
+    pub async fn delete_collection(&self, collection_id:&str) -> Result<(), ClientError> {
+        Client::send(self.request(Method::DELETE, &format!("/collection/{}", collection_id))).await?;
+        Ok( () )
+    }
+
+    /// One page of a collection's contents
+    pub async fn contents(&self, collection_id:&str, page:&PageQuery, conditions:&Conditions) -> Result<Fetched<CollectionContentResponse>, ClientError> {
+        let mut query:Vec<(&str, String)> = Vec::new();
+        if let Some(limit) = page.limit {
+            query.push(("limit", limit.to_string()));
+        }
+        if let Some(offset) = page.offset {
+            query.push(("offset", offset.to_string()));
+        }
+        if let Some(cursor) = &page.cursor {
+            query.push(("cursor", cursor.to_owned()));
+        }
+
+        let request = self.request(Method::GET, &format!("/collection/{}/contents", collection_id)).query(&query);
+        self.fetch(request, conditions).await
+    }
+
+    /// Every recipe in a collection, following `nextCursor` through pages of `page_size`
+    pub async fn all_contents(&self, collection_id:&str, page_size:usize) -> Result<Vec<String>, ClientError> {
+        let mut recipes:Vec<String> = Vec::new();
+        let mut page = PageQuery{
+            limit: Some(page_size),
+            ..PageQuery::default()
+        };
+
+        loop {
+            let Fetched::Modified(fetched) = self.contents(collection_id, &page, &Conditions::default()).await? else {
+                // Can't happen without conditions, but there's nothing more to read either way
+                return Ok(recipes)
+            };
+            recipes.extend(fetched.body.content);
+            match fetched.body.next_cursor {
+                Some(cursor)=>page.cursor = Some(cursor),
+                None=>return Ok(recipes),
+            }
+        }
+    }
+
+    /// What has been added to and removed from a collection since `since`, which is either the `version` from an earlier
+    /// call or an RFC 3339 timestamp. Fails with `HistoryExpired` if that's too long ago.
+    pub async fn changes(&self, collection_id:&str, since:&str) -> Result<CollectionChangesResponse, ClientError> {
+        let request = self.request(Method::GET, &format!("/collection/{}/changes", collection_id)).query(&[("since", since)]);
+        let (_, _, body) = Client::send(request).await?;
+        serde_json::from_slice(&body).map_err(ClientError::Decode)
+    }
+
+    /// Adds recipes to a collection. With `if_match`, fails with `PreconditionFailed` if the collection has changed since that ETag.
+    pub async fn add(&self, collection_id:&str, recipe_ids:&[&str], if_match:Option<&str>) -> Result<Versioned<UpdateResponse>, ClientError> {
+        let (headers, body) = self.change_contents(Method::PUT, collection_id, recipe_ids, if_match).await?;
+        Ok(Versioned{
+            body: serde_json::from_slice(&body).map_err(ClientError::Decode)?,
+            etag: header_string(&headers, header::ETAG),
+            last_modified: header_string(&headers, header::LAST_MODIFIED),
+        })
+    }
+
+    /// Removes recipes from a collection, returning its new ETag. `if_match` works as for `add`.
+    pub async fn remove(&self, collection_id:&str, recipe_ids:&[&str], if_match:Option<&str>) -> Result<Option<String>, ClientError> {
+        let (headers, _) = self.change_contents(Method::DELETE, collection_id, recipe_ids, if_match).await?;
+        Ok(header_string(&headers, header::ETAG))
+    }
+
+    async fn change_contents(&self, method:Method, collection_id:&str, recipe_ids:&[&str], if_match:Option<&str>) -> Result<(HeaderMap, Vec<u8>), ClientError> {
+        let mut request = Client::with_json(self.request(method, &format!("/collection/{}/contents", collection_id)), &RecipeIdsRequest{
+            ids: recipe_ids.iter().map(|id| id.to_string()).collect(),
+        })?;
+        if let Some(etag) = if_match {
+            request = request.header(header::IF_MATCH, etag);
+        }
+
+        let (_, headers, body) = Client::send(request).await?;
+        Ok((headers, body))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{fixture::{loader::{FixtureCollection, FixtureFile}, Environment}, handlers::errors::ErrorFormat, MockServer};
+    use super::*;
+
+    fn fixture() -> FixtureFile {
+        FixtureFile{
+            collections: vec![FixtureCollection{
+                id: "c1".into(),
+                kind: CollectionKind::Saved,
+                name: None,
+                recipes: (1..=5).map(|n| format!("r{}", n)).collect(),
+                last_modified: None,
+            }],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_collections_and_contents() {
+        let server = MockServer::builder().env(Environment::CODE).fixture(fixture()).start().await.unwrap();
+        let client = Client::new(&server.url()).as_user("alice");
+
+        let Fetched::Modified(collections) = client.collections(&Conditions::default()).await.unwrap() else {
+            panic!("an unconditional GET should always get a body")
+        };
+        assert_eq!(collections.body.collections[0].id, "c1");
+        assert!(collections.etag.is_some());
+        assert!(matches!(client.collections(&Conditions::since(&collections)).await, Ok(Fetched::NotModified)));
+
+        let page = PageQuery{ limit: Some(2), ..PageQuery::default() };
+        let Ok(Fetched::Modified(first)) = client.contents("c1", &page, &Conditions::default()).await else {
+            panic!("the first page should be there")
+        };
+        assert_eq!(first.body.content, vec!["r1", "r2"]);
+        assert!(first.body.has_more);
+
+        assert_eq!(client.all_contents("c1", 2).await.unwrap(), vec!["r1", "r2", "r3", "r4", "r5"]);
+        assert!(matches!(client.all_contents("missing", 2).await, Err(ClientError::NotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn test_changes_and_errors() {
+        let server = MockServer::builder().env(Environment::CODE).fixture(fixture()).start().await.unwrap();
+        let client = Client::new(&server.url()).as_user("bob");
+
+        let added = client.add("c1", &["r6", "r1"], None).await.unwrap();
+        assert_eq!(added.body.added, vec!["r6"]);
+        assert_eq!(added.body.already_present, vec!["r1"]);
+        let etag = added.etag.unwrap();
+
+        let removed = client.remove("c1", &["r6"], Some(&etag)).await.unwrap();
+        assert!(removed.is_some_and(|new_etag| new_etag!=etag));
+        let changes = client.changes("c1", "1").await.unwrap();
+        assert_eq!(changes.removed[0].id, "r6");
+        assert_eq!(changes.version, 3);
+        assert!(matches!(client.changes("c1", "0").await, Err(ClientError::HistoryExpired)));
+        assert!(matches!(client.add("c1", &["r7"], Some(&etag)).await, Err(ClientError::PreconditionFailed)));
+        assert!(matches!(client.add("c1", &[], None).await, Err(ClientError::BadRequest(_))));
+
+        let created = client.create_collection(CollectionKind::UserCreated, Some("Puddings")).await.unwrap();
+        assert_eq!(client.rename_collection(&created.id, None).await.unwrap().name, None);
+        assert!(matches!(client.create_collection(CollectionKind::Saved, None).await, Err(ClientError::Conflict(_))));
+        client.delete_collection(&created.id).await.unwrap();
+        assert!(matches!(client.delete_collection(&created.id).await, Err(ClientError::NotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn test_legacy_errors() {
+        let server = MockServer::builder().fixture(fixture()).error_format(ErrorFormat::Legacy).start().await.unwrap();
+        let client = Client::new(&server.url());
+
+        match client.delete_collection("missing").await {
+            Err(ClientError::NotFound(detail))=>assert_eq!(detail, "That collection ID does not exist"),
+            other=>panic!("expected NotFound, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,121 @@
+//! What the middleware and the files given on the command line have in common
+use std::{fmt, fs, io, path::{Path, PathBuf}};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Request bodies bigger than this are turned away with a 400 by any middleware that has to read them
+pub const MAX_BODY_BYTES:usize = 10 * 1024 * 1024;
+
+/// Whether a request is for the admin API, which the middleware standing in for the real API's behaviour leaves alone
+pub fn is_admin_path(path:&str) -> bool {
+    path=="/__admin" || path.starts_with("/__admin/")
+}
+
+/// A fixture, fault config, stub file or contract that couldn't be read or written
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    UnknownFormat(PathBuf),
+    Json(PathBuf, serde_json::Error),
+    Toml(PathBuf, toml::de::Error),
+    TomlWrite(PathBuf, toml::ser::Error),
+    Yaml(PathBuf, serde_yaml::Error),
+    /// Parsed, but makes no sense
+    Invalid(PathBuf, Vec<String>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e)=>write!(f, "could not access {}: {}", path.display(), e),
+            ConfigError::UnknownFormat(path)=>write!(f, "{} must have a .json, .toml, .yaml or .yml extension", path.display()),
+            ConfigError::Json(path, e)=>write!(f, "{} is not valid JSON: {}", path.display(), e),
+            ConfigError::Toml(path, e)=>write!(f, "{} is not valid TOML: {}", path.display(), e),
+            ConfigError::TomlWrite(path, e)=>write!(f, "could not write {} as TOML: {}", path.display(), e),
+            ConfigError::Yaml(path, e)=>write!(f, "{} is not valid YAML: {}", path.display(), e),
+            ConfigError::Invalid(path, problems)=>write!(f, "{} failed validation:\n\t{}", path.display(), problems.join("\n\t")),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl ConfigError {
+    /// Turns a validator's list of problems into an error, if there are any
+    pub fn check(path:&Path, problems:Vec<String>) -> Result<(), ConfigError> {
+        if problems.is_empty() {
+            Ok( () )
+        } else {
+            Err(ConfigError::Invalid(path.to_owned(), problems))
+        }
+    }
+}
+
+/// Reads and parses the file at `path` as JSON, TOML or YAML, going by its extension
+pub fn load_config<T:DeserializeOwned>(path:&Path) -> Result<T, ConfigError> {
+    let content = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_owned(), e))?;
+
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("json")=>serde_json::from_str(&content).map_err(|e| ConfigError::Json(path.to_owned(), e)),
+        Some("toml")=>toml::from_str(&content).map_err(|e| ConfigError::Toml(path.to_owned(), e)),
+        Some("yaml") | Some("yml")=>serde_yaml::from_str(&content).map_err(|e| ConfigError::Yaml(path.to_owned(), e)),
+        _=>Err(ConfigError::UnknownFormat(path.to_owned())),
+    }
+}
+
+/// Writes `value` out in whichever of the formats `load_config` reads the extension of `path` calls for
+pub fn save_config<T:Serialize>(path:&Path, value:&T) -> Result<(), ConfigError> {
+    let content = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("json")=>serde_json::to_string_pretty(value).map_err(|e| ConfigError::Json(path.to_owned(), e))?,
+        Some("toml")=>toml::to_string_pretty(value).map_err(|e| ConfigError::TomlWrite(path.to_owned(), e))?,
+        Some("yaml") | Some("yml")=>serde_yaml::to_string(value).map_err(|e| ConfigError::Yaml(path.to_owned(), e))?,
+        _=>return Err(ConfigError::UnknownFormat(path.to_owned())),
+    };
+
+    fs::write(path, content).map_err(|e| ConfigError::Io(path.to_owned(), e))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, path::PathBuf};
+    use crate::fixture::loader::FixtureFile;
+    use super::*;
+
+    fn temp_path(name:&str) -> PathBuf {
+        std::env::temp_dir().join(format!("{}-config-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn test_is_admin_path() {
+        assert!(is_admin_path("/__admin"));
+        assert!(is_admin_path("/__admin/stubs"));
+        assert!(!is_admin_path("/__administrator"));
+        assert!(!is_admin_path("/collection"));
+    }
+
+    #[test]
+    fn test_formats() {
+        let fixture = FixtureFile::builtin(&crate::fixture::Environment::PROD);
+
+        for name in ["fixture.json", "fixture.toml", "fixture.yaml"] {
+            let path = temp_path(name);
+            save_config(&path, &fixture).unwrap();
+            let loaded:FixtureFile = load_config(&path).unwrap();
+            fs::remove_file(&path).unwrap();
+            assert_eq!(loaded.collections.len(), fixture.collections.len(), "{}", name);
+        }
+
+        let unknown = temp_path("fixture.txt");
+        assert!(matches!(save_config(&unknown, &fixture), Err(ConfigError::UnknownFormat(_))));
+        assert!(matches!(load_config::<FixtureFile>(&unknown), Err(ConfigError::Io(_, _))));
+    }
+
+    #[test]
+    fn test_check() {
+        let path = Path::new("faults.toml");
+        assert!(ConfigError::check(path, Vec::new()).is_ok());
+        match ConfigError::check(path, vec!["rule #0: path 'x' must start with /".into()]) {
+            Err(e)=>assert_eq!(e.to_string(), "faults.toml failed validation:\n\trule #0: path 'x' must start with /"),
+            Ok(())=>panic!("problems should have made the check fail"),
+        }
+    }
+}
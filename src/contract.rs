@@ -0,0 +1,651 @@
+use std::{collections::HashMap, path::Path, sync::Arc};
+use axum::{body::{self, Body, Bytes}, extract::{Query, Request, State}, http::{header, HeaderMap, Method, StatusCode}, middleware::Next, response::{IntoResponse, Response}};
+use futures_util::StreamExt;
+use jsonschema::{Draft, Validator};
+use serde_json::Value;
+use crate::{config::{is_admin_path, load_config, ConfigError, MAX_BODY_BYTES}, events::is_event_stream, faults::{cut_short_body, path_matches, ConnectionDropped}, handlers::errors::{ApiError, ErrorFormat}};
+
+/// What the contract document is known as when schemas refer into it
+const CONTRACT_URI:&str = "urn:contract";
+
+/// Only this many schema errors are reported for one body, as one mistake tends to cause a cascade
+const MAX_SCHEMA_ERRORS:usize = 5;
+
+/// Endpoints that only the mock has. They are checked if the contract describes them, but otherwise left alone,
+/// as the real service's contract can't be expected to.
+const MOCK_ONLY_PATHS:[&str; 4] = [
+    "/collection/events",
+    "/collection/{collection_id}/changes",
+    "/collection/{collection_id}/events",
+    "/ws",
+];
+
+/// The operations of an OpenAPI 3.x document, with their schemas compiled ready to check requests and responses against
+pub struct Contract {
+    operations: Vec<Operation>,
+}
+
+struct Operation {
+    method: Method,
+    /// The path template, e.g. `/collection/{collection_id}/contents`
+    path: String,
+    parameters: Vec<Parameter>,
+    body: Option<RequestBody>,
+    /// Keyed by status code, `4XX`-style range or `default`
+    responses: HashMap<String, Vec<MediaType>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ParameterLocation {
+    Path,
+    Query,
+    Header,
+    Cookie,
+}
+
+struct Parameter {
+    name: String,
+    location: ParameterLocation,
+    required: bool,
+    schema: Option<Validator>,
+}
+
+struct RequestBody {
+    required: bool,
+    content: Vec<MediaType>,
+}
+
+struct MediaType {
+    /// May be a wildcard like `application/*`
+    mime: String,
+    schema: Option<Validator>,
+}
+
+/// Follows `$ref`s to components elsewhere in the document
+fn resolve<'a>(document:&'a Value, mut value:&'a Value) -> Result<&'a Value, String> {
+    for _ in 0..16 {
+        let Some(reference) = value.get("$ref").and_then(|r| r.as_str()) else {
+            return Ok(value)
+        };
+        let pointer = reference.strip_prefix('#').ok_or_else(|| format!("only local references are supported, not {}", reference))?;
+        value = document.pointer(pointer).ok_or_else(|| format!("{} does not exist", reference))?;
+    }
+    Err("references nest too deeply".into())
+}
+
+/// Points local `$ref`s at the registered copy of the document, so they still resolve once a schema is taken out of it
+fn absolutize_refs(schema:&mut Value) {
+    match schema {
+        Value::Object(map)=>{
+            for (key, value) in map.iter_mut() {
+                match value {
+                    Value::String(reference) if key=="$ref" && reference.starts_with('#')=>*reference = format!("{}{}", CONTRACT_URI, reference),
+                    _=>absolutize_refs(value),
+                }
+            }
+        },
+        Value::Array(items)=>items.iter_mut().for_each(absolutize_refs),
+        _=>{},
+    }
+}
+
+/// OpenAPI 3.0 marks nullable schemas with `nullable: true`, which JSON Schema doesn't know. 3.1 uses a `null` type instead, so convert to that.
+fn convert_nullable(value:&mut Value) {
+    match value {
+        Value::Object(map)=>{
+            if map.remove("nullable")==Some(Value::Bool(true)) {
+                if let Some(Value::String(kind)) = map.get("type").cloned() {
+                    map.insert("type".into(), serde_json::json!([kind, "null"]));
+                }
+            }
+            map.values_mut().for_each(convert_nullable);
+        },
+        Value::Array(items)=>items.iter_mut().for_each(convert_nullable),
+        _=>{},
+    }
+}
+
+fn compile(document:&Value, schema:&Value) -> Result<Validator, String> {
+    let mut schema = schema.clone();
+    absolutize_refs(&mut schema);
+
+    jsonschema::options()
+        .with_draft(Draft::Draft202012)
+        .with_resource(CONTRACT_URI, Draft::Draft202012.create_resource(document.clone()))
+        .build(&schema)
+        .map_err(|e| e.to_string())
+}
+
+fn media_types(document:&Value, content:Option<&Value>) -> Result<Vec<MediaType>, String> {
+    let Some(content) = content.and_then(|c| c.as_object()) else {
+        return Ok(Vec::new())
+    };
+
+    content.iter().map(|(mime, media)| Ok(MediaType{
+        mime: mime.to_ascii_lowercase(),
+        schema: media.get("schema").map(|schema| compile(document, schema)).transpose()?,
+    })).collect()
+}
+
+fn parameter(document:&Value, value:&Value) -> Result<Parameter, String> {
+    let value = resolve(document, value)?;
+    let name = value.get("name").and_then(|n| n.as_str()).ok_or("a parameter has no name")?;
+    let location = match value.get("in").and_then(|l| l.as_str()) {
+        Some("path")=>ParameterLocation::Path,
+        Some("query")=>ParameterLocation::Query,
+        Some("header")=>ParameterLocation::Header,
+        Some("cookie")=>ParameterLocation::Cookie,
+        other=>return Err(format!("parameter {} has an unknown location {:?}", name, other)),
+    };
+
+    Ok(Parameter{
+        name: name.to_owned(),
+        location,
+        required: location==ParameterLocation::Path || value.get("required").and_then(|r| r.as_bool()).unwrap_or(false),
+        schema: value.get("schema").map(|schema| compile(document, schema)).transpose()?,
+    })
+}
+
+fn operation(document:&Value, path:&str, method:&str, path_parameters:&[Value], value:&Value) -> Result<Operation, String> {
+    let mut parameters:Vec<Parameter> = Vec::new();
+    // The operation's own parameters come last, so they win over the path's
+    let declared = path_parameters.iter().chain(value.get("parameters").and_then(|p| p.as_array()).into_iter().flatten());
+    for declared in declared {
+        let parameter = parameter(document, declared)?;
+        parameters.retain(|p| !(p.location==parameter.location && p.name.eq_ignore_ascii_case(&parameter.name)));
+        parameters.push(parameter);
+    }
+
+    let body = match value.get("requestBody") {
+        None=>None,
+        Some(body)=>{
+            let body = resolve(document, body)?;
+            Some(RequestBody{
+                required: body.get("required").and_then(|r| r.as_bool()).unwrap_or(false),
+                content: media_types(document, body.get("content"))?,
+            })
+        }
+    };
+
+    let mut responses:HashMap<String, Vec<MediaType>> = HashMap::new();
+    for (status, response) in value.get("responses").and_then(|r| r.as_object()).into_iter().flatten() {
+        let response = resolve(document, response)?;
+        responses.insert(status.to_ascii_uppercase(), media_types(document, response.get("content"))?);
+    }
+
+    Ok(Operation{
+        method: Method::from_bytes(method.to_ascii_uppercase().as_bytes()).map_err(|e| e.to_string())?,
+        path: path.to_owned(),
+        parameters,
+        body,
+        responses,
+    })
+}
+
+/// `None` if the header is missing, `Some("")` if it isn't a recognisable media type
+fn mime_of(headers:&HeaderMap) -> Option<String> {
+    headers.get(header::CONTENT_TYPE).map(|v| v.to_str().unwrap_or("").split(';').next().unwrap_or("").trim().to_ascii_lowercase())
+}
+
+fn is_json(mime:&str) -> bool {
+    mime=="application/json" || mime.ends_with("+json")
+}
+
+/// Finds the media type the spec gives for a body, most specific first
+fn media_type<'a>(content:&'a [MediaType], mime:&str) -> Option<&'a MediaType> {
+    let range = format!("{}/*", mime.split('/').next().unwrap_or(""));
+    content.iter().find(|m| m.mime==mime)
+        .or_else(|| content.iter().find(|m| m.mime==range))
+        .or_else(|| content.iter().find(|m| m.mime=="*/*"))
+}
+
+fn schema_errors(validator:&Validator, value:&Value) -> Vec<String> {
+    validator.iter_errors(value)
+        .take(MAX_SCHEMA_ERRORS)
+        .map(|e| {
+            let at = e.instance_path.to_string();
+            if at.is_empty() { e.to_string() } else { format!("{} at {}", e, at) }
+        })
+        .collect()
+}
+
+/// Checks a body against the media types documented for it. `what` is used in the messages.
+fn check_body(content:&[MediaType], headers:&HeaderMap, body:&[u8], what:&str) -> Vec<String> {
+    if content.is_empty() {
+        return Vec::new()
+    }
+
+    let Some(mime) = mime_of(headers) else {
+        return vec![format!("{} has no Content-Type", what)]
+    };
+    let Some(media) = media_type(content, &mime) else {
+        let documented:Vec<&str> = content.iter().map(|m| m.mime.as_str()).collect();
+        return vec![format!("{} is {}, but the contract only allows {}", what, mime, documented.join(", "))]
+    };
+
+    match &media.schema {
+        Some(validator) if is_json(&mime)=>match serde_json::from_slice::<Value>(body) {
+            Ok(json)=>schema_errors(validator, &json).into_iter().map(|e| format!("{}: {}", what, e)).collect(),
+            Err(e)=>vec![format!("{} is not valid JSON: {}", what, e)],
+        },
+        _=>Vec::new(),
+    }
+}
+
+/// Parameters arrive as strings, so let them through if either the string or what it parses to as JSON fits the schema
+fn parameter_fits(validator:&Validator, raw:&str) -> bool {
+    validator.is_valid(&Value::String(raw.to_owned()))
+        || serde_json::from_str::<Value>(raw).is_ok_and(|parsed| validator.is_valid(&parsed))
+}
+
+/// The values of the `{...}` segments of a path template
+fn path_parameters<'a>(template:&'a str, path:&'a str) -> HashMap<&'a str, &'a str> {
+    template.split('/').zip(path.split('/'))
+        .filter_map(|(pattern, segment)| pattern.strip_prefix('{').and_then(|p| p.strip_suffix('}')).map(|name| (name, segment)))
+        .collect()
+}
+
+impl Contract {
+    /// Reads an OpenAPI 3.x document, usually JSON or YAML, and compiles its schemas
+    pub fn load(path:&Path) -> Result<Contract, ConfigError> {
+        let document:Value = load_config(path)?;
+        Contract::from_document(document).map_err(|problems| ConfigError::Invalid(path.to_owned(), problems))
+    }
+
+    /// Returns a description of every part of the document that couldn't be understood
+    pub fn from_document(mut document:Value) -> Result<Contract, Vec<String>> {
+        let version = document.get("openapi").and_then(|v| v.as_str()).unwrap_or("");
+        if !version.starts_with("3.") {
+            return Err(vec![format!("only OpenAPI 3.x is supported, not '{}'", version)])
+        }
+        if version.starts_with("3.0") {
+            convert_nullable(&mut document);
+        }
+
+        let mut operations:Vec<Operation> = Vec::new();
+        let mut problems:Vec<String> = Vec::new();
+        let paths = document.get("paths").and_then(|p| p.as_object()).cloned().unwrap_or_default();
+        for (path, item) in paths.iter() {
+            let item = match resolve(&document, item) {
+                Ok(item)=>item,
+                Err(e)=>{
+                    problems.push(format!("{}: {}", path, e));
+                    continue
+                }
+            };
+            let path_parameters = item.get("parameters").and_then(|p| p.as_array()).cloned().unwrap_or_default();
+
+            for method in ["get", "put", "post", "delete", "options", "head", "patch", "trace"] {
+                if let Some(value) = item.get(method) {
+                    match operation(&document, path, method, &path_parameters, value) {
+                        Ok(operation)=>operations.push(operation),
+                        Err(e)=>problems.push(format!("{} {}: {}", method.to_ascii_uppercase(), path, e)),
+                    }
+                }
+            }
+        }
+
+        if operations.is_empty() && problems.is_empty() {
+            problems.push("there are no operations in the contract".into());
+        }
+
+        if problems.is_empty() {
+            Ok(Contract{ operations })
+        } else {
+            Err(problems)
+        }
+    }
+
+    /// The operation a request is for. Literal segments are preferred over `{...}` ones, so `/collection/events`
+    /// beats `/collection/{collection_id}`.
+    fn operation(&self, method:&Method, path:&str) -> Result<&Operation, String> {
+        let mut candidates:Vec<&Operation> = self.operations.iter().filter(|op| path_matches(&op.path, path)).collect();
+        if candidates.is_empty() {
+            return Err(format!("{} is not in the contract", path))
+        }
+
+        candidates.retain(|op| op.method==method);
+        candidates.into_iter()
+            .min_by_key(|op| op.path.matches('{').count())
+            .ok_or_else(|| format!("{} {} is not in the contract", method, path))
+    }
+
+    /// Whether there is an operation for the request
+    pub fn describes(&self, method:&Method, path:&str) -> bool {
+        self.operation(method, path).is_ok()
+    }
+
+    /// Everything about the request that doesn't fit the contract
+    pub fn check_request(&self, method:&Method, path:&str, query:&HashMap<String, String>, headers:&HeaderMap, body:&[u8]) -> Vec<String> {
+        let operation = match self.operation(method, path) {
+            Ok(operation)=>operation,
+            Err(e)=>return vec![e],
+        };
+
+        let mut violations:Vec<String> = Vec::new();
+        let from_path = path_parameters(&operation.path, path);
+        for parameter in operation.parameters.iter() {
+            let value = match parameter.location {
+                ParameterLocation::Path=>from_path.get(parameter.name.as_str()).copied(),
+                ParameterLocation::Query=>query.get(&parameter.name).map(|v| v.as_str()),
+                ParameterLocation::Header=>headers.get(parameter.name.as_str()).and_then(|v| v.to_str().ok()),
+                // Cookies aren't used by anything the mock serves
+                ParameterLocation::Cookie=>continue,
+            };
+            match (value, &parameter.schema) {
+                (None, _) if parameter.required=>violations.push(format!("{:?} parameter {} is required", parameter.location, parameter.name).to_lowercase()),
+                (Some(value), Some(schema)) if !parameter_fits(schema, value)=>violations.push(format!("{:?} parameter {} does not fit its schema: {}", parameter.location, parameter.name, value).to_lowercase()),
+                _=>{},
+            }
+        }
+
+        match (&operation.body, body.is_empty()) {
+            (None, false)=>violations.push("the request has a body, but the contract doesn't allow one".into()),
+            (Some(spec), true) if spec.required=>violations.push("the request needs a body".into()),
+            (Some(spec), false)=>violations.extend(check_body(&spec.content, headers, body, "the request body")),
+            _=>{},
+        }
+
+        violations
+    }
+
+    /// Everything about the response that doesn't fit the contract. Requests that aren't in the contract have
+    /// already been reported, so their responses aren't checked.
+    pub fn check_response(&self, method:&Method, path:&str, status:StatusCode, headers:&HeaderMap, body:&[u8]) -> Vec<String> {
+        let Ok(operation) = self.operation(method, path) else {
+            return Vec::new()
+        };
+
+        let code = status.as_u16().to_string();
+        let range = format!("{}XX", status.as_u16() / 100);
+        let Some(content) = [code.as_str(), range.as_str(), "DEFAULT"].iter().find_map(|key| operation.responses.get(*key)) else {
+            return vec![format!("status {} is not documented", status.as_u16())]
+        };
+
+        if content.is_empty() {
+            if body.is_empty() {
+                Vec::new()
+            } else {
+                vec![format!("the contract says status {} has no body, but one was sent", status.as_u16())]
+            }
+        } else if body.is_empty() {
+            // HEAD, 304s and so on legitimately leave out a documented body
+            Vec::new()
+        } else {
+            check_body(content, headers, body, &format!("the {} response", status.as_u16()))
+        }
+    }
+}
+
+/// How `check_contract` is set up
+#[derive(Clone)]
+pub struct ContractGuard {
+    pub contract: Arc<Contract>,
+    /// Answer 400 to requests that break the contract, and 500 instead of responses that do, rather than just logging them
+    pub strict: bool,
+    /// Errors from this layer are sent after `render_errors` has had its go, so it has to format them itself
+    pub errors: ErrorFormat,
+}
+
+/// Checks requests and responses against the contract, logging anything that doesn't fit. The mock's own
+/// endpoints aren't part of the real service's contract, so are left alone.
+pub async fn check_contract(
+    State(guard): State<ContractGuard>,
+    request: Request,
+    next: Next
+) -> Response {
+    let path = request.uri().path().to_owned();
+    if is_admin_path(&path) || path=="/openapi.json" {
+        return next.run(request).await
+    }
+    if MOCK_ONLY_PATHS.iter().any(|pattern| path_matches(pattern, &path)) && !guard.contract.describes(request.method(), &path) {
+        return next.run(request).await
+    }
+
+    let (parts, body) = request.into_parts();
+    let Ok(body) = body::to_bytes(body, MAX_BODY_BYTES).await else {
+        return ApiError::BadRequest(format!("request bodies must be under {} bytes", MAX_BODY_BYTES)).render(guard.errors, Some(&path))
+    };
+    let query = Query::<HashMap<String, String>>::try_from_uri(&parts.uri).map(|Query(q)| q).unwrap_or_default();
+    let method = parts.method.clone();
+
+    let violations = guard.contract.check_request(&method, &path, &query, &parts.headers, &body);
+    if !violations.is_empty() {
+        log::warn!("Contract violation in request {} {}: {}", method, parts.uri, violations.join("; "));
+        if guard.strict {
+            return ApiError::BadRequest(format!("the request does not match the contract: {}", violations.join("; "))).render(guard.errors, Some(&path))
+        }
+    }
+
+    let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+    if is_event_stream(response.headers()) {
+        // It never ends, so can't be read in full to check
+        return response
+    }
+    let injected = matches!(response.extensions().get::<ApiError>(), Some(ApiError::InjectedFault(_)))
+        || response.extensions().get::<ConnectionDropped>().is_some();
+    if injected {
+        // Failures asked for through fault injection are meant to be off-contract
+        return response
+    }
+    let (parts, body) = response.into_parts();
+    let mut chunks = body.into_data_stream();
+    let mut received = Vec::new();
+    while let Some(chunk) = chunks.next().await {
+        match chunk {
+            Ok(chunk)=>received.extend_from_slice(&chunk),
+            // Cut short, most likely on purpose by fault injection, so there is nothing to check. Pass on what
+            // did arrive and then the same failure, so the client sees what it would have without the contract.
+            Err(e)=>return Response::from_parts(parts, cut_short_body(Bytes::from(received), e)),
+        }
+    }
+    let body = Bytes::from(received);
+
+    let violations = guard.contract.check_response(&method, &path, parts.status, &parts.headers, &body);
+    if !violations.is_empty() {
+        log::warn!("Contract violation in response to {} {}: {}", method, path, violations.join("; "));
+        if guard.strict {
+            return ApiError::ContractDrift(violations.join("; ")).render(guard.errors, Some(&path)).into_response()
+        }
+    }
+
+    Response::from_parts(parts, Body::from(body))
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use axum::{middleware, routing::get, Json, Router};
+    use axum_test::TestServer;
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream};
+    use crate::{faults::{ErrorFault, FaultConfig, FaultRule, TruncateFault}, fixture::SAVED_COLLECTION_ID};
+    use super::*;
+
+    const CONTRACT:&str = r##"
+openapi: 3.0.3
+info: {title: Recipes, version: "1"}
+paths:
+  /collection/{collection_id}/contents:
+    parameters:
+      - {name: collection_id, in: path, required: true, schema: {type: string}}
+    get:
+      parameters:
+        - {name: limit, in: query, schema: {type: integer, minimum: 1}}
+      responses:
+        "200":
+          description: ok
+          content:
+            application/json:
+              schema: {$ref: "#/components/schemas/Contents"}
+        4XX:
+          description: error
+          content:
+            application/problem+json:
+              schema: {type: object, required: [title]}
+    put:
+      requestBody:
+        required: true
+        content:
+          application/json:
+            schema:
+              type: object
+              required: [ids]
+              properties: {ids: {type: array, items: {type: string}}}
+      responses:
+        "204": {description: updated}
+components:
+  schemas:
+    Contents:
+      type: object
+      required: [content, lastModified]
+      properties:
+        content: {type: array, items: {type: string}}
+        lastModified: {type: string, nullable: true}
+"##;
+
+    fn contract() -> Contract {
+        Contract::from_document(serde_yaml::from_str(CONTRACT).unwrap()).unwrap()
+    }
+
+    fn json_headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, "application/json".parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_check_request() {
+        let contract = contract();
+        let no_query = HashMap::new();
+        let path = "/collection/c1/contents";
+
+        assert!(contract.check_request(&Method::GET, path, &no_query, &HeaderMap::new(), b"").is_empty());
+        assert!(contract.check_request(&Method::GET, path, &[("limit".to_string(), "10".to_string())].into(), &HeaderMap::new(), b"").is_empty());
+        assert_eq!(contract.check_request(&Method::GET, path, &[("limit".to_string(), "0".to_string())].into(), &HeaderMap::new(), b"").len(), 1);
+        assert_eq!(contract.check_request(&Method::DELETE, path, &no_query, &HeaderMap::new(), b""), vec!["DELETE /collection/c1/contents is not in the contract"]);
+        assert_eq!(contract.check_request(&Method::GET, "/collection", &no_query, &HeaderMap::new(), b""), vec!["/collection is not in the contract"]);
+
+        assert!(contract.check_request(&Method::PUT, path, &no_query, &json_headers(), br#"{"ids": ["r1"]}"#).is_empty());
+        assert_eq!(contract.check_request(&Method::PUT, path, &no_query, &json_headers(), b""), vec!["the request needs a body"]);
+        assert_eq!(contract.check_request(&Method::PUT, path, &no_query, &json_headers(), br#"{"ids": "r1"}"#).len(), 1);
+        assert_eq!(contract.check_request(&Method::PUT, path, &no_query, &HeaderMap::new(), br#"{"ids": ["r1"]}"#), vec!["the request body has no Content-Type"]);
+    }
+
+    #[test]
+    fn test_check_response() {
+        let contract = contract();
+        let path = "/collection/c1/contents";
+
+        let contents = br#"{"content": ["r1"], "lastModified": null}"#;
+        assert!(contract.check_response(&Method::GET, path, StatusCode::OK, &json_headers(), contents).is_empty(), "3.0 nullable should be understood");
+        assert_eq!(contract.check_response(&Method::GET, path, StatusCode::OK, &json_headers(), br#"{"content": ["r1"]}"#).len(), 1);
+        assert_eq!(contract.check_response(&Method::GET, path, StatusCode::INTERNAL_SERVER_ERROR, &json_headers(), b"{}"), vec!["status 500 is not documented"]);
+
+        let mut problem_headers = HeaderMap::new();
+        problem_headers.insert(header::CONTENT_TYPE, "application/problem+json".parse().unwrap());
+        assert!(contract.check_response(&Method::GET, path, StatusCode::NOT_FOUND, &problem_headers, br#"{"title": "Not found"}"#).is_empty());
+        assert_eq!(contract.check_response(&Method::GET, path, StatusCode::NOT_FOUND, &json_headers(), br#"{"title": "Not found"}"#).len(), 1);
+        assert_eq!(contract.check_response(&Method::PUT, path, StatusCode::NO_CONTENT, &json_headers(), b"{}").len(), 1);
+    }
+
+    #[test]
+    fn test_invalid_contract() {
+        assert!(Contract::from_document(serde_json::json!({"swagger": "2.0"})).is_err());
+        assert!(Contract::from_document(serde_json::json!({"openapi": "3.1.0", "paths": {
+            "/a": {"get": {"responses": {"200": {"$ref": "#/components/responses/Missing"}}}}
+        }})).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_strict_mode() {
+        let guard = ContractGuard{
+            contract: Arc::new(contract()),
+            strict: true,
+            errors: ErrorFormat::Problem,
+        };
+        let fake_app = Router::new()
+            .route("/collection/{collection_id}/contents", get(|| async { Json(serde_json::json!({"content": ["r1"]})) }))
+            .layer(middleware::from_fn_with_state(guard, check_contract));
+
+        let fake_server = TestServer::new(fake_app).unwrap();
+
+        let drifted = fake_server.get("/collection/c1/contents").await;
+        drifted.assert_status(StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(drifted.json::<Value>()["type"], "urn:mock-recipe-persistence:problem:contract-drift");
+
+        let bad_request = fake_server.get("/collection/c1/contents?limit=none").await;
+        bad_request.assert_status(StatusCode::BAD_REQUEST);
+        assert_eq!(bad_request.json::<Value>()["instance"], "/collection/c1/contents");
+    }
+
+    #[tokio::test]
+    async fn test_mock_only_paths() {
+        let guard = ContractGuard{
+            contract: Arc::new(contract()),
+            strict: true,
+            errors: ErrorFormat::Problem,
+        };
+        let fake_app = Router::new()
+            .route("/collection/{collection_id}/changes", get(|| async { Json(serde_json::json!({"added": []})) }))
+            .route("/collection/{collection_id}/history", get(|| async { Json(serde_json::json!({"added": []})) }))
+            .layer(middleware::from_fn_with_state(guard, check_contract));
+
+        let fake_server = TestServer::new(fake_app).unwrap();
+
+        fake_server.get("/collection/c1/changes").await.assert_status_ok();
+        fake_server.get("/collection/c1/history").await.assert_status(StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_injected_faults() {
+        let faults = FaultConfig{
+            rules: vec![FaultRule{
+                method: Some("PUT".into()),
+                path: "/collection/{collection_id}/contents".into(),
+                latency: None,
+                error: Some(ErrorFault{ status: 503, probability: 1.0 }),
+                truncate: None,
+                close_connection: None,
+            }],
+        };
+        let app = crate::MockServer::builder().faults(faults).mock_headers(true).contract(contract(), true).router();
+        let server = TestServer::new(app).unwrap();
+
+        let contents = format!("/collection/{}/contents", SAVED_COLLECTION_ID);
+        let from_rule = server.put(&contents).json(&serde_json::json!({"ids": ["r1"]})).await;
+        from_rule.assert_status(StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(from_rule.json::<Value>()["type"], "urn:mock-recipe-persistence:problem:injected-fault");
+
+        let from_header = server.get(&contents)
+            .add_header("X-Mock-Status", "503")
+            .await;
+        from_header.assert_status(StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(from_header.json::<Value>()["type"], "urn:mock-recipe-persistence:problem:injected-fault");
+    }
+
+    #[tokio::test]
+    async fn test_truncated_response() {
+        let faults = FaultConfig{
+            rules: vec![FaultRule{
+                method: None,
+                path: "/collection/{collection_id}/contents".into(),
+                latency: None,
+                error: None,
+                truncate: Some(TruncateFault{ after_bytes: 5, probability: 1.0 }),
+                close_connection: None,
+            }],
+        };
+        let server = crate::MockServer::builder().faults(faults).contract(contract(), true).start().await.unwrap();
+
+        let mut stream = TcpStream::connect(server.addr()).await.unwrap();
+        let request = format!("GET /collection/{}/contents HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", SAVED_COLLECTION_ID);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut received = Vec::new();
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut received)).await;
+        assert!(read.is_ok(), "the server never finished responding");
+
+        let received = String::from_utf8_lossy(&received);
+        assert!(received.starts_with("HTTP/1.1 200"), "{}", received);
+        assert!(received.ends_with("\r\n\r\n{\"con"), "{}", received);
+    }
+}
@@ -0,0 +1,279 @@
+use std::{collections::VecDeque, convert::Infallible, sync::Mutex, time::Duration};
+use axum::{http::{header, HeaderMap}, response::{sse::{Event, KeepAlive, Sse}, IntoResponse, Response}};
+use futures_util::{stream, Stream, StreamExt};
+use serde::Serialize;
+use time::OffsetDateTime;
+use tokio::sync::{broadcast::{self, error::RecvError}, watch};
+use utoipa::ToSchema;
+use crate::fixture::history::ContentChange;
+
+pub const DEFAULT_EVENT_BUFFER:usize = 1000;
+
+/// How often idle streams get a comment, so that proxies don't time them out
+const KEEP_ALIVE_INTERVAL:Duration = Duration::from_secs(15);
+
+/// Sent as the data of a `changed` event whenever recipes are added to or removed from a collection
+#[derive(Serialize, ToSchema, Debug, Clone)]
+pub struct CollectionEvent {
+    /// Counts up from 1 over the life of the server; sent as the SSE event ID
+    #[serde(skip)]
+    pub id: u64,
+    #[serde(skip)]
+    pub user_id: String,
+    #[serde(rename="collectionId")]
+    pub collection_id: String,
+    pub version: u64,
+    #[serde(rename="lastModified", with="time::serde::rfc3339")]
+    #[schema(value_type=String)]
+    pub last_modified: OffsetDateTime,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl CollectionEvent {
+    pub fn concerns(&self, user_id:&str, collection_id:Option<&str>) -> bool {
+        self.user_id==user_id && collection_id.is_none_or(|id| self.collection_id==id)
+    }
+
+    fn to_sse(&self) -> Event {
+        Event::default()
+            .id(self.id.to_string())
+            .event("changed")
+            .json_data(self)
+            .unwrap_or_else(|_| Event::default().comment("could not serialise event"))
+    }
+}
+
+/// Event streams never end, so layers that read whole responses have to leave them alone
+pub fn is_event_stream(headers:&HeaderMap) -> bool {
+    headers.get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("text/event-stream"))
+}
+
+/// Tells the client it may have missed events and should fetch everything again
+fn resync() -> Event {
+    Event::default().event("resync").data("{}")
+}
+
+#[derive(Debug)]
+struct Buffer {
+    events: VecDeque<CollectionEvent>,
+    capacity: usize,
+    next_id: u64,
+    /// The ID of the last event to fall out of the buffer; anyone who saw an earlier one can't catch up
+    dropped: u64,
+}
+
+/// Fans collection changes out to event streams, keeping the most recent for clients that reconnect with Last-Event-ID
+#[derive(Debug)]
+pub struct EventBus {
+    buffer: Mutex<Buffer>,
+    sender: broadcast::Sender<CollectionEvent>,
+    closed: watch::Sender<bool>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        EventBus::new(DEFAULT_EVENT_BUFFER)
+    }
+}
+
+impl EventBus {
+    /// `capacity` is how many events are kept for resuming streams
+    pub fn new(capacity:usize) -> EventBus {
+        EventBus{
+            buffer: Mutex::new(Buffer{
+                events: VecDeque::new(),
+                capacity,
+                next_id: 1,
+                dropped: 0,
+            }),
+            sender: broadcast::channel(capacity.max(16)).0,
+            closed: watch::channel(false).0,
+        }
+    }
+
+    /// Lets anyone listening to the user or the collection know that its content changed
+    pub fn publish(&self, user_id:&str, collection_id:&str, version:u64, last_modified:OffsetDateTime, change:ContentChange) {
+        // Held while sending, so that a new subscriber sees each event either in the backlog or live, never both or neither
+        let mut buffer = self.buffer.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        let event = CollectionEvent{
+            id: buffer.next_id,
+            user_id: user_id.to_owned(),
+            collection_id: collection_id.to_owned(),
+            version,
+            last_modified,
+            added: change.added,
+            removed: change.removed,
+        };
+        buffer.next_id += 1;
+        buffer.events.push_back(event.clone());
+        while buffer.events.len() > buffer.capacity {
+            if let Some(dropped) = buffer.events.pop_front() {
+                buffer.dropped = dropped.id;
+            }
+        }
+
+        //no receivers is fine, nobody is listening
+        let _ = self.sender.send(event);
+    }
+
+    /// Ends every stream, so that a graceful shutdown doesn't wait on them forever
+    pub fn close(&self) {
+        self.closed.send_replace(true);
+    }
+
+    /// Every event published from now on, for anyone and any collection
+    pub fn subscribe(&self) -> Subscription {
+        Subscription{
+            receiver: self.sender.subscribe(),
+            closed: self.closed.subscribe(),
+        }
+    }
+
+    /// An SSE response with the events for `user_id`, or just those for one of their collections.
+    /// With a Last-Event-ID header, the buffered events after that one are sent first; if some have been
+    /// dropped from the buffer since then, a `resync` event is sent instead.
+    pub fn stream(&self, headers:&HeaderMap, user_id:String, collection_id:Option<String>) -> Response {
+        let last_event_id = headers.get("last-event-id")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse::<u64>().ok());
+
+        let (subscription, backlog) = {
+            let buffer = self.buffer.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            let subscription = self.subscribe();
+
+            let backlog:Vec<Event> = match last_event_id {
+                None=>Vec::new(),
+                Some(last) if last < buffer.dropped || last >= buffer.next_id=>vec![resync()],
+                Some(last)=>buffer.events.iter()
+                    .filter(|event| event.id > last && event.concerns(&user_id, collection_id.as_deref()))
+                    .map(CollectionEvent::to_sse)
+                    .collect(),
+            };
+            (subscription, backlog)
+        };
+
+        let live = live_events(subscription, user_id, collection_id);
+        let events = stream::iter(backlog).map(Ok::<Event, Infallible>).chain(live);
+
+        Sse::new(events).keep_alive(KeepAlive::new().interval(KEEP_ALIVE_INTERVAL)).into_response()
+    }
+}
+
+/// What a subscription has to report
+#[derive(Debug)]
+pub enum Received {
+    Event(CollectionEvent),
+    /// The subscriber fell too far behind, so has missed some events
+    Missed,
+}
+
+/// A feed of every event published, until the bus is closed
+pub struct Subscription {
+    receiver: broadcast::Receiver<CollectionEvent>,
+    closed: watch::Receiver<bool>,
+}
+
+impl Subscription {
+    /// Waits for the next event. `None` once the bus has been closed.
+    pub async fn next(&mut self) -> Option<Received> {
+        tokio::select! {
+            received = self.receiver.recv()=>match received {
+                Ok(event)=>Some(Received::Event(event)),
+                Err(RecvError::Lagged(_))=>Some(Received::Missed),
+                Err(RecvError::Closed)=>None,
+            },
+            _ = self.closed.wait_for(|closed| *closed)=>None,
+        }
+    }
+}
+
+fn live_events(subscription:Subscription, user_id:String, collection_id:Option<String>) -> impl Stream<Item=Result<Event, Infallible>> {
+    stream::unfold(subscription, move |mut subscription| {
+        let user_id = user_id.clone();
+        let collection_id = collection_id.clone();
+        async move {
+            loop {
+                match subscription.next().await? {
+                    Received::Event(event) if event.concerns(&user_id, collection_id.as_deref())=>return Some((Ok(event.to_sse()), subscription)),
+                    Received::Event(_)=>continue,
+                    Received::Missed=>return Some((Ok(resync()), subscription)),
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use axum::http::HeaderValue;
+    use super::*;
+
+    fn change(added:&[&str]) -> ContentChange {
+        ContentChange{
+            added: added.iter().map(|s| s.to_string()).collect(),
+            removed: Vec::new(),
+        }
+    }
+
+    /// Reads what the stream has sent so far, without waiting for more
+    async fn read_available(body:&mut axum::body::BodyDataStream) -> String {
+        let mut text = String::new();
+        while let Ok(Some(Ok(chunk))) = tokio::time::timeout(Duration::from_millis(50), body.next()).await {
+            text.push_str(&String::from_utf8_lossy(&chunk));
+        }
+        text
+    }
+
+    fn resume_from(last:&str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("last-event-id", HeaderValue::from_str(last).unwrap());
+        headers
+    }
+
+    #[tokio::test]
+    async fn test_live_and_filtered() {
+        let bus = EventBus::new(10);
+        let mut everything = bus.stream(&HeaderMap::new(), "alice".into(), None).into_body().into_data_stream();
+        let mut one_collection = bus.stream(&HeaderMap::new(), "alice".into(), Some("c1".into())).into_body().into_data_stream();
+
+        bus.publish("alice", "c1", 2, OffsetDateTime::now_utc(), change(&["r1"]));
+        bus.publish("alice", "c2", 2, OffsetDateTime::now_utc(), change(&["r2"]));
+        bus.publish("bob", "c1", 2, OffsetDateTime::now_utc(), change(&["r3"]));
+
+        let sent = read_available(&mut everything).await;
+        assert!(sent.contains("id: 1\n") && sent.contains("id: 2\n") && !sent.contains("id: 3\n"), "{}", sent);
+        assert!(sent.contains("event: changed\n"));
+        assert!(sent.contains(r#""collectionId":"c1","version":2"#), "{}", sent);
+
+        let sent = read_available(&mut one_collection).await;
+        assert!(sent.contains("id: 1\n") && !sent.contains("id: 2\n"), "{}", sent);
+
+        bus.close();
+        assert!(everything.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_resume() {
+        let bus = EventBus::new(2);
+        for n in 1..=3 {
+            bus.publish("alice", "c1", n + 1, OffsetDateTime::now_utc(), change(&[&format!("r{}", n)]));
+        }
+
+        let mut resumed = bus.stream(&resume_from("2"), "alice".into(), None).into_body().into_data_stream();
+        let sent = read_available(&mut resumed).await;
+        assert!(sent.contains("id: 3\n") && !sent.contains("id: 2\n"), "{}", sent);
+
+        //event 1 has fallen out of the buffer, so resuming from before it can't be done
+        let mut too_old = bus.stream(&resume_from("0"), "alice".into(), None).into_body().into_data_stream();
+        let sent = read_available(&mut too_old).await;
+        assert!(sent.starts_with("event: resync\n"), "{}", sent);
+        assert!(!sent.contains("id: 3\n"));
+
+        let mut from_the_future = bus.stream(&resume_from("9"), "alice".into(), None).into_body().into_data_stream();
+        assert!(read_available(&mut from_the_future).await.starts_with("event: resync\n"));
+    }
+}
@@ -0,0 +1,510 @@
+use std::{io, path::Path, time::Duration};
+use axum::{body::{self, Body, Bytes}, extract::Request, http::{header, HeaderMap, HeaderValue, Method, StatusCode}, middleware::Next, response::{IntoResponse, Response}, Extension};
+use futures_util::StreamExt;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use crate::{config::{is_admin_path, load_config, ConfigError}, events::is_event_stream, handlers::{errors::ApiError, SharedState}};
+
+const MOCK_STATUS_HEADER:&str = "x-mock-status";
+const MOCK_DELAY_HEADER:&str = "x-mock-delay-ms";
+const MOCK_FAIL_AFTER_BYTES_HEADER:&str = "x-mock-fail-after-bytes";
+
+/// The statuses an error fault may use: the ones a real deployment is likely to produce on its own
+const FAULT_STATUSES:[u16; 4] = [429, 500, 502, 503];
+
+fn always() -> f64 {
+    1.0
+}
+
+/// A set of faults to inject, read from the --faults file or set through /__admin/faults. JSON, TOML or YAML
+/// is accepted, the format being chosen from the file extension. For example, in TOML:
+///
+/// ```toml
+/// [[rules]]
+/// method = "PUT"                                   # optional, any method if absent
+/// path = "/collection/{collection_id}/contents"    # {...} and * each match one path segment
+/// latency = { minMs = 200, maxMs = 2000 }          # maxMs is optional, for a fixed delay
+/// error = { status = 503, probability = 0.25 }     # probability defaults to 1
+/// truncate = { afterBytes = 10 }
+/// closeConnection = { probability = 0.05 }
+/// ```
+///
+/// Only the first rule that matches a request is applied.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FaultConfig {
+    #[serde(default)]
+    pub rules: Vec<FaultRule>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FaultRule {
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub method: Option<String>,
+    pub path: String,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub latency: Option<LatencyFault>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub error: Option<ErrorFault>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub truncate: Option<TruncateFault>,
+    #[serde(rename="closeConnection", default, skip_serializing_if="Option::is_none")]
+    pub close_connection: Option<CloseConnectionFault>,
+}
+
+/// Delays the response by a random time between `min_ms` and `max_ms`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct LatencyFault {
+    #[serde(rename="minMs")]
+    pub min_ms: u64,
+    #[serde(rename="maxMs", default, skip_serializing_if="Option::is_none")]
+    pub max_ms: Option<u64>,
+}
+
+/// Answers with `status` instead of handling the request
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ErrorFault {
+    pub status: u16,
+    #[serde(default="always")]
+    pub probability: f64,
+}
+
+/// Handles the request but stops sending the body after `after_bytes`. The Content-Length still gives the full size.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TruncateFault {
+    #[serde(rename="afterBytes")]
+    pub after_bytes: usize,
+    #[serde(default="always")]
+    pub probability: f64,
+}
+
+/// Drops the connection without handling the request
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct CloseConnectionFault {
+    #[serde(default="always")]
+    pub probability: f64,
+}
+
+impl FaultConfig {
+    /// Reads, parses and validates the fault config at the given path
+    pub fn load(path:&Path) -> Result<FaultConfig, ConfigError> {
+        let parsed:FaultConfig = load_config(path)?;
+        ConfigError::check(path, parsed.validate())?;
+        Ok(parsed)
+    }
+
+    /// Checks each rule in turn, prefixing its problems with the rule's position
+    pub fn validate(&self) -> Vec<String> {
+        self.rules.iter().enumerate().flat_map(|(idx, rule)| rule.validate().into_iter().map(move |problem| format!("rule #{}: {}", idx, problem))).collect()
+    }
+
+    /// The first rule that applies to the request, if any
+    pub fn matching(&self, method:&Method, path:&str) -> Option<&FaultRule> {
+        self.rules.iter().find(|rule| rule.matches(method, path))
+    }
+}
+
+impl FaultRule {
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems:Vec<String> = Vec::new();
+
+        if let Some(method) = &self.method {
+            if Method::from_bytes(method.to_ascii_uppercase().as_bytes()).is_err() {
+                problems.push(format!("'{}' is not an HTTP method", method));
+            }
+        }
+        if !self.path.starts_with('/') {
+            problems.push(format!("path '{}' must start with /", self.path));
+        }
+        if let Some(LatencyFault{min_ms, max_ms: Some(max_ms)}) = &self.latency {
+            if max_ms < min_ms {
+                problems.push(format!("latency maxMs {} is less than minMs {}", max_ms, min_ms));
+            }
+        }
+        if let Some(error) = &self.error {
+            if !FAULT_STATUSES.contains(&error.status) {
+                problems.push(format!("error status must be one of {:?}, not {}", FAULT_STATUSES, error.status));
+            }
+        }
+
+        let probabilities = [
+            ("error", self.error.as_ref().map(|f| f.probability)),
+            ("truncate", self.truncate.as_ref().map(|f| f.probability)),
+            ("closeConnection", self.close_connection.as_ref().map(|f| f.probability)),
+        ];
+        for (fault, probability) in probabilities {
+            if let Some(p) = probability.filter(|p| !(0.0..=1.0).contains(p)) {
+                problems.push(format!("{} probability must be between 0 and 1, not {}", fault, p));
+            }
+        }
+
+        problems
+    }
+
+    pub fn matches(&self, method:&Method, path:&str) -> bool {
+        if let Some(rule_method) = &self.method {
+            if !rule_method.eq_ignore_ascii_case(method.as_str()) {
+                return false
+            }
+        }
+
+        path_matches(&self.path, path)
+    }
+}
+
+/// Whether `path` fits a route pattern like `/collection/{collection_id}/contents`, where `{...}` and `*` each match any one segment
+pub fn path_matches(pattern:&str, path:&str) -> bool {
+    let mut pattern_segments = pattern.trim_end_matches('/').split('/');
+    let mut path_segments = path.trim_end_matches('/').split('/');
+    loop {
+        match (pattern_segments.next(), path_segments.next()) {
+            (None, None)=>return true,
+            (Some(pattern), Some(segment)) if pattern=="*" || (pattern.starts_with('{') && pattern.ends_with('}')) || pattern==segment=>continue,
+            _=>return false,
+        }
+    }
+}
+
+/// Marks a response whose connection is to be dropped, so that outer layers leave its body alone
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionDropped;
+
+/// A body that fails as soon as it is read, which makes hyper abandon the connection before sending anything
+fn broken_body() -> Body {
+    Body::from_stream(futures_util::stream::once(async {
+        Err::<Bytes, io::Error>(io::Error::new(io::ErrorKind::ConnectionAborted, "connection closed by fault injection"))
+    }))
+}
+
+/// A body that sends `partial` and then fails with `error`
+pub fn cut_short_body<E>(partial:Bytes, error:E) -> Body
+where
+    E: std::error::Error + Send + Sync + 'static
+{
+    //hyper holds back what it has if the body fails straight away, so give it a moment to send the first part
+    let rest = futures_util::stream::once(async {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        Err(error)
+    });
+    Body::from_stream(futures_util::stream::once(async { Ok(partial) }).chain(rest))
+}
+
+/// What to do to one request, once the dice have been rolled
+#[derive(Debug, Default, PartialEq)]
+struct FaultPlan {
+    delay: Option<Duration>,
+    close_connection: bool,
+    status: Option<StatusCode>,
+    fail_after_bytes: Option<usize>,
+}
+
+impl FaultPlan {
+    fn from_rule(rule:&FaultRule) -> FaultPlan {
+        let mut rng = rand::thread_rng();
+        let delay = rule.latency.as_ref().map(|l| Duration::from_millis(rng.gen_range(l.min_ms..=l.max_ms.unwrap_or(l.min_ms).max(l.min_ms))));
+        //Rules given to MockServerBuilder haven't been through validate(), and gen_bool panics outside [0,1]
+        let mut happens = |probability:f64| rng.gen_bool(if probability.is_nan() { 0.0 } else { probability.clamp(0.0, 1.0) });
+        FaultPlan{
+            delay,
+            close_connection: rule.close_connection.as_ref().is_some_and(|f| happens(f.probability)),
+            status: rule.error.as_ref().filter(|f| happens(f.probability)).map(|f| StatusCode::from_u16(f.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)),
+            fail_after_bytes: rule.truncate.as_ref().filter(|f| happens(f.probability)).map(|f| f.after_bytes),
+        }
+    }
+
+    /// Reads the X-Mock-* headers. Fails if any of them has a value that can't be acted on.
+    fn from_headers(headers:&HeaderMap) -> Result<FaultPlan, ApiError> {
+        fn header_value<T: std::str::FromStr>(headers:&HeaderMap, name:&str) -> Result<Option<T>, ApiError> {
+            match headers.get(name) {
+                None=>Ok(None),
+                Some(value)=>value.to_str().ok()
+                    .and_then(|v| v.trim().parse().ok())
+                    .map(Some)
+                    .ok_or_else(|| ApiError::BadRequest(format!("{} must be a whole number, not {:?}", name, value))),
+            }
+        }
+
+        let status = match header_value::<u16>(headers, MOCK_STATUS_HEADER)? {
+            None=>None,
+            Some(code) if (400..600).contains(&code)=>StatusCode::from_u16(code).ok(),
+            Some(code)=>return Err(ApiError::BadRequest(format!("{} must be a 4xx or 5xx status, not {}", MOCK_STATUS_HEADER, code))),
+        };
+
+        Ok(FaultPlan{
+            delay: header_value(headers, MOCK_DELAY_HEADER)?.map(Duration::from_millis),
+            close_connection: false,
+            status,
+            fail_after_bytes: header_value(headers, MOCK_FAIL_AFTER_BYTES_HEADER)?,
+        })
+    }
+
+    async fn apply(self, request:Request, next:Next) -> Response {
+        if let Some(delay) = self.delay {
+            tokio::time::sleep(delay).await;
+        }
+
+        if self.close_connection {
+            log::info!("Dropping connection for {} {}", request.method(), request.uri());
+            let mut response = Response::new(broken_body());
+            response.extensions_mut().insert(ConnectionDropped);
+            return response
+        }
+
+        if let Some(status) = self.status {
+            return ApiError::InjectedFault(status).into_response()
+        }
+
+        let response = next.run(request).await;
+
+        match self.fail_after_bytes {
+            None=>response,
+            Some(_) if is_event_stream(response.headers())=>response,
+            Some(after_bytes)=>{
+                let (mut parts, full_body) = response.into_parts();
+                let Ok(full_body) = body::to_bytes(full_body, usize::MAX).await else {
+                    return Response::from_parts(parts, broken_body())
+                };
+                if full_body.len() <= after_bytes {
+                    return Response::from_parts(parts, Body::from(full_body))
+                }
+
+                parts.headers.insert(header::CONTENT_LENGTH, HeaderValue::from(full_body.len()));
+                let error = io::Error::new(io::ErrorKind::ConnectionAborted, "body truncated by fault injection");
+                Response::from_parts(parts, cut_short_body(full_body.slice(..after_bytes), error))
+            }
+        }
+    }
+}
+
+/// Applies the first matching fault rule to each request. The admin API is never affected, so faults can always be turned off again.
+pub async fn inject_faults(
+    Extension(shared_state): Extension<SharedState>,
+    request: Request,
+    next: Next
+) -> Response {
+    if is_admin_path(request.uri().path()) {
+        return next.run(request).await
+    }
+
+    // Worked out in its own statement so the read lock is released before the handler, which may want to write, runs
+    let plan = shared_state.read().await.faults.matching(request.method(), request.uri().path()).map(FaultPlan::from_rule);
+
+    match plan {
+        None=>next.run(request).await,
+        Some(plan)=>plan.apply(request, next).await,
+    }
+}
+
+/// Lets a single request ask for its own failure with X-Mock-Status, X-Mock-Delay-Ms and X-Mock-Fail-After-Bytes.
+/// Only installed when the server is started with --mock-headers.
+pub async fn mock_header_faults(
+    request: Request,
+    next: Next
+) -> Response {
+    match FaultPlan::from_headers(request.headers()) {
+        Err(e)=>e.into_response(),
+        Ok(plan) if plan==FaultPlan::default()=>next.run(request).await,
+        Ok(plan)=>plan.apply(request, next).await,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use axum::{middleware, routing::get, Router};
+    use axum_test::TestServer;
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpListener, TcpStream}, sync::RwLock};
+    use crate::fixture::{Environment, MutableStaticData};
+    use super::*;
+
+    fn rule(path:&str) -> FaultRule {
+        FaultRule{
+            method: None,
+            path: path.into(),
+            latency: None,
+            error: None,
+            truncate: None,
+            close_connection: None,
+        }
+    }
+
+    /// Serves a fixed body from /collection/{collection_id}/contents over real TCP, since dropped
+    /// connections and short bodies only show up on the wire. Returns everything the server sent back.
+    /// The handler takes the write lock, as the real ones do.
+    async fn raw_exchange(faults:FaultConfig) -> String {
+        let state:SharedState = Arc::new(RwLock::new(MutableStaticData::new(&Environment::PROD)));
+        state.write().await.faults = faults;
+
+        let fake_app = Router::new()
+            .route("/collection/{collection_id}/contents", get(|Extension(state): Extension<SharedState>| async move {
+                state.write().await.mark_changed();
+                "0123456789abcdefghij"
+            }))
+            .layer(middleware::from_fn(inject_faults))
+            .layer(Extension(state));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, fake_app).await });
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"GET /collection/c1/contents HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await.unwrap();
+
+        let mut received = Vec::new();
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut received)).await;
+        assert!(read.is_ok(), "the server never finished responding");
+        String::from_utf8_lossy(&received).into_owned()
+    }
+
+    #[test]
+    fn test_matching() {
+        let mut put_contents = rule("/collection/{collection_id}/contents");
+        put_contents.method = Some("put".into());
+        let config = FaultConfig{
+            rules: vec![put_contents, rule("/collection/*")],
+        };
+
+        assert_eq!(config.matching(&Method::PUT, "/collection/c1/contents").map(|r| r.method.is_some()), Some(true));
+        assert_eq!(config.matching(&Method::GET, "/collection/c1/contents"), None);
+        assert_eq!(config.matching(&Method::GET, "/collection/c1").map(|r| r.path.as_str()), Some("/collection/*"));
+        assert_eq!(config.matching(&Method::GET, "/collection"), None);
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut bad = rule("collection");
+        bad.method = Some("not a method".into());
+        bad.latency = Some(LatencyFault{ min_ms: 10, max_ms: Some(5) });
+        bad.error = Some(ErrorFault{ status: 404, probability: 1.5 });
+
+        let config = FaultConfig{
+            rules: vec![rule("/collection"), bad],
+        };
+        let problems = config.validate();
+        assert_eq!(problems.len(), 5);
+        assert!(problems.iter().all(|p| p.starts_with("rule #1:")));
+    }
+
+    #[test]
+    fn test_parse_toml() {
+        let parsed:FaultConfig = toml::from_str(r#"
+            [[rules]]
+            path = "/collection"
+            latency = { minMs = 5 }
+            error = { status = 503 }
+        "#).unwrap();
+
+        assert_eq!(parsed.rules[0].latency, Some(LatencyFault{ min_ms: 5, max_ms: None }));
+        assert_eq!(parsed.rules[0].error, Some(ErrorFault{ status: 503, probability: 1.0 }));
+        assert!(parsed.validate().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_error_fault() {
+        let mut failing = rule("/collection/{collection_id}/contents");
+        failing.error = Some(ErrorFault{ status: 503, probability: 1.0 });
+
+        let received = raw_exchange(FaultConfig{ rules: vec![failing] }).await;
+        assert!(received.starts_with("HTTP/1.1 503"), "{}", received);
+        assert!(received.contains("injected-fault"));
+    }
+
+    #[tokio::test]
+    async fn test_truncate_fault() {
+        let mut truncating = rule("/collection/{collection_id}/contents");
+        truncating.truncate = Some(TruncateFault{ after_bytes: 5, probability: 1.0 });
+
+        let received = raw_exchange(FaultConfig{ rules: vec![truncating] }).await;
+        assert!(received.starts_with("HTTP/1.1 200"), "{}", received);
+        assert!(received.contains("content-length: 20"), "{}", received);
+        assert!(received.ends_with("\r\n\r\n01234"), "{}", received);
+    }
+
+    #[tokio::test]
+    async fn test_close_connection_fault() {
+        let mut closing = rule("/collection/{collection_id}/contents");
+        closing.close_connection = Some(CloseConnectionFault{ probability: 1.0 });
+
+        let received = raw_exchange(FaultConfig{ rules: vec![closing] }).await;
+        assert_eq!(received, "");
+    }
+
+    #[tokio::test]
+    async fn test_out_of_range_probability() {
+        let mut failing = rule("/collection/{collection_id}/contents");
+        failing.error = Some(ErrorFault{ status: 503, probability: 1.5 });
+        let mut never = rule("/collection");
+        never.truncate = Some(TruncateFault{ after_bytes: 5, probability: -0.5 });
+        never.close_connection = Some(CloseConnectionFault{ probability: f64::NAN });
+        let faults = FaultConfig{ rules: vec![failing, never] };
+        assert_eq!(faults.validate().len(), 3);
+
+        let server = TestServer::new(crate::MockServer::builder().faults(faults).router()).unwrap();
+        server.get("/collection/c1/contents").await.assert_status(StatusCode::SERVICE_UNAVAILABLE);
+        server.get("/collection").await.assert_status_ok();
+    }
+
+    #[tokio::test]
+    async fn test_no_rules() {
+        let received = raw_exchange(FaultConfig::default()).await;
+        assert!(received.ends_with("0123456789abcdefghij"), "{}", received);
+    }
+
+    #[tokio::test]
+    async fn test_no_faults() {
+        let mut never = rule("/collection/{collection_id}/contents");
+        never.error = Some(ErrorFault{ status: 500, probability: 0.0 });
+
+        let received = raw_exchange(FaultConfig{ rules: vec![never] }).await;
+        assert!(received.ends_with("0123456789abcdefghij"), "{}", received);
+    }
+
+    #[test]
+    fn test_plan_from_headers() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(FaultPlan::from_headers(&headers), Ok(FaultPlan::default()));
+
+        headers.insert("X-Mock-Status", HeaderValue::from_static("503"));
+        headers.insert("X-Mock-Delay-Ms", HeaderValue::from_static("20"));
+        headers.insert("X-Mock-Fail-After-Bytes", HeaderValue::from_static("7"));
+        assert_eq!(FaultPlan::from_headers(&headers), Ok(FaultPlan{
+            delay: Some(Duration::from_millis(20)),
+            close_connection: false,
+            status: Some(StatusCode::SERVICE_UNAVAILABLE),
+            fail_after_bytes: Some(7),
+        }));
+
+        headers.insert("X-Mock-Status", HeaderValue::from_static("200"));
+        assert!(FaultPlan::from_headers(&headers).is_err());
+
+        headers.insert("X-Mock-Status", HeaderValue::from_static("soon"));
+        assert!(FaultPlan::from_headers(&headers).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_mock_headers() {
+        let fake_app = Router::new()
+            .route("/collection", get(|| async { "all good" }))
+            .layer(middleware::from_fn(mock_header_faults));
+
+        let fake_server = TestServer::new(fake_app).unwrap();
+
+        fake_server.get("/collection").await.assert_text("all good");
+
+        let failed = fake_server.get("/collection")
+            .add_header("X-Mock-Status", "429")
+            .await;
+        failed.assert_status(StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(failed.header(header::CONTENT_TYPE), "application/problem+json");
+
+        fake_server.get("/collection")
+            .add_header("X-Mock-Delay-Ms", "-5")
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+    }
+}
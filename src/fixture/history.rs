@@ -0,0 +1,254 @@
+use std::collections::{HashMap, VecDeque};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+pub const DEFAULT_HISTORY_LENGTH:usize = 100;
+
+/// How a collection's content changed in one version. A recipe that moves to the front of a recentlyViewed collection counts as added.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ContentChange {
+    #[serde(default, skip_serializing_if="Vec::is_empty")]
+    pub added: Vec<String>,
+    #[serde(default, skip_serializing_if="Vec::is_empty")]
+    pub removed: Vec<String>,
+}
+
+impl ContentChange {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// A version of a collection and when it came about
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+struct Point {
+    version: u64,
+    #[serde(with="time::serde::rfc3339")]
+    at: OffsetDateTime,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Revision {
+    version: u64,
+    #[serde(with="time::serde::rfc3339")]
+    at: OffsetDateTime,
+    #[serde(flatten)]
+    change: ContentChange,
+}
+
+/// The point a delta sync starts from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Since {
+    Version(u64),
+    Time(OffsetDateTime),
+}
+
+impl Since {
+    /// Either a version number from an earlier delta or ETag, or an RFC 3339 timestamp
+    pub fn parse(value:&str) -> Option<Since> {
+        match value.parse::<u64>() {
+            Ok(version)=>Some(Since::Version(version)),
+            Err(_)=>OffsetDateTime::parse(value, &time::format_description::well_known::Rfc3339).ok().map(Since::Time),
+        }
+    }
+}
+
+/// A recipe that has been removed, and when
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tombstone {
+    pub recipe_id: String,
+    pub version: u64,
+    pub removed_at: OffsetDateTime,
+}
+
+/// The net effect of every change after a given point. A recipe appears in at most one of `added` and `removed`,
+/// according to whatever last happened to it; `added` is in the order the recipes were last added.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Delta {
+    pub added: Vec<String>,
+    pub removed: Vec<Tombstone>,
+}
+
+/// The most recent changes to a collection's content, for delta syncs. Only the last few versions are kept;
+/// asking for changes since anything older gets `None`, and the client has to fetch the whole collection again.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ChangeLog {
+    revisions: VecDeque<Revision>,
+    /// The version and time just before the oldest revision kept. `None` until something is recorded,
+    /// in which case the history starts at the collection's current version.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    base: Option<Point>,
+}
+
+impl ChangeLog {
+    /// A history that starts at `version`, as of `at`. Asking for changes since anything before that gets `None`.
+    pub fn starting_at(version:u64, at:OffsetDateTime) -> ChangeLog {
+        ChangeLog{
+            revisions: VecDeque::new(),
+            base: Some(Point{ version, at }),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.revisions.is_empty() && self.base.is_none()
+    }
+
+    /// Notes that the collection went from `previous` to `version` at `at`, dropping the oldest revisions beyond `max_length`
+    pub fn record(&mut self, previous:(u64, OffsetDateTime), version:u64, at:OffsetDateTime, change:ContentChange, max_length:usize) {
+        self.base.get_or_insert(Point{ version: previous.0, at: previous.1 });
+        self.revisions.push_back(Revision{ version, at, change });
+
+        while self.revisions.len() > max_length {
+            if let Some(dropped) = self.revisions.pop_front() {
+                self.base = Some(Point{ version: dropped.version, at: dropped.at });
+            }
+        }
+    }
+
+    /// Everything that changed after `since`, for a collection that is now at `current`. `None` if `since` is from
+    /// before the history kept, or is a version this collection has never had. Resets and restores start the history
+    /// again past every version and time handed out before, so anything from then gets `None` too.
+    pub fn since(&self, since:Since, current:(u64, OffsetDateTime)) -> Option<Delta> {
+        let (base_version, base_time) = self.base.map(|base| (base.version, base.at)).unwrap_or(current);
+
+        let revisions:Vec<&Revision> = match since {
+            Since::Version(version) if version < base_version || version > current.0=>return None,
+            Since::Version(version)=>self.revisions.iter().filter(|r| r.version > version).collect(),
+            Since::Time(time) if time < base_time=>return None,
+            Since::Time(time)=>self.revisions.iter().filter(|r| r.at > time).collect(),
+        };
+
+        //The last thing that happened to each recipe, and the order it happened in
+        let mut last_change:HashMap<&str, (usize, Option<&Revision>)> = HashMap::new();
+        let mut sequence = 0;
+        for revision in revisions {
+            for recipe_id in revision.change.removed.iter() {
+                last_change.insert(recipe_id, (sequence, Some(revision)));
+                sequence += 1;
+            }
+            for recipe_id in revision.change.added.iter() {
+                last_change.insert(recipe_id, (sequence, None));
+                sequence += 1;
+            }
+        }
+
+        let mut changes:Vec<(&str, (usize, Option<&Revision>))> = last_change.into_iter().collect();
+        changes.sort_by_key(|(_, (sequence, _))| *sequence);
+
+        let mut delta = Delta::default();
+        for (recipe_id, (_, removal)) in changes {
+            match removal {
+                None=>delta.added.push(recipe_id.to_owned()),
+                Some(revision)=>delta.removed.push(Tombstone{
+                    recipe_id: recipe_id.to_owned(),
+                    version: revision.version,
+                    removed_at: revision.at,
+                }),
+            }
+        }
+        Some(delta)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use super::*;
+
+    fn change(added:&[&str], removed:&[&str]) -> ContentChange {
+        ContentChange{
+            added: added.iter().map(|s| s.to_string()).collect(),
+            removed: removed.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    /// Records `changes` one second apart, starting at version 1, returning the time of version 1
+    fn log_of(changes:Vec<ContentChange>, max_length:usize) -> (ChangeLog, OffsetDateTime) {
+        let start = OffsetDateTime::from_unix_timestamp(1737367200).unwrap();
+        let mut log = ChangeLog::default();
+        for (idx, change) in changes.into_iter().enumerate() {
+            let previous = (idx as u64 + 1, start + Duration::from_secs(idx as u64));
+            log.record(previous, previous.0 + 1, previous.1 + Duration::from_secs(1), change, max_length);
+        }
+        (log, start)
+    }
+
+    #[test]
+    fn test_since_version() {
+        let (log, start) = log_of(vec![
+            change(&["r1", "r2"], &[]),
+            change(&[], &["r1", "r3"]),
+            change(&["r3"], &[]),
+        ], 10);
+        let current = (4, start + Duration::from_secs(3));
+
+        let delta = log.since(Since::Version(1), current).unwrap();
+        assert_eq!(delta.added, vec!["r2", "r3"]);
+        assert_eq!(delta.removed.len(), 1);
+        assert_eq!(delta.removed[0].recipe_id, "r1");
+        assert_eq!(delta.removed[0].version, 3);
+
+        let delta = log.since(Since::Version(3), current).unwrap();
+        assert_eq!(delta.added, vec!["r3"]);
+        assert!(delta.removed.is_empty());
+
+        assert_eq!(log.since(Since::Version(4), current), Some(Delta::default()));
+        assert_eq!(log.since(Since::Version(5), current), None);
+    }
+
+    #[test]
+    fn test_starting_at() {
+        let start = OffsetDateTime::from_unix_timestamp(1737367200).unwrap();
+        let mut log = ChangeLog::starting_at(10, start);
+        let current = (10, start - Duration::from_secs(60));
+
+        assert_eq!(log.since(Since::Version(10), current), Some(Delta::default()));
+        assert_eq!(log.since(Since::Version(3), current), None);
+        assert_eq!(log.since(Since::Time(start - Duration::from_secs(1)), current), None);
+
+        log.record((10, start), 11, start + Duration::from_secs(1), change(&["r1"], &[]), 10);
+        assert_eq!(log.since(Since::Version(10), (11, start + Duration::from_secs(1))).unwrap().added, vec!["r1"]);
+    }
+
+    #[test]
+    fn test_since_time() {
+        let (log, start) = log_of(vec![
+            change(&["r1"], &[]),
+            change(&["r2"], &[]),
+        ], 10);
+        let current = (3, start + Duration::from_secs(2));
+
+        let delta = log.since(Since::Time(start + Duration::from_millis(1500)), current).unwrap();
+        assert_eq!(delta.added, vec!["r2"]);
+        assert_eq!(log.since(Since::Time(start - Duration::from_secs(1)), current), None);
+    }
+
+    #[test]
+    fn test_window() {
+        let (log, start) = log_of(vec![
+            change(&["r1"], &[]),
+            change(&["r2"], &[]),
+            change(&["r3"], &[]),
+        ], 2);
+        let current = (4, start + Duration::from_secs(3));
+
+        assert_eq!(log.since(Since::Version(1), current), None);
+        assert_eq!(log.since(Since::Version(2), current).unwrap().added, vec!["r2", "r3"]);
+    }
+
+    #[test]
+    fn test_no_history_yet() {
+        let log = ChangeLog::default();
+        let now = OffsetDateTime::now_utc();
+
+        assert_eq!(log.since(Since::Version(1), (1, now)), Some(Delta::default()));
+        assert_eq!(log.since(Since::Version(0), (1, now)), None);
+    }
+
+    #[test]
+    fn test_parse_since() {
+        assert_eq!(Since::parse("12"), Some(Since::Version(12)));
+        assert_eq!(Since::parse("2025-01-20T10:00:00Z"), OffsetDateTime::from_unix_timestamp(1737367200).ok().map(Since::Time));
+        assert_eq!(Since::parse("yesterday"), None);
+    }
+}
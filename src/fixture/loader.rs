@@ -0,0 +1,183 @@
+use std::{collections::HashSet, path::Path};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::config::{load_config, save_config, ConfigError};
+use super::models::CollectionKind;
+
+/// On-disk representation of a fixture. JSON, TOML or YAML is accepted, the format being chosen
+/// from the file extension. For example, in TOML:
+///
+/// ```toml
+/// [[collections]]
+/// id = "F8895D13-CCB2-4864-9DE6-C35A1FC943BE"
+/// kind = "saved"
+/// name = "Saved recipes"                  # optional
+/// recipes = ["ed9e148c614d47f0b236f5ce7113d196"]
+/// lastModified = "2025-01-20T10:00:00Z"   # optional, RFC3339 string
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct FixtureFile {
+    pub collections: Vec<FixtureCollection>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct FixtureCollection {
+    pub id: String,
+    pub kind: CollectionKind,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub recipes: Vec<String>,
+    #[serde(rename="lastModified", default, with="time::serde::rfc3339::option", skip_serializing_if="Option::is_none")]
+    pub last_modified: Option<OffsetDateTime>,
+}
+
+impl FixtureFile {
+    /// Reads, parses and validates the fixture at the given path
+    pub fn load(path:&Path) -> Result<FixtureFile, ConfigError> {
+        let parsed:FixtureFile = load_config(path)?;
+        ConfigError::check(path, parsed.validate())?;
+        Ok(parsed)
+    }
+
+    /// Writes the fixture out, in whichever format the file extension calls for
+    pub fn save(&self, path:&Path) -> Result<(), ConfigError> {
+        save_config(path, self)
+    }
+
+    /// Checks there is at least one collection, and that no collection ID or recipe ID within a collection is empty
+    /// or used twice
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems:Vec<String> = Vec::new();
+        let mut seen_ids:HashSet<&str> = HashSet::new();
+
+        if self.collections.is_empty() {
+            problems.push("at least one collection must be defined".into());
+        }
+
+        for (idx, collection) in self.collections.iter().enumerate() {
+            if collection.id.trim().is_empty() {
+                problems.push(format!("collection #{} has an empty id", idx));
+            } else if !seen_ids.insert(collection.id.as_str()) {
+                problems.push(format!("collection #{} re-uses the id {}", idx, collection.id));
+            }
+
+            let mut seen_recipes:HashSet<&str> = HashSet::new();
+            for recipe_id in collection.recipes.iter() {
+                if recipe_id.trim().is_empty() {
+                    problems.push(format!("collection {} contains an empty recipe id", collection.id));
+                } else if !seen_recipes.insert(recipe_id.as_str()) {
+                    problems.push(format!("collection {} lists recipe {} more than once", collection.id, recipe_id));
+                }
+            }
+        }
+
+        problems
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, io::Write, path::PathBuf};
+    use super::*;
+
+    fn write_temp(name:&str, content:&str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}-{}", std::process::id(), name));
+        let mut f = fs::File::create(&path).unwrap();
+        f.write_all(content.as_bytes()).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_load_json() {
+        let path = write_temp("fixture.json", r#"{
+            "collections": [
+                {"id": "c1", "kind": "saved", "recipes": ["r1", "r2"], "lastModified": "2025-01-20T10:00:00Z"},
+                {"id": "c2", "kind": "cooked"}
+            ]
+        }"#);
+
+        let fixture = FixtureFile::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(fixture.collections.len(), 2);
+        assert_eq!(fixture.collections[0].kind, CollectionKind::Saved);
+        assert_eq!(fixture.collections[0].recipes, vec!["r1".to_string(), "r2".to_string()]);
+        assert_eq!(fixture.collections[0].last_modified.map(|t| t.unix_timestamp()), Some(1737367200));
+        assert_eq!(fixture.collections[1].recipes.len(), 0);
+        assert_eq!(fixture.collections[1].last_modified, None);
+    }
+
+    #[test]
+    fn test_load_toml() {
+        let path = write_temp("fixture.toml", r#"
+            [[collections]]
+            id = "c1"
+            kind = "userCreated"
+            recipes = ["r1"]
+        "#);
+
+        let fixture = FixtureFile::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(fixture.collections.len(), 1);
+        assert_eq!(fixture.collections[0].kind, CollectionKind::UserCreated);
+    }
+
+    #[test]
+    fn test_save_roundtrip() {
+        let path = std::env::temp_dir().join(format!("{}-saved-fixture.toml", std::process::id()));
+        let fixture = FixtureFile{
+            collections: vec![FixtureCollection{
+                id: "c1".into(),
+                kind: CollectionKind::Saved,
+                name: None,
+                recipes: vec!["r1".into(), "r2".into()],
+                last_modified: OffsetDateTime::from_unix_timestamp(1737367200).ok(),
+            }],
+        };
+
+        fixture.save(&path).unwrap();
+        let loaded = FixtureFile::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.collections[0].recipes, fixture.collections[0].recipes);
+        assert_eq!(loaded.collections[0].last_modified, fixture.collections[0].last_modified);
+    }
+
+    #[test]
+    fn test_load_invalid() {
+        let path = write_temp("invalid.json", r#"{
+            "collections": [
+                {"id": "c1", "kind": "saved", "recipes": ["r1", "r1"]},
+                {"id": "c1", "kind": "cooked", "recipes": [""]}
+            ]
+        }"#);
+
+        let result = FixtureFile::load(&path);
+        fs::remove_file(&path).unwrap();
+
+        match result {
+            Err(ConfigError::Invalid(_, problems))=>{
+                assert_eq!(problems.len(), 3);
+                assert_eq!(problems[0], "collection c1 lists recipe r1 more than once");
+                assert_eq!(problems[1], "collection #1 re-uses the id c1");
+                assert_eq!(problems[2], "collection c1 contains an empty recipe id");
+            },
+            other=>panic!("Unexpected return value {:?}", other)
+        }
+    }
+
+    #[test]
+    fn test_load_unknown_kind() {
+        let path = write_temp("badkind.json", r#"{"collections": [{"id": "c1", "kind": "favourites"}]}"#);
+
+        let result = FixtureFile::load(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert!(matches!(result, Err(ConfigError::Json(_, _))));
+    }
+}
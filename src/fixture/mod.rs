@@ -1,42 +1,40 @@
-use std::collections::HashMap;
+use std::{collections::{HashMap, HashSet}, fmt, sync::Arc, time::Duration};
 
-use models::{CollectionResponse, CollectionsResponse};
+use history::{ChangeLog, ContentChange, Delta, Since};
+use loader::{FixtureCollection, FixtureFile};
+use recipe_set::RecipeSet;
+use models::{CollectionKind, CollectionResponse, CollectionsResponse};
+use serde::{Deserialize, Serialize};
 use time::OffsetDateTime;
+use tokio::sync::Notify;
+use crate::{events::EventBus, faults::FaultConfig, stubs::Stub};
 
-mod models;
+pub mod history;
+pub mod loader;
+pub mod models;
+pub mod recipe_set;
 
-#[derive(clap::ValueEnum, Debug, Clone, Default)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(clap::ValueEnum, Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all="lowercase")]
 pub enum Environment {
     CODE,
     #[default]
     PROD
 }
 
-impl ToString for Environment {
-    fn to_string(&self) -> String {
+impl fmt::Display for Environment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Environment::CODE=>"code".into(),
-            Environment::PROD=>"prod".into()
+            Environment::CODE=>write!(f, "code"),
+            Environment::PROD=>write!(f, "prod"),
         }
     }
 }
 
-pub fn gen_user_collections(timestamp: OffsetDateTime) -> CollectionsResponse {
-    CollectionsResponse{
-        collections: vec![
-            CollectionResponse{
-                id: "F8895D13-CCB2-4864-9DE6-C35A1FC943BE".into(),
-                collection_type: models::CollectionKind::Saved,
-                last_modified: timestamp,
-            },
-            CollectionResponse{
-                id: "22468120-81C4-4E4A-8B9D-71AEE5E25C40".into(),
-                collection_type: models::CollectionKind::Cooked,
-                last_modified: timestamp,
-            },
-        ]
-    }
-}
+pub const SAVED_COLLECTION_ID:&str = "F8895D13-CCB2-4864-9DE6-C35A1FC943BE";
+pub const COOKED_COLLECTION_ID:&str = "22468120-81C4-4E4A-8B9D-71AEE5E25C40";
+pub const RECENTLY_VIEWED_COLLECTION_ID:&str = "6D3C1E0A-5B8F-4E8D-9C3A-2F1B7A4E9D10";
 
 pub const CODE_RECIPES_SAVED_SAMPLE:[&str; 6] = [
     "99ea87d53eb3dc2f2f445b38919d9b9cbda4b7b1",
@@ -64,7 +62,7 @@ pub const PROD_RECIPES_SAVED_SAMPLE:[&str; 6] = [
     "d5a731c27edd44ff9df5b9dd4f8d983e"
 ];
 
-const PROD_RECIPES_COOKED_SAMPLE:[&str; 5] = [
+pub const PROD_RECIPES_COOKED_SAMPLE:[&str; 5] = [
     "01d206bc58b54044836e3c5046101062",
     "cfac27aee0d01ea8718a61fd1d23f441402dee17",
     "17ad58e0cffc482b8b0e591a94e30809",
@@ -72,40 +70,482 @@ const PROD_RECIPES_COOKED_SAMPLE:[&str; 5] = [
     "9a6b1e956f774667ad7562d6410ab73e"
 ];
 
+impl FixtureFile {
+    /// The compiled-in fixture, used when no --fixture is given
+    pub fn builtin(env:&Environment) -> FixtureFile {
+        let (saved, cooked):(&[&str], &[&str]) = match env {
+            Environment::CODE=>(&CODE_RECIPES_SAVED_SAMPLE, &CODE_RECIPES_COOKED_SAMPLE),
+            Environment::PROD=>(&PROD_RECIPES_SAVED_SAMPLE, &PROD_RECIPES_COOKED_SAMPLE),
+        };
+
+        FixtureFile{
+            collections: vec![
+                FixtureCollection{
+                    id: SAVED_COLLECTION_ID.into(),
+                    kind: CollectionKind::Saved,
+                    name: None,
+                    recipes: saved.iter().map(|v| v.to_string()).collect(),
+                    last_modified: None,
+                },
+                FixtureCollection{
+                    id: COOKED_COLLECTION_ID.into(),
+                    kind: CollectionKind::Cooked,
+                    name: None,
+                    recipes: cooked.iter().map(|v| v.to_string()).collect(),
+                    last_modified: None,
+                },
+                FixtureCollection{
+                    id: RECENTLY_VIEWED_COLLECTION_ID.into(),
+                    kind: CollectionKind::RecentlyViewed,
+                    name: None,
+                    recipes: Vec::new(),
+                    last_modified: None,
+                },
+            ]
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CollectionData {
+    pub kind: CollectionKind,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub name: Option<String>,
+    pub content: RecipeSet,
+    #[serde(rename="lastModified", with="time::serde::rfc3339")]
+    pub last_modified: OffsetDateTime,
+    /// Incremented every time the content changes; this is what the ETag is made from
+    pub version: u64,
+    /// For recentlyViewed collections, when each recipe was last viewed
+    #[serde(rename="viewedAt", default, skip_serializing_if="HashMap::is_empty")]
+    pub viewed_at: HashMap<String, OffsetDateTime>,
+    /// The latest changes to the content, for /changes
+    #[serde(default, skip_serializing_if="ChangeLog::is_empty")]
+    pub history: ChangeLog,
+}
+
+/// Controls how recentlyViewed collections behave
+#[derive(Debug, Clone)]
+pub struct RecentlyViewedPolicy {
+    /// The oldest entries are dropped once a collection grows beyond this
+    pub max_length: usize,
+    /// If set, entries that were viewed longer ago than this are dropped
+    pub max_age: Option<Duration>,
+}
+
+impl Default for RecentlyViewedPolicy {
+    fn default() -> Self {
+        RecentlyViewedPolicy{
+            max_length: 20,
+            max_age: None,
+        }
+    }
+}
+
+impl CollectionData {
+    pub fn new(kind:CollectionKind, name:Option<String>) -> CollectionData {
+        CollectionData{
+            kind,
+            name,
+            content: RecipeSet::new(),
+            last_modified: OffsetDateTime::now_utc(),
+            version: 1,
+            viewed_at: HashMap::new(),
+            history: ChangeLog::default(),
+        }
+    }
+
+    /// Adds recipes to a recentlyViewed collection, most recent first. Each recipe in `recipe_ids` counts as viewed after
+    /// the one before it, so the last one ends up at the front. A recipe that is already present moves to the front rather
+    /// than appearing twice. Returns what changed, which is nothing if the order of the content stayed the same;
+    /// otherwise every recipe viewed counts as added, in the order they now come to the front.
+    pub fn record_views(&mut self, recipe_ids:&[&str], policy:&RecentlyViewedPolicy) -> ContentChange {
+        let now = OffsetDateTime::now_utc();
+        let original:Vec<String> = self.content.iter().cloned().collect();
+
+        for recipe_id in recipe_ids {
+            self.content.push_front(recipe_id);
+            self.viewed_at.insert(recipe_id.to_string(), now);
+        }
+
+        let mut evicted:Vec<String> = Vec::new();
+        while self.content.len() > policy.max_length {
+            if let Some(recipe_id) = self.content.pop_back() {
+                self.viewed_at.remove(&recipe_id);
+                evicted.push(recipe_id);
+            }
+        }
+
+        if self.content.iter().eq(original.iter()) {
+            return ContentChange::default()
+        }
+
+        let mut seen:HashSet<&str> = HashSet::new();
+        let mut added:Vec<String> = recipe_ids.iter().rev()
+            .filter(|recipe_id| seen.insert(recipe_id) && self.content.contains(recipe_id))
+            .map(|recipe_id| recipe_id.to_string())
+            .collect();
+        added.reverse();
+
+        ContentChange{
+            added,
+            removed: evicted,
+        }
+    }
+
+    /// Drops anything from a recentlyViewed collection that was viewed longer ago than the policy allows.
+    /// Recipes that came from the fixture count as viewed when the collection was last modified.
+    /// Returns the recipes that were removed.
+    pub fn expire_views(&mut self, policy:&RecentlyViewedPolicy) -> ContentChange {
+        let Some(cutoff) = self.view_cutoff(policy) else {
+            return ContentChange::default()
+        };
+
+        let last_modified = self.last_modified;
+        let viewed_at = &mut self.viewed_at;
+        let mut removed:Vec<String> = Vec::new();
+
+        self.content.retain(|id| {
+            let keep = viewed_at.get(id).copied().unwrap_or(last_modified) >= cutoff;
+            if !keep {
+                viewed_at.remove(id);
+                removed.push(id.to_owned());
+            }
+            keep
+        });
+
+        ContentChange{
+            added: Vec::new(),
+            removed,
+        }
+    }
+
+    /// Whether `expire_views` would remove anything, without the need to borrow the collection mutably
+    pub fn has_expired_views(&self, policy:&RecentlyViewedPolicy) -> bool {
+        self.view_cutoff(policy).is_some_and(|cutoff| {
+            self.content.iter().any(|id| self.viewed_at.get(id).copied().unwrap_or(self.last_modified) < cutoff)
+        })
+    }
+
+    /// Views before this have expired, if the policy expires views in this collection at all
+    fn view_cutoff(&self, policy:&RecentlyViewedPolicy) -> Option<OffsetDateTime> {
+        match policy.max_age {
+            Some(max_age) if self.kind==CollectionKind::RecentlyViewed=>Some(OffsetDateTime::now_utc() - max_age),
+            _=>None,
+        }
+    }
+
+    /// Records that the content has just changed, keeping the last `history_length` changes for /changes
+    pub fn touch(&mut self, change:ContentChange, history_length:usize) {
+        let previous = (self.version, self.last_modified);
+        self.version += 1;
+        self.last_modified = OffsetDateTime::now_utc();
+        self.history.record(previous, self.version, self.last_modified, change, history_length);
+    }
+
+    /// What has happened to the content since the given point, or `None` if that is older than the history kept
+    pub fn changes_since(&self, since:Since) -> Option<Delta> {
+        self.history.since(since, (self.version, self.last_modified))
+    }
+
+    /// How the collection appears in the /collection listing
+    pub fn describe(&self, id:&str) -> CollectionResponse {
+        CollectionResponse{
+            id: id.to_owned(),
+            collection_type: self.kind,
+            name: self.name.to_owned(),
+            last_modified: self.last_modified,
+        }
+    }
+}
+
+/// The collections belonging to a single caller
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UserData {
+    pub collections:HashMap<String, CollectionData>,
+    /// Incremented whenever any of the user's collections change, so that the listing can have an ETag too
+    pub version: u64,
+    /// When a collection was last created, renamed or deleted
+    #[serde(default="OffsetDateTime::now_utc", with="time::serde::rfc3339")]
+    pub modified: OffsetDateTime,
+}
+
+impl UserData {
+    /// Builds a fresh copy of the fixture. Collections that don't specify a lastModified are treated as modified at `timestamp`
+    pub fn from_fixture(fixture:&FixtureFile, timestamp: OffsetDateTime) -> UserData {
+        let collections:HashMap<String, CollectionData> = fixture.collections.iter().map(|c| {
+            (
+                c.id.to_owned(),
+                CollectionData{
+                    kind: c.kind,
+                    name: c.name.to_owned(),
+                    content: c.recipes.iter().cloned().collect(),
+                    last_modified: c.last_modified.unwrap_or(timestamp),
+                    version: 1,
+                    viewed_at: HashMap::new(),
+                    history: ChangeLog::default(),
+                }
+            )
+        }).collect();
+
+        let modified = collections.values().map(|c| c.last_modified).max().unwrap_or(timestamp);
+
+        UserData{
+            collections,
+            version: 1,
+            modified,
+        }
+    }
+
+    /// Lists the collections currently held, ordered by kind and then id so that the output is stable.
+    pub fn user_collections(&self) -> CollectionsResponse {
+        let mut collections:Vec<CollectionResponse> = self.collections.iter().map(|(id, c)| c.describe(id)).collect();
+
+        collections.sort_by(|a, b| a.collection_type.cmp(&b.collection_type).then_with(|| a.id.cmp(&b.id)));
+
+        CollectionsResponse{
+            collections
+        }
+    }
+
+    /// The most recent modification time of the listing or any of the user's collections
+    pub fn last_modified(&self) -> OffsetDateTime {
+        self.collections.values().map(|c| c.last_modified).max().unwrap_or(self.modified).max(self.modified)
+    }
+
+    /// Records that a collection has just been created, renamed or deleted
+    pub fn touch(&mut self) {
+        self.version += 1;
+        self.modified = OffsetDateTime::now_utc();
+    }
+
+    /// The highest version of the listing or any of the user's collections
+    pub fn highest_version(&self) -> u64 {
+        self.collections.values().map(|c| c.version).max().unwrap_or(self.version).max(self.version)
+    }
+
+    /// Puts the listing and every collection at the start of `generation`. Their history is forgotten, as it is in
+    /// terms of versions that now belong to somebody else's timeline, and starts again when the generation did.
+    pub fn start_at(&mut self, generation:&Generation) {
+        self.version = generation.first_version;
+        for collection in self.collections.values_mut() {
+            collection.version = generation.first_version;
+            collection.history = match generation.started {
+                None=>ChangeLog::default(),
+                Some(started)=>ChangeLog::starting_at(generation.first_version, started),
+            };
+        }
+    }
+}
+
+/// Where versions start from after a reset or restore. They carry on from the highest version handed out so far,
+/// so an ETag or /changes `since` from before can't match content from after.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Generation {
+    /// The version new users' collections start at
+    #[serde(rename="firstVersion")]
+    pub first_version: u64,
+    /// When the last reset or restore happened, if there has been one
+    #[serde(with="time::serde::rfc3339::option")]
+    pub started: Option<OffsetDateTime>,
+}
+
+impl Default for Generation {
+    fn default() -> Self {
+        Generation{
+            first_version: 1,
+            started: None,
+        }
+    }
+}
+
+/// A saved copy of the state that can be restored later
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Snapshot {
+    pub env: Environment,
+    pub template: FixtureFile,
+    pub users: HashMap<String, UserData>,
+    #[serde(rename="createdAt", with="time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+}
+
+pub const DEFAULT_MAX_PAGE_SIZE:usize = 1000;
+
 #[derive(Debug)]
 pub struct MutableStaticData {
-    pub _env: Environment,
-    pub collections:HashMap<String, Vec<String>>,
+    /// The environment whose IDs are being served
+    pub env: Environment,
+    /// Every user starts off with a copy of this
+    pub template: FixtureFile,
+    pub users:HashMap<String, UserData>,
+    pub snapshots:HashMap<String, Snapshot>,
+    /// Moves on with every reset and restore
+    pub generation: Generation,
+    pub recently_viewed: RecentlyViewedPolicy,
+    /// The largest page of a collection's contents a client may ask for
+    pub max_page_size: usize,
+    /// How many changes to each collection are kept for /changes
+    pub history_length: usize,
+    /// Misbehaviour to inject into responses. Not affected by resets or snapshots.
+    pub faults: FaultConfig,
+    /// Canned responses for requests the real routes don't handle, in the order they were added. Not affected by resets or snapshots.
+    pub stubs: Vec<Stub>,
+    /// Responses the proxy got from the upstream server, as stubs that replay them. Not affected by resets or snapshots.
+    pub recordings: Vec<Stub>,
+    /// Woken whenever the data changes, so that it can be persisted
+    pub changes: Arc<Notify>,
+    /// Tells event streams about changes to collections' content. Not affected by resets or snapshots.
+    pub events: Arc<EventBus>,
 }
 
 impl MutableStaticData {
     pub fn new(env:&Environment) -> MutableStaticData {
-        match env {
-            Environment::CODE=>{
-                let mut collections:HashMap<String, Vec<String>> = HashMap::new();
-                let saved:Vec<String> = CODE_RECIPES_SAVED_SAMPLE.into_iter().map(|v| v.to_string()).collect();
-                let cooked:Vec<String> = CODE_RECIPES_COOKED_SAMPLE.into_iter().map(|v| v.to_string()).collect();
-
-                collections.insert("F8895D13-CCB2-4864-9DE6-C35A1FC943BE".into(), saved);
-                collections.insert("22468120-81C4-4E4A-8B9D-71AEE5E25C40".into(), cooked);
-
-                MutableStaticData{
-                    _env: env.clone(),
-                    collections: collections,
-                }
-            }
-            Environment::PROD=>{
-                let mut collections:HashMap<String, Vec<String>> = HashMap::new();
-                let saved:Vec<String> = PROD_RECIPES_SAVED_SAMPLE.into_iter().map(|v| v.to_string()).collect();
-                let cooked:Vec<String> = PROD_RECIPES_COOKED_SAMPLE.into_iter().map(|v| v.to_string()).collect();
-                collections.insert("F8895D13-CCB2-4864-9DE6-C35A1FC943BE".into(), saved);
-                collections.insert("22468120-81C4-4E4A-8B9D-71AEE5E25C40".into(), cooked);
-
-                MutableStaticData{
-                    _env: env.clone(),
-                    collections: collections,
-                }
-            }
+        MutableStaticData::from_fixture(env, &FixtureFile::builtin(env))
+    }
+
+    pub fn from_fixture(env:&Environment, fixture:&FixtureFile) -> MutableStaticData {
+        MutableStaticData{
+            env: env.clone(),
+            template: fixture.clone(),
+            users: HashMap::new(),
+            snapshots: HashMap::new(),
+            generation: Generation::default(),
+            recently_viewed: RecentlyViewedPolicy::default(),
+            max_page_size: DEFAULT_MAX_PAGE_SIZE,
+            history_length: history::DEFAULT_HISTORY_LENGTH,
+            faults: FaultConfig::default(),
+            stubs: Vec::new(),
+            recordings: Vec::new(),
+            changes: Arc::new(Notify::new()),
+            events: Arc::new(EventBus::default()),
+        }
+    }
+
+    /// Throws away every user's changes. If `env` is given then the built-in fixture for that environment
+    /// becomes the template, otherwise the current template is kept.
+    pub fn reset(&mut self, env:Option<&Environment>) {
+        if let Some(new_env) = env {
+            self.env = new_env.clone();
+            self.template = FixtureFile::builtin(new_env);
         }
+        self.next_generation();
+        self.users.clear();
+        self.mark_changed();
     }
-}
\ No newline at end of file
+
+    /// Copies the current env, template and users
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot{
+            env: self.env.clone(),
+            template: self.template.clone(),
+            users: self.users.clone(),
+            created_at: OffsetDateTime::now_utc(),
+        }
+    }
+
+    /// Replaces the env, template and users with those from the snapshot. Other snapshots are left alone.
+    /// The restored collections get new versions, as the ones in the snapshot may have been handed out since.
+    pub fn restore(&mut self, snapshot:&Snapshot) {
+        self.next_generation();
+        self.env = snapshot.env.clone();
+        self.template = snapshot.template.clone();
+        self.users = snapshot.users.clone();
+        for user_data in self.users.values_mut() {
+            user_data.start_at(&self.generation);
+        }
+        self.mark_changed();
+    }
+
+    /// Moves versions on past everything handed out so far
+    fn next_generation(&mut self) {
+        let highest = self.users.values().map(|u| u.highest_version()).max().unwrap_or(0);
+        self.generation.first_version = self.generation.first_version.max(highest + 1);
+        self.generation.started = Some(OffsetDateTime::now_utc());
+    }
+
+    /// Lets anything waiting on `changes` know that there is something new to save
+    pub fn mark_changed(&self) {
+        self.changes.notify_one();
+    }
+
+    /// Returns the data for the given user, creating it from the template if this is the first time we have seen them
+    pub fn user(&mut self, user_id:&str) -> &mut UserData {
+        let template = &self.template;
+        let generation = self.generation;
+        self.users.entry(user_id.to_owned()).or_insert_with(|| {
+            log::info!("Creating collections for new user {}", user_id);
+            let mut user_data = UserData::from_fixture(template, OffsetDateTime::now_utc());
+            user_data.start_at(&generation);
+            user_data
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn recently_viewed(content:&[&str]) -> CollectionData {
+        let mut collection = CollectionData::new(CollectionKind::RecentlyViewed, None);
+        collection.content = content.iter().map(|s| s.to_string()).collect();
+        collection
+    }
+
+    #[test]
+    fn test_record_views_moves_to_front() {
+        let policy = RecentlyViewedPolicy::default();
+        let mut collection = recently_viewed(&["r3", "r2", "r1"]);
+
+        assert_eq!(collection.record_views(&["r1"], &policy).added, vec!["r1"]);
+        assert_eq!(collection.content.iter().collect::<Vec<_>>(), vec!["r1", "r3", "r2"]);
+
+        assert_eq!(collection.record_views(&["r4", "r2"], &policy).added, vec!["r4", "r2"]);
+        assert_eq!(collection.content.iter().collect::<Vec<_>>(), vec!["r2", "r4", "r1", "r3"]);
+
+        //viewing the most recent one again doesn't change the order
+        assert!(collection.record_views(&["r2"], &policy).is_empty());
+        assert_eq!(collection.content.iter().collect::<Vec<_>>(), vec!["r2", "r4", "r1", "r3"]);
+    }
+
+    #[test]
+    fn test_record_views_evicts_oldest() {
+        let policy = RecentlyViewedPolicy{
+            max_length: 3,
+            max_age: None,
+        };
+        let mut collection = recently_viewed(&[]);
+
+        collection.record_views(&["r1", "r2", "r3", "r4"], &policy);
+        assert_eq!(collection.content.iter().collect::<Vec<_>>(), vec!["r4", "r3", "r2"]);
+        assert!(!collection.viewed_at.contains_key("r1"));
+
+        let change = collection.record_views(&["r2", "r5"], &policy);
+        assert_eq!(change.removed, vec!["r3"]);
+        assert_eq!(collection.content.iter().collect::<Vec<_>>(), vec!["r5", "r2", "r4"]);
+        assert!(!collection.viewed_at.contains_key("r3"));
+    }
+
+    #[test]
+    fn test_expire_views() {
+        let policy = RecentlyViewedPolicy{
+            max_length: 10,
+            max_age: Some(Duration::from_secs(3600)),
+        };
+        let mut collection = recently_viewed(&[]);
+        collection.record_views(&["r1", "r2", "r3"], &policy);
+        collection.viewed_at.insert("r1".into(), OffsetDateTime::now_utc() - Duration::from_secs(7200));
+
+        assert!(collection.has_expired_views(&policy));
+        assert_eq!(collection.expire_views(&policy).removed, vec!["r1"]);
+        assert_eq!(collection.content.iter().collect::<Vec<_>>(), vec!["r3", "r2"]);
+        assert!(!collection.has_expired_views(&policy));
+        assert!(collection.expire_views(&policy).is_empty());
+
+        //only recentlyViewed collections expire
+        let mut saved = CollectionData::new(CollectionKind::Saved, None);
+        saved.content.insert("r1");
+        saved.last_modified = OffsetDateTime::now_utc() - Duration::from_secs(7200);
+        assert!(!saved.has_expired_views(&policy));
+        assert!(saved.expire_views(&policy).is_empty());
+    }
+}
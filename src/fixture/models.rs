@@ -1,7 +1,8 @@
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
 //Note - use rfc2822 for last-modified and if-modified-since
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum CollectionKind {
     #[serde(rename="saved")]
     Saved,
@@ -14,16 +15,18 @@ pub enum CollectionKind {
 }
 
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
 pub struct CollectionResponse {
     pub id: String,
     #[serde(rename="collectionType")]
     pub collection_type:CollectionKind,
-    #[serde(rename="lastModified",serialize_with="time::serde::rfc3339::serialize")]
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub name: Option<String>,
+    #[serde(rename="lastModified",with="time::serde::rfc3339")]
     pub last_modified: time::OffsetDateTime   //also in header
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
 pub struct CollectionsResponse {
     pub collections:Vec<CollectionResponse>
-}
\ No newline at end of file
+}
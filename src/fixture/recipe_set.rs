@@ -0,0 +1,314 @@
+use std::collections::{HashMap, VecDeque};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Compaction waits until there are at least this many tombstones, so that small collections never bother
+const MIN_TOMBSTONES_TO_COMPACT:usize = 32;
+
+/// The recipes in a collection: a set that remembers the order things were added in.
+///
+/// Every recipe is given a position when it is added, after everything else (or before everything else, for
+/// `push_front`). Positions are never reused and don't change while the recipe is present, so adding or removing
+/// one recipe never moves any other. Membership checks, adds and removes are all O(1): a removed recipe just leaves
+/// a tombstone in its slot, and the slots are compacted once tombstones outnumber the recipes, which keeps the
+/// amortised cost constant and iteration proportional to the number of recipes.
+#[derive(Debug, Clone, Default)]
+pub struct RecipeSet {
+    /// In position order. Never starts or ends with a tombstone.
+    slots: VecDeque<Slot>,
+    /// Which slot each recipe is in, numbered so that `first_slot` is the one at the front
+    slot_numbers: HashMap<String, i64>,
+    first_slot: i64,
+    tombstones: usize,
+    /// The next position to hand out at the end
+    back: i64,
+    /// One before the lowest position handed out so far
+    front: i64,
+}
+
+#[derive(Debug, Clone)]
+struct Slot {
+    position: i64,
+    /// `None` once the recipe has been removed
+    recipe_id: Option<String>,
+}
+
+impl RecipeSet {
+    pub fn new() -> RecipeSet {
+        RecipeSet::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.slot_numbers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slot_numbers.is_empty()
+    }
+
+    pub fn contains(&self, recipe_id:&str) -> bool {
+        self.slot_numbers.contains_key(recipe_id)
+    }
+
+    /// Iterates the recipes in order
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item=&String> {
+        self.entries().map(|(_, id)| id)
+    }
+
+    /// Iterates the recipes in order along with their positions
+    pub fn entries(&self) -> impl DoubleEndedIterator<Item=(i64, &String)> {
+        RecipeSet::live(self.slots.range(..))
+    }
+
+    /// Iterates, in order, the recipes that come after `position`. There need not be a recipe at `position` itself.
+    pub fn entries_after(&self, position:i64) -> impl DoubleEndedIterator<Item=(i64, &String)> {
+        let start = self.slots.partition_point(|slot| slot.position <= position);
+        RecipeSet::live(self.slots.range(start..))
+    }
+
+    /// Iterates, in order, the recipes that come before `position`. There need not be a recipe at `position` itself.
+    pub fn entries_before(&self, position:i64) -> impl DoubleEndedIterator<Item=(i64, &String)> {
+        let end = self.slots.partition_point(|slot| slot.position < position);
+        RecipeSet::live(self.slots.range(..end))
+    }
+
+    fn live<'a>(slots:impl DoubleEndedIterator<Item=&'a Slot>) -> impl DoubleEndedIterator<Item=(i64, &'a String)> {
+        slots.filter_map(|slot| slot.recipe_id.as_ref().map(|id| (slot.position, id)))
+    }
+
+    fn next_back(&mut self) -> i64 {
+        self.back += 1;
+        self.back - 1
+    }
+
+    fn next_front(&mut self) -> i64 {
+        self.front -= 1;
+        self.front
+    }
+
+    /// Adds the recipe at the end. Returns false, leaving it where it was, if it is already present.
+    pub fn insert(&mut self, recipe_id:&str) -> bool {
+        if self.contains(recipe_id) {
+            return false
+        }
+
+        let position = self.next_back();
+        self.slot_numbers.insert(recipe_id.to_owned(), self.first_slot + self.slots.len() as i64);
+        self.slots.push_back(Slot{ position, recipe_id: Some(recipe_id.to_owned()) });
+        true
+    }
+
+    /// Puts the recipe at the start, moving it there if it is already present
+    pub fn push_front(&mut self, recipe_id:&str) {
+        if self.slots.front().and_then(|slot| slot.recipe_id.as_deref())==Some(recipe_id) {
+            return
+        }
+
+        self.remove(recipe_id);
+        let position = self.next_front();
+        self.first_slot -= 1;
+        self.slot_numbers.insert(recipe_id.to_owned(), self.first_slot);
+        self.slots.push_front(Slot{ position, recipe_id: Some(recipe_id.to_owned()) });
+    }
+
+    /// Returns false if the recipe wasn't present
+    pub fn remove(&mut self, recipe_id:&str) -> bool {
+        let Some(slot_number) = self.slot_numbers.remove(recipe_id) else {
+            return false
+        };
+
+        self.slots[(slot_number - self.first_slot) as usize].recipe_id = None;
+        self.tombstones += 1;
+        self.trim();
+        if self.tombstones >= MIN_TOMBSTONES_TO_COMPACT && self.tombstones > self.slot_numbers.len() {
+            self.compact();
+        }
+        true
+    }
+
+    pub fn pop_back(&mut self) -> Option<String> {
+        let recipe_id = self.slots.pop_back()?.recipe_id?;
+        self.slot_numbers.remove(&recipe_id);
+        self.trim();
+        Some(recipe_id)
+    }
+
+    /// Keeps only the recipes for which `keep` returns true
+    pub fn retain<F>(&mut self, mut keep:F) where F: FnMut(&str) -> bool {
+        let slot_numbers = &mut self.slot_numbers;
+        self.slots.retain(|slot| match &slot.recipe_id {
+            None=>false,
+            Some(recipe_id)=>{
+                let kept = keep(recipe_id);
+                if !kept {
+                    slot_numbers.remove(recipe_id.as_str());
+                }
+                kept
+            }
+        });
+        self.renumber();
+    }
+
+    /// Drops tombstones from either end, so the first and last slots always hold a recipe
+    fn trim(&mut self) {
+        while self.slots.front().is_some_and(|slot| slot.recipe_id.is_none()) {
+            self.slots.pop_front();
+            self.first_slot += 1;
+            self.tombstones -= 1;
+        }
+        while self.slots.back().is_some_and(|slot| slot.recipe_id.is_none()) {
+            self.slots.pop_back();
+            self.tombstones -= 1;
+        }
+    }
+
+    /// Drops every tombstone. Recipes keep their positions, but not their slots.
+    fn compact(&mut self) {
+        self.slots.retain(|slot| slot.recipe_id.is_some());
+        self.renumber();
+    }
+
+    /// Brings `slot_numbers` back in line once the slots have been closed up
+    fn renumber(&mut self) {
+        for (idx, slot) in self.slots.iter().enumerate() {
+            if let Some(slot_number) = slot.recipe_id.as_ref().and_then(|id| self.slot_numbers.get_mut(id)) {
+                *slot_number = idx as i64;
+            }
+        }
+        self.first_slot = 0;
+        self.tombstones = 0;
+    }
+}
+
+impl FromIterator<String> for RecipeSet {
+    /// Later duplicates are ignored
+    fn from_iter<T: IntoIterator<Item = String>>(iter: T) -> Self {
+        let mut set = RecipeSet::new();
+        for recipe_id in iter {
+            set.insert(&recipe_id);
+        }
+        set
+    }
+}
+
+/// Stored as a plain list, so state files look the same as they did when collections were a Vec
+impl Serialize for RecipeSet {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+impl<'de> Deserialize<'de> for RecipeSet {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let list:Vec<String> = Vec::deserialize(deserializer)?;
+        Ok(list.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn set_of(ids:&[&str]) -> RecipeSet {
+        ids.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn position(set:&RecipeSet, recipe_id:&str) -> i64 {
+        set.entries().find(|(_, id)| *id==recipe_id).map(|(position, _)| position).unwrap()
+    }
+
+    #[test]
+    fn test_insert_is_idempotent() {
+        let mut set = set_of(&["r1", "r2", "r3"]);
+
+        assert!(!set.insert("r1"));
+        assert!(set.insert("r4"));
+        assert!(!set.insert("r2"));
+
+        assert_eq!(set.len(), 4);
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec!["r1", "r2", "r3", "r4"]);
+    }
+
+    #[test]
+    fn test_remove_keeps_order() {
+        let mut set = set_of(&["r1", "r2", "r3", "r4"]);
+
+        assert!(set.remove("r2"));
+        assert!(!set.remove("r2"));
+        assert!(!set.contains("r2"));
+        set.insert("r2");
+
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec!["r1", "r3", "r4", "r2"]);
+
+        //removing the last recipe must not let its position be handed out again
+        let old_position = position(&set, "r2");
+        set.remove("r2");
+        set.insert("r5");
+        assert!(position(&set, "r5") > old_position);
+    }
+
+    #[test]
+    fn test_push_front_and_pop_back() {
+        let mut set = set_of(&["r1", "r2", "r3"]);
+
+        set.push_front("r3");
+        set.push_front("r0");
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec!["r0", "r3", "r1", "r2"]);
+
+        assert_eq!(set.pop_back(), Some("r2".to_string()));
+        assert!(!set.contains("r2"));
+        assert_eq!(set.len(), 3);
+    }
+
+    #[test]
+    fn test_entries_after_and_before() {
+        let mut set = set_of(&["r1", "r2", "r3", "r4"]);
+        let r2_position = position(&set, "r2");
+        set.remove("r2");
+
+        let after:Vec<&String> = set.entries_after(r2_position).map(|(_, id)| id).collect();
+        assert_eq!(after, vec!["r3", "r4"]);
+
+        let before:Vec<&String> = set.entries_before(r2_position).map(|(_, id)| id).collect();
+        assert_eq!(before, vec!["r1"]);
+    }
+
+    #[test]
+    fn test_compaction() {
+        let ids:Vec<String> = (0..200).map(|n| format!("r{}", n)).collect();
+        let mut set:RecipeSet = ids.iter().cloned().collect();
+        let r150_position = position(&set, "r150");
+
+        for id in ids.iter().filter(|id| id.as_str()!="r0" && id.as_str()!="r199" && id.as_str()!="r150" && id.as_str()!="r151") {
+            set.remove(id);
+            assert!(set.tombstones <= set.len().max(MIN_TOMBSTONES_TO_COMPACT), "tombstones should be compacted away");
+        }
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec!["r0", "r150", "r151", "r199"]);
+        assert_eq!(position(&set, "r150"), r150_position, "compaction must not move anything");
+
+        set.push_front("r151");
+        assert!(set.remove("r150"));
+        assert!(set.insert("r150"));
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec!["r151", "r0", "r199", "r150"]);
+        let after:Vec<&String> = set.entries_after(r150_position).map(|(_, id)| id).collect();
+        assert_eq!(after, vec!["r199", "r150"]);
+    }
+
+    #[test]
+    fn test_retain() {
+        let mut set = set_of(&["r1", "r2", "r3"]);
+        set.retain(|id| id != "r2");
+
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec!["r1", "r3"]);
+        assert!(!set.contains("r2"));
+    }
+
+    #[test]
+    fn test_serde_roundtrip() {
+        let set = set_of(&["r2", "r1", "r3"]);
+        let json = serde_json::to_string(&set).unwrap();
+        assert_eq!(json, r#"["r2","r1","r3"]"#);
+
+        let parsed:RecipeSet = serde_json::from_str(r#"["r2","r1","r2"]"#).unwrap();
+        assert_eq!(parsed.iter().collect::<Vec<_>>(), vec!["r2", "r1"]);
+    }
+}
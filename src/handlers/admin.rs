@@ -0,0 +1,733 @@
+use std::collections::HashMap;
+use axum::{extract::{rejection::{JsonRejection, QueryRejection}, Path, Query}, http::{header, StatusCode}, response::{IntoResponse, Response}, routing::{get, post}, Extension, Json, Router};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use uuid::Uuid;
+use crate::{faults::{path_matches, FaultConfig, FaultRule}, fixture::{Environment, UserData}, journal::{JournalEntry, SharedJournal}, proxy::recorded_fixture, stubs::{Stub, StubRequest}};
+use super::{errors::ApiError, responses::GenericResponse, SharedState};
+
+/// Endpoints for test harnesses to manipulate the mock itself. These live under /__admin so they can't clash with the real API.
+pub fn router() -> Router {
+    Router::new()
+        .route("/reset", post(reset_state))
+        .route("/state", get(dump_state))
+        .route("/snapshots", get(list_snapshots))
+        .route("/snapshots/{name}", post(save_snapshot).delete(delete_snapshot))
+        .route("/snapshots/{name}/restore", post(restore_snapshot))
+        .route("/faults", get(get_faults).put(replace_faults).post(add_fault).delete(clear_faults))
+        .route("/stubs", get(list_stubs).post(create_stub).delete(clear_stubs))
+        .route("/stubs/{id}", get(get_stub).put(replace_stub).delete(delete_stub))
+        .route("/requests", get(list_requests).delete(clear_requests))
+        .route("/requests/count", post(count_requests))
+        .route("/recordings", get(list_recordings).delete(clear_recordings))
+        .route("/recordings/fixture", get(recordings_as_fixture))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ResetParams {
+    env: Option<Environment>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct StateDump {
+    env: Environment,
+    users: HashMap<String, UserData>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SnapshotSummary {
+    name: String,
+    env: Environment,
+    #[serde(rename="userCount")]
+    user_count: usize,
+    #[serde(rename="createdAt", with="time::serde::rfc3339")]
+    created_at: OffsetDateTime,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SnapshotsResponse {
+    snapshots: Vec<SnapshotSummary>,
+}
+
+/// Snapshot names end up in URLs and the state file, so keep them simple
+fn valid_snapshot_name(name:&str) -> bool {
+    !name.is_empty() && name.len() <= 64 && name.chars().all(|c| c.is_ascii_alphanumeric() || c=='-' || c=='_' || c=='.')
+}
+
+/// Throws away all changes, optionally switching to the built-in fixture for another environment
+pub async fn reset_state(
+    params: Result<Query<ResetParams>, QueryRejection>,
+    Extension(shared_state): Extension<SharedState>,
+) -> Result<Response, ApiError> {
+    let Query(params) = params?;
+    let mut guarded_data = shared_state.write().await;
+    guarded_data.reset(params.env.as_ref());
+
+    log::info!("State reset, now serving IDs for {} environment", guarded_data.env);
+
+    Ok((
+        StatusCode::OK,
+        Json(GenericResponse{
+            status: "reset".into(),
+            detail: Some(format!("Serving IDs for {} environment", guarded_data.env)),
+        })
+    ).into_response())
+}
+
+pub async fn dump_state(
+    Extension(shared_state): Extension<SharedState>,
+) -> impl IntoResponse {
+    let guarded_data = shared_state.read().await;
+
+    (
+        StatusCode::OK,
+        Json(StateDump{
+            env: guarded_data.env.clone(),
+            users: guarded_data.users.clone(),
+        })
+    )
+}
+
+pub async fn list_snapshots(
+    Extension(shared_state): Extension<SharedState>,
+) -> impl IntoResponse {
+    let guarded_data = shared_state.read().await;
+
+    let mut snapshots:Vec<SnapshotSummary> = guarded_data.snapshots.iter().map(|(name, snapshot)| SnapshotSummary{
+        name: name.to_owned(),
+        env: snapshot.env.clone(),
+        user_count: snapshot.users.len(),
+        created_at: snapshot.created_at,
+    }).collect();
+    snapshots.sort_by(|a, b| a.name.cmp(&b.name));
+
+    (
+        StatusCode::OK,
+        Json(SnapshotsResponse{
+            snapshots
+        })
+    )
+}
+
+/// Saves the current state under the given name, replacing any existing snapshot with that name
+pub async fn save_snapshot(
+    Path(name): Path<String>,
+    Extension(shared_state): Extension<SharedState>,
+) -> Result<Response, ApiError> {
+    if !valid_snapshot_name(&name) {
+        return Err(ApiError::BadRequest("snapshot names must be 1-64 characters of letters, digits, '-', '_' or '.'".into()))
+    }
+
+    let mut guarded_data = shared_state.write().await;
+    let snapshot = guarded_data.snapshot();
+    let replaced = guarded_data.snapshots.insert(name.to_owned(), snapshot).is_some();
+    guarded_data.mark_changed();
+
+    log::info!("Saved snapshot {}", name);
+
+    Ok((
+        if replaced { StatusCode::OK } else { StatusCode::CREATED },
+        Json(GenericResponse{
+            status: "saved".into(),
+            detail: None,
+        })
+    ).into_response())
+}
+
+/// Replaces the current state with the named snapshot. This happens under the write lock, so no request sees a partial restore.
+pub async fn restore_snapshot(
+    Path(name): Path<String>,
+    Extension(shared_state): Extension<SharedState>,
+) -> Result<Response, ApiError> {
+    let mut guarded_data = shared_state.write().await;
+
+    let snapshot = guarded_data.snapshots.get(&name).cloned().ok_or_else(|| ApiError::SnapshotNotFound(name.to_owned()))?;
+    guarded_data.restore(&snapshot);
+    log::info!("Restored snapshot {}", name);
+
+    Ok((
+        StatusCode::OK,
+        Json(GenericResponse{
+            status: "restored".into(),
+            detail: None,
+        })
+    ).into_response())
+}
+
+pub async fn delete_snapshot(
+    Path(name): Path<String>,
+    Extension(shared_state): Extension<SharedState>,
+) -> Result<Response, ApiError> {
+    let mut guarded_data = shared_state.write().await;
+
+    guarded_data.snapshots.remove(&name).ok_or_else(|| ApiError::SnapshotNotFound(name.to_owned()))?;
+    guarded_data.mark_changed();
+
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+pub async fn get_faults(
+    Extension(shared_state): Extension<SharedState>,
+) -> impl IntoResponse {
+    let guarded_data = shared_state.read().await;
+
+    (
+        StatusCode::OK,
+        Json(guarded_data.faults.clone())
+    )
+}
+
+/// Replaces every fault rule with the ones given
+pub async fn replace_faults(
+    Extension(shared_state): Extension<SharedState>,
+    config: Result<Json<FaultConfig>, JsonRejection>,
+) -> Result<Response, ApiError> {
+    let Json(config) = config?;
+
+    let problems = config.validate();
+    if !problems.is_empty() {
+        return Err(ApiError::BadRequest(problems.join("; ")))
+    }
+
+    log::info!("Replaced fault rules, {} now active", config.rules.len());
+    shared_state.write().await.faults = config.clone();
+
+    Ok((
+        StatusCode::OK,
+        Json(config)
+    ).into_response())
+}
+
+/// Adds a rule after the existing ones, so it only applies to requests that none of them match
+pub async fn add_fault(
+    Extension(shared_state): Extension<SharedState>,
+    rule: Result<Json<FaultRule>, JsonRejection>,
+) -> Result<Response, ApiError> {
+    let Json(rule) = rule?;
+
+    let problems = rule.validate();
+    if !problems.is_empty() {
+        return Err(ApiError::BadRequest(problems.join("; ")))
+    }
+
+    let mut guarded_data = shared_state.write().await;
+    log::info!("Added fault rule for {}", rule.path);
+    guarded_data.faults.rules.push(rule);
+
+    Ok((
+        StatusCode::CREATED,
+        Json(guarded_data.faults.clone())
+    ).into_response())
+}
+
+pub async fn clear_faults(
+    Extension(shared_state): Extension<SharedState>,
+) -> impl IntoResponse {
+    shared_state.write().await.faults = FaultConfig::default();
+    log::info!("Cleared fault rules");
+
+    StatusCode::NO_CONTENT
+}
+
+#[derive(Serialize, Debug)]
+pub struct StubsResponse {
+    stubs: Vec<Stub>,
+}
+
+fn validate_stub(stub:&Stub) -> Result<(), ApiError> {
+    let problems = stub.validate();
+    if problems.is_empty() {
+        Ok( () )
+    } else {
+        Err(ApiError::BadRequest(problems.join("; ")))
+    }
+}
+
+pub async fn list_stubs(
+    Extension(shared_state): Extension<SharedState>,
+) -> impl IntoResponse {
+    let guarded_data = shared_state.read().await;
+
+    (
+        StatusCode::OK,
+        Json(StubsResponse{
+            stubs: guarded_data.stubs.clone(),
+        })
+    )
+}
+
+/// Adds a stub, giving it an ID unless it came with one
+pub async fn create_stub(
+    Extension(shared_state): Extension<SharedState>,
+    stub: Result<Json<Stub>, JsonRejection>,
+) -> Result<Response, ApiError> {
+    let Json(mut stub) = stub?;
+    validate_stub(&stub)?;
+
+    let mut guarded_data = shared_state.write().await;
+    if stub.id.is_empty() {
+        stub.id = Uuid::new_v4().to_string();
+    } else if guarded_data.stubs.iter().any(|s| s.id==stub.id) {
+        return Err(ApiError::Conflict(format!("there is already a stub with ID {}", stub.id)))
+    }
+
+    log::info!("Added stub {} for {}", stub.id, stub.request.path);
+    guarded_data.stubs.push(stub.clone());
+
+    Ok((
+        StatusCode::CREATED,
+        [(header::LOCATION, format!("/__admin/stubs/{}", stub.id))],
+        Json(stub)
+    ).into_response())
+}
+
+pub async fn get_stub(
+    Path(id): Path<String>,
+    Extension(shared_state): Extension<SharedState>,
+) -> Result<Response, ApiError> {
+    let guarded_data = shared_state.read().await;
+    let stub = guarded_data.stubs.iter().find(|s| s.id==id).ok_or(ApiError::StubNotFound(id.to_owned()))?;
+
+    Ok((
+        StatusCode::OK,
+        Json(stub.clone())
+    ).into_response())
+}
+
+/// Swaps in a new definition for an existing stub. It keeps its place in the order stubs were added.
+pub async fn replace_stub(
+    Path(id): Path<String>,
+    Extension(shared_state): Extension<SharedState>,
+    stub: Result<Json<Stub>, JsonRejection>,
+) -> Result<Response, ApiError> {
+    let Json(mut stub) = stub?;
+    validate_stub(&stub)?;
+    stub.id = id.to_owned();
+
+    let mut guarded_data = shared_state.write().await;
+    let existing = guarded_data.stubs.iter_mut().find(|s| s.id==id).ok_or(ApiError::StubNotFound(id.to_owned()))?;
+    *existing = stub.clone();
+
+    Ok((
+        StatusCode::OK,
+        Json(stub)
+    ).into_response())
+}
+
+pub async fn delete_stub(
+    Path(id): Path<String>,
+    Extension(shared_state): Extension<SharedState>,
+) -> Result<Response, ApiError> {
+    let mut guarded_data = shared_state.write().await;
+    let before = guarded_data.stubs.len();
+    guarded_data.stubs.retain(|s| s.id != id);
+
+    if guarded_data.stubs.len()==before {
+        Err(ApiError::StubNotFound(id))
+    } else {
+        Ok(StatusCode::NO_CONTENT.into_response())
+    }
+}
+
+pub async fn clear_stubs(
+    Extension(shared_state): Extension<SharedState>,
+) -> impl IntoResponse {
+    shared_state.write().await.stubs.clear();
+    log::info!("Cleared stubs");
+
+    StatusCode::NO_CONTENT
+}
+
+/// Narrows down GET /__admin/requests
+#[derive(Deserialize, Debug)]
+pub struct JournalFilter {
+    method: Option<String>,
+    /// A route pattern, as for stubs
+    path: Option<String>,
+    status: Option<u16>,
+    /// Only the most recent this many matching requests
+    limit: Option<usize>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct JournalResponse {
+    requests: Vec<JournalEntry>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CountResponse {
+    count: usize,
+}
+
+/// Requests the mock has received, oldest first
+pub async fn list_requests(
+    filter: Result<Query<JournalFilter>, QueryRejection>,
+    Extension(journal): Extension<SharedJournal>,
+) -> Result<Response, ApiError> {
+    let Query(filter) = filter?;
+    let guarded_journal = journal.lock().await;
+
+    let mut requests:Vec<JournalEntry> = guarded_journal.entries()
+        .rev()
+        .filter(|entry| filter.method.as_ref().is_none_or(|m| m.eq_ignore_ascii_case(&entry.method)))
+        .filter(|entry| filter.path.as_ref().is_none_or(|p| path_matches(p, entry.path())))
+        .filter(|entry| filter.status.is_none_or(|s| s==entry.status))
+        .take(filter.limit.unwrap_or(usize::MAX))
+        .cloned()
+        .collect();
+    requests.reverse();
+
+    Ok((
+        StatusCode::OK,
+        Json(JournalResponse{
+            requests
+        })
+    ).into_response())
+}
+
+/// How many journalled requests match the pattern in the body, which takes the same form as a stub's `request`
+pub async fn count_requests(
+    Extension(journal): Extension<SharedJournal>,
+    pattern: Result<Json<StubRequest>, JsonRejection>,
+) -> Result<Response, ApiError> {
+    let Json(pattern) = pattern?;
+
+    let problems = pattern.validate();
+    if !problems.is_empty() {
+        return Err(ApiError::BadRequest(problems.join("; ")))
+    }
+
+    let guarded_journal = journal.lock().await;
+
+    Ok((
+        StatusCode::OK,
+        Json(CountResponse{
+            count: guarded_journal.entries().filter(|entry| entry.matches(&pattern)).count(),
+        })
+    ).into_response())
+}
+
+pub async fn clear_requests(
+    Extension(journal): Extension<SharedJournal>,
+) -> impl IntoResponse {
+    journal.lock().await.clear();
+
+    StatusCode::NO_CONTENT
+}
+
+/// What the proxy has recorded so far, in the same shape as /__admin/stubs so it can be saved and loaded back with --stubs
+pub async fn list_recordings(
+    Extension(shared_state): Extension<SharedState>,
+) -> impl IntoResponse {
+    let guarded_data = shared_state.read().await;
+
+    (
+        StatusCode::OK,
+        Json(StubsResponse{
+            stubs: guarded_data.recordings.clone(),
+        })
+    )
+}
+
+/// The recorded collections as a fixture, for --fixture
+pub async fn recordings_as_fixture(
+    Extension(shared_state): Extension<SharedState>,
+) -> Result<Response, ApiError> {
+    let guarded_data = shared_state.read().await;
+    let fixture = recorded_fixture(&guarded_data.recordings)
+        .ok_or_else(|| ApiError::Conflict("no successful GET /collection has been recorded yet".into()))?;
+
+    Ok((
+        StatusCode::OK,
+        Json(fixture)
+    ).into_response())
+}
+
+pub async fn clear_recordings(
+    Extension(shared_state): Extension<SharedState>,
+) -> impl IntoResponse {
+    shared_state.write().await.recordings.clear();
+    log::info!("Cleared recordings");
+
+    StatusCode::NO_CONTENT
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use axum_test::TestServer;
+    use serde_json::Value;
+    use tokio::sync::RwLock;
+    use crate::fixture::{recipe_set::RecipeSet, MutableStaticData, SAVED_COLLECTION_ID};
+    use super::*;
+
+    #[tokio::test]
+    async fn test_reset() {
+        let state:SharedState = Arc::new(RwLock::new(MutableStaticData::new(&Environment::PROD)));
+        state.write().await.user("alice").collections.get_mut(SAVED_COLLECTION_ID).unwrap().content = RecipeSet::new();
+
+        let fake_app = Router::new()
+            .nest("/__admin", router())
+            .layer(Extension(state.clone()));
+
+        let fake_server = TestServer::new(fake_app).unwrap();
+
+        let before:Value = fake_server.get("/__admin/state").await.json();
+        assert_eq!(before["env"], "prod");
+        assert_eq!(before["users"]["alice"]["collections"][SAVED_COLLECTION_ID]["content"].as_array().map(|a| a.len()), Some(0));
+
+        fake_server.post("/__admin/reset").await.assert_status_ok();
+
+        let after:Value = fake_server.get("/__admin/state").await.json();
+        assert_eq!(after["env"], "prod");
+        assert_eq!(after["users"].as_object().map(|u| u.len()), Some(0));
+        assert_eq!(state.write().await.user("alice").collections[SAVED_COLLECTION_ID].content.len(), 6);
+
+        fake_server.post("/__admin/reset?env=code").await.assert_status_ok();
+        assert_eq!(state.write().await.user("alice").collections[SAVED_COLLECTION_ID].content.iter().next().unwrap(), "99ea87d53eb3dc2f2f445b38919d9b9cbda4b7b1");
+
+        fake_server.post("/__admin/reset?env=staging").await.assert_status(StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_old_etags_go_stale() {
+        let state:SharedState = Arc::new(RwLock::new(MutableStaticData::new(&Environment::PROD)));
+
+        let fake_app = Router::new()
+            .route("/collection/{collection_id}/contents", get(crate::handlers::get_collection_content).put(crate::handlers::put_to_collection))
+            .nest("/__admin", router())
+            .layer(Extension(state.clone()));
+
+        let fake_server = TestServer::new(fake_app).unwrap();
+        let contents = format!("/collection/{}/contents", SAVED_COLLECTION_ID);
+        let etag = |response:&axum_test::TestResponse| response.header(header::ETAG).to_str().unwrap().to_owned();
+
+        let original = etag(&fake_server.get(&contents).await);
+        fake_server.post("/__admin/snapshots/original").await.assert_status(StatusCode::CREATED);
+        fake_server.post("/__admin/reset").await.assert_status_ok();
+
+        //the content after a reset is the same as at the start, but the client can't know that
+        let after_reset = fake_server.get(&contents).add_header(header::IF_NONE_MATCH, &original).await;
+        after_reset.assert_status_ok();
+        assert_ne!(etag(&after_reset), original);
+
+        let changed = fake_server.put(&format!("{}?id=r1", contents)).await;
+        changed.assert_status_ok();
+        let before_restore = etag(&changed);
+
+        fake_server.post("/__admin/snapshots/original/restore").await.assert_status_ok();
+        fake_server.put(&format!("{}?id=r2", contents))
+            .add_header(header::IF_MATCH, &before_restore)
+            .await
+            .assert_status(StatusCode::PRECONDITION_FAILED);
+        let after_restore = fake_server.get(&contents).add_header(header::IF_NONE_MATCH, &before_restore).await;
+        after_restore.assert_status_ok();
+        assert_ne!(etag(&after_restore), original);
+        assert_ne!(etag(&after_restore), before_restore);
+    }
+
+    #[tokio::test]
+    async fn test_changes_after_reset() {
+        let state:SharedState = Arc::new(RwLock::new(MutableStaticData::new(&Environment::PROD)));
+
+        let fake_app = Router::new()
+            .route("/collection/{collection_id}/contents", axum::routing::put(crate::handlers::put_to_collection))
+            .route("/collection/{collection_id}/changes", get(crate::handlers::get_collection_changes))
+            .nest("/__admin", router())
+            .layer(Extension(state.clone()));
+
+        let fake_server = TestServer::new(fake_app).unwrap();
+        let changes = format!("/collection/{}/changes", SAVED_COLLECTION_ID);
+
+        let before = fake_server.put(&format!("/collection/{}/contents?id=r1", SAVED_COLLECTION_ID)).await;
+        let version = before.header(header::ETAG).to_str().unwrap().trim_matches('"').to_owned();
+        let time = time::OffsetDateTime::now_utc().format(&time::format_description::well_known::Rfc3339).unwrap();
+        fake_server.get(&format!("{}?since={}", changes, version)).await.assert_status_ok();
+
+        fake_server.post("/__admin/reset").await.assert_status_ok();
+
+        fake_server.get(&format!("{}?since={}", changes, version)).await.assert_status(StatusCode::GONE);
+        fake_server.get(&format!("{}?since={}", changes, time)).await.assert_status(StatusCode::GONE);
+        fake_server.get(&format!("{}?since=1", changes)).await.assert_status(StatusCode::GONE);
+    }
+
+    #[tokio::test]
+    async fn test_snapshots() {
+        let state:SharedState = Arc::new(RwLock::new(MutableStaticData::new(&Environment::PROD)));
+        state.write().await.user("alice").collections.get_mut(SAVED_COLLECTION_ID).unwrap().content = RecipeSet::new();
+
+        let fake_app = Router::new()
+            .nest("/__admin", router())
+            .layer(Extension(state.clone()));
+
+        let fake_server = TestServer::new(fake_app).unwrap();
+
+        fake_server.post("/__admin/snapshots/empty-saved").await.assert_status(StatusCode::CREATED);
+        fake_server.post("/__admin/snapshots/not%20valid").await.assert_status(StatusCode::BAD_REQUEST);
+
+        fake_server.post("/__admin/reset?env=code").await.assert_status_ok();
+        state.write().await.user("bob");
+
+        let listing:Value = fake_server.get("/__admin/snapshots").await.json();
+        assert_eq!(listing["snapshots"].as_array().map(|a| a.len()), Some(1));
+        assert_eq!(listing["snapshots"][0]["name"], "empty-saved");
+        assert_eq!(listing["snapshots"][0]["env"], "prod");
+        assert_eq!(listing["snapshots"][0]["userCount"], 1);
+
+        fake_server.post("/__admin/snapshots/empty-saved/restore").await.assert_status_ok();
+        {
+            let restored = state.read().await;
+            assert_eq!(restored.env.to_string(), "prod");
+            assert_eq!(restored.users.len(), 1);
+            assert_eq!(restored.users["alice"].collections[SAVED_COLLECTION_ID].content.len(), 0);
+        }
+
+        fake_server.post("/__admin/snapshots/missing/restore").await.assert_status(StatusCode::NOT_FOUND);
+        fake_server.delete("/__admin/snapshots/empty-saved").await.assert_status(StatusCode::NO_CONTENT);
+        fake_server.delete("/__admin/snapshots/empty-saved").await.assert_status(StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_faults() {
+        let state:SharedState = Arc::new(RwLock::new(MutableStaticData::new(&Environment::PROD)));
+
+        let fake_app = Router::new()
+            .nest("/__admin", router())
+            .layer(Extension(state.clone()));
+
+        let fake_server = TestServer::new(fake_app).unwrap();
+
+        fake_server.put("/__admin/faults")
+            .json(&serde_json::json!({"rules": [{"path": "/collection", "error": {"status": 503}}]}))
+            .await
+            .assert_status_ok();
+
+        fake_server.post("/__admin/faults")
+            .json(&serde_json::json!({"method": "GET", "path": "/collection/*/contents", "latency": {"minMs": 10, "maxMs": 50}}))
+            .await
+            .assert_status(StatusCode::CREATED);
+
+        let listing:Value = fake_server.get("/__admin/faults").await.json();
+        assert_eq!(listing["rules"].as_array().map(|a| a.len()), Some(2));
+        assert_eq!(listing["rules"][0]["error"]["probability"], 1.0);
+        assert_eq!(listing["rules"][1]["latency"]["maxMs"], 50);
+
+        fake_server.post("/__admin/faults")
+            .json(&serde_json::json!({"path": "/collection", "error": {"status": 404}}))
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+
+        //resetting the data leaves the faults alone
+        fake_server.post("/__admin/reset").await.assert_status_ok();
+        assert_eq!(state.read().await.faults.rules.len(), 2);
+
+        fake_server.delete("/__admin/faults").await.assert_status(StatusCode::NO_CONTENT);
+        assert!(state.read().await.faults.rules.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_stubs() {
+        let state:SharedState = Arc::new(RwLock::new(MutableStaticData::new(&Environment::PROD)));
+
+        let fake_app = Router::new()
+            .nest("/__admin", router())
+            .layer(Extension(state.clone()));
+
+        let fake_server = TestServer::new(fake_app).unwrap();
+
+        let created = fake_server.post("/__admin/stubs")
+            .json(&serde_json::json!({"request": {"path": "/recipe/*"}, "response": {"status": 204}}))
+            .await;
+        created.assert_status(StatusCode::CREATED);
+        let created_data:Value = created.json();
+        let id = created_data["id"].as_str().unwrap();
+        assert_eq!(created_data["priority"], 5);
+        assert_eq!(created.header("Location"), format!("/__admin/stubs/{}", id).as_str());
+
+        fake_server.post("/__admin/stubs")
+            .json(&serde_json::json!({"id": "notes", "request": {"path": "/notes"}, "response": {"body": "none"}}))
+            .await
+            .assert_status(StatusCode::CREATED);
+        fake_server.post("/__admin/stubs")
+            .json(&serde_json::json!({"id": "notes", "request": {"path": "/notes"}, "response": {}}))
+            .await
+            .assert_status(StatusCode::CONFLICT);
+        fake_server.post("/__admin/stubs")
+            .json(&serde_json::json!({"request": {"path": "notes"}, "response": {}}))
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+        fake_server.post("/__admin/stubs")
+            .json(&serde_json::json!({"request": {"path": "/notes", "query": {"q": {"matches": "("}}}, "response": {}}))
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+
+        fake_server.put("/__admin/stubs/notes")
+            .json(&serde_json::json!({"priority": 1, "request": {"path": "/notes"}, "response": {"status": 503}}))
+            .await
+            .assert_status_ok();
+
+        let notes:Value = fake_server.get("/__admin/stubs/notes").await.json();
+        assert_eq!(notes["id"], "notes");
+        assert_eq!(notes["response"]["status"], 503);
+
+        let listing:Value = fake_server.get("/__admin/stubs").await.json();
+        assert_eq!(listing["stubs"].as_array().map(|a| a.len()), Some(2));
+
+        fake_server.delete("/__admin/stubs/notes").await.assert_status(StatusCode::NO_CONTENT);
+        fake_server.delete("/__admin/stubs/notes").await.assert_status(StatusCode::NOT_FOUND);
+        fake_server.get("/__admin/stubs/notes").await.assert_status(StatusCode::NOT_FOUND);
+
+        fake_server.delete("/__admin/stubs").await.assert_status(StatusCode::NO_CONTENT);
+        assert!(state.read().await.stubs.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_requests() {
+        let state:SharedState = Arc::new(RwLock::new(MutableStaticData::new(&Environment::PROD)));
+
+        let fake_app = Router::new()
+            .route("/collection/{collection_id}/contents", axum::routing::delete(|| async { StatusCode::NO_CONTENT }))
+            .nest("/__admin", router())
+            .layer(axum::middleware::from_fn(crate::journal::record_requests))
+            .layer(Extension(crate::journal::Journal::shared(crate::journal::DEFAULT_JOURNAL_SIZE)))
+            .layer(Extension(state.clone()));
+
+        let fake_server = TestServer::new(fake_app).unwrap();
+
+        for ids in [["r1", "r2"], ["r1", "r2"], ["r3", "r4"]] {
+            fake_server.delete(&format!("/collection/{}/contents", SAVED_COLLECTION_ID))
+                .json(&serde_json::json!({"ids": ids}))
+                .await;
+        }
+        fake_server.get("/collection").await;
+
+        let everything:Value = fake_server.get("/__admin/requests").await.json();
+        assert_eq!(everything["requests"].as_array().map(|a| a.len()), Some(4), "admin requests aren't journalled");
+
+        let deletes:Value = fake_server.get("/__admin/requests?method=delete&path=/collection/*/contents&limit=2").await.json();
+        assert_eq!(deletes["requests"].as_array().map(|a| a.len()), Some(2));
+        assert_eq!(deletes["requests"][1]["body"], r#"{"ids":["r3","r4"]}"#);
+
+        let not_found:Value = fake_server.get("/__admin/requests?status=404").await.json();
+        assert_eq!(not_found["requests"][0]["uri"], "/collection");
+
+        let count:Value = fake_server.post("/__admin/requests/count")
+            .json(&serde_json::json!({
+                "method": "DELETE",
+                "path": format!("/collection/{}/contents", SAVED_COLLECTION_ID),
+                "body": {"equalToJson": {"ids": ["r1", "r2"]}}
+            }))
+            .await
+            .json();
+        assert_eq!(count["count"], 2);
+
+        fake_server.post("/__admin/requests/count")
+            .json(&serde_json::json!({"path": "collection"}))
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+
+        fake_server.delete("/__admin/requests").await.assert_status(StatusCode::NO_CONTENT);
+        let cleared:Value = fake_server.get("/__admin/requests").await.json();
+        assert_eq!(cleared["requests"].as_array().map(|a| a.len()), Some(0));
+    }
+}
@@ -0,0 +1,149 @@
+use std::time::SystemTime;
+use axum::http::{header, HeaderMap, HeaderValue};
+use time::OffsetDateTime;
+
+/// Formats a timestamp as an HTTP-date (the RFC 2822 derived IMF-fixdate), suitable for `Last-Modified`
+pub fn http_date(timestamp: OffsetDateTime) -> HeaderValue {
+    //an HTTP-date only ever contains ASCII so this can't fail
+    HeaderValue::from_str(&httpdate::fmt_http_date(SystemTime::from(timestamp))).unwrap()
+}
+
+/// Returns true if the request carries an `If-Modified-Since` that is not older than `last_modified`,
+/// i.e. the client's copy is still current and a 304 should be sent.
+/// HTTP-dates only have a resolution of one second, so anything within the same second counts as unmodified.
+/// An unparseable header is ignored, as RFC 9110 requires.
+pub fn not_modified_since(headers: &HeaderMap, last_modified: OffsetDateTime) -> bool {
+    let maybe_since = headers.get(header::IF_MODIFIED_SINCE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| httpdate::parse_http_date(v).ok())
+        .map(OffsetDateTime::from);
+
+    match maybe_since {
+        None=>false,
+        Some(since)=>last_modified.unix_timestamp() <= since.unix_timestamp(),
+    }
+}
+
+/// Formats a version number as a strong entity tag
+pub fn etag(version: u64) -> HeaderValue {
+    HeaderValue::from_str(&format!("\"{}\"", version)).unwrap()
+}
+
+/// Splits an If-Match / If-None-Match value into its entity tags, keeping any W/ prefix
+fn parse_etag_list(value: &str) -> Vec<&str> {
+    value.split(',').map(|tag| tag.trim()).filter(|tag| !tag.is_empty()).collect()
+}
+
+/// Returns true if the request's `If-None-Match` matches the current version, using the weak comparison RFC 9110 asks for
+fn none_match_matches(headers: &HeaderMap, version: u64) -> Option<bool> {
+    let value = headers.get(header::IF_NONE_MATCH)?.to_str().ok()?;
+    let current = format!("\"{}\"", version);
+
+    Some(parse_etag_list(value).iter().any(|tag| *tag == "*" || tag.trim_start_matches("W/") == current))
+}
+
+/// Decides whether a GET can be answered with 304 Not Modified.
+/// If-None-Match takes precedence; If-Modified-Since is only consulted when there is no If-None-Match.
+pub fn is_not_modified(headers: &HeaderMap, version: u64, last_modified: OffsetDateTime) -> bool {
+    match none_match_matches(headers, version) {
+        Some(matched)=>matched,
+        None=>not_modified_since(headers, last_modified),
+    }
+}
+
+/// The entity tags from an `If-Match` header, if the client sent one
+#[derive(Debug, Clone, Default)]
+pub struct IfMatch(Option<Vec<String>>);
+
+impl IfMatch {
+    pub fn from_headers(headers: &HeaderMap) -> IfMatch {
+        IfMatch::from_value(headers.get(header::IF_MATCH).map(|v| v.to_str().unwrap_or("")))
+    }
+
+    /// From an If-Match value that didn't come in a header
+    pub fn from_value(value: Option<&str>) -> IfMatch {
+        IfMatch(value.map(|v| parse_etag_list(v).iter().map(|tag| tag.to_string()).collect()))
+    }
+
+    /// Returns true if a mutation against the given version may go ahead.
+    /// Uses strong comparison, so weak tags never match.
+    pub fn allows(&self, version: u64) -> bool {
+        match &self.0 {
+            None=>true,
+            Some(tags)=>{
+                let current = format!("\"{}\"", version);
+                tags.iter().any(|tag| tag == "*" || *tag == current)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use time::Duration;
+    use super::*;
+
+    //2025-01-20 10:00:00.5 UTC
+    fn sample_time() -> OffsetDateTime {
+        OffsetDateTime::from_unix_timestamp(1737367200).unwrap() + Duration::milliseconds(500)
+    }
+
+    #[test]
+    fn test_http_date() {
+        assert_eq!(http_date(sample_time()), "Mon, 20 Jan 2025 10:00:00 GMT");
+    }
+
+    #[test]
+    fn test_not_modified_since() {
+        let last_modified = sample_time();
+        let mut headers = HeaderMap::new();
+
+        assert!(!not_modified_since(&headers, last_modified));
+
+        headers.insert(header::IF_MODIFIED_SINCE, HeaderValue::from_static("Mon, 20 Jan 2025 10:00:00 GMT"));
+        assert!(not_modified_since(&headers, last_modified));
+
+        headers.insert(header::IF_MODIFIED_SINCE, HeaderValue::from_static("Mon, 20 Jan 2025 11:00:00 GMT"));
+        assert!(not_modified_since(&headers, last_modified));
+
+        headers.insert(header::IF_MODIFIED_SINCE, HeaderValue::from_static("Mon, 20 Jan 2025 09:59:59 GMT"));
+        assert!(!not_modified_since(&headers, last_modified));
+
+        headers.insert(header::IF_MODIFIED_SINCE, HeaderValue::from_static("yesterday"));
+        assert!(!not_modified_since(&headers, last_modified));
+    }
+
+    #[test]
+    fn test_is_not_modified() {
+        let last_modified = sample_time();
+        let mut headers = HeaderMap::new();
+
+        headers.insert(header::IF_NONE_MATCH, HeaderValue::from_static("\"2\", W/\"3\""));
+        assert!(is_not_modified(&headers, 3, last_modified));
+        assert!(is_not_modified(&headers, 2, last_modified));
+        assert!(!is_not_modified(&headers, 4, last_modified));
+
+        //If-None-Match wins over If-Modified-Since
+        headers.insert(header::IF_MODIFIED_SINCE, HeaderValue::from_static("Mon, 20 Jan 2025 11:00:00 GMT"));
+        assert!(!is_not_modified(&headers, 4, last_modified));
+
+        headers.insert(header::IF_NONE_MATCH, HeaderValue::from_static("*"));
+        assert!(is_not_modified(&headers, 4, last_modified));
+    }
+
+    #[test]
+    fn test_if_match() {
+        let mut headers = HeaderMap::new();
+        assert!(IfMatch::from_headers(&headers).allows(7));
+
+        headers.insert(header::IF_MATCH, HeaderValue::from_static("\"6\",\"7\""));
+        assert!(IfMatch::from_headers(&headers).allows(7));
+        assert!(!IfMatch::from_headers(&headers).allows(8));
+
+        headers.insert(header::IF_MATCH, HeaderValue::from_static("W/\"7\""));
+        assert!(!IfMatch::from_headers(&headers).allows(7));
+
+        headers.insert(header::IF_MATCH, HeaderValue::from_static("*"));
+        assert!(IfMatch::from_headers(&headers).allows(7));
+    }
+}
@@ -0,0 +1,252 @@
+use axum::{extract::{rejection::{JsonRejection, QueryRejection}, Request, State}, http::{header, HeaderValue, StatusCode}, middleware::Next, response::{IntoResponse, Response}, Json};
+use super::responses::{GenericResponse, ProblemDetails};
+
+/// Problem types are URNs rather than URLs because there is nowhere to serve documentation for them from
+const PROBLEM_TYPE_PREFIX:&str = "urn:mock-recipe-persistence:problem:";
+
+/// How errors are written out
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ErrorFormat {
+    /// RFC 7807 `application/problem+json`
+    #[default]
+    Problem,
+    /// `{"status": ..., "detail": ...}`, for clients written against older versions of the mock
+    Legacy,
+}
+
+/// Everything the handlers can fail with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ApiError {
+    RouteNotFound,
+    /// The route exists, but not for the request's method
+    MethodNotAllowed,
+    CollectionNotFound,
+    SnapshotNotFound(String),
+    StubNotFound(String),
+    BadRequest(String),
+    UnsupportedMediaType(String),
+    Conflict(String),
+    /// The collection has changed since the ETag given in If-Match
+    PreconditionFailed,
+    /// A delta sync asked for changes since a point older than the history kept
+    HistoryExpired,
+    /// Returned instead of handling the request, because a fault rule or X-Mock-Status header said so
+    InjectedFault(StatusCode),
+    /// The proxy couldn't get an answer from the real server
+    UpstreamFailed(String),
+    /// A response didn't fit the contract given with --contract, and strict checking is on
+    ContractDrift(String),
+}
+
+impl ApiError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::RouteNotFound | ApiError::CollectionNotFound | ApiError::SnapshotNotFound(_) | ApiError::StubNotFound(_)=>StatusCode::NOT_FOUND,
+            ApiError::MethodNotAllowed=>StatusCode::METHOD_NOT_ALLOWED,
+            ApiError::BadRequest(_)=>StatusCode::BAD_REQUEST,
+            ApiError::UnsupportedMediaType(_)=>StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ApiError::Conflict(_)=>StatusCode::CONFLICT,
+            ApiError::PreconditionFailed=>StatusCode::PRECONDITION_FAILED,
+            ApiError::HistoryExpired=>StatusCode::GONE,
+            ApiError::InjectedFault(status)=>*status,
+            ApiError::UpstreamFailed(_)=>StatusCode::BAD_GATEWAY,
+            ApiError::ContractDrift(_)=>StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// The `status` string in the legacy format
+    fn legacy_status(&self) -> &'static str {
+        match self {
+            ApiError::RouteNotFound | ApiError::CollectionNotFound | ApiError::SnapshotNotFound(_) | ApiError::StubNotFound(_)=>"not_found",
+            ApiError::MethodNotAllowed=>"method_not_allowed",
+            ApiError::BadRequest(_)=>"bad_request",
+            ApiError::UnsupportedMediaType(_)=>"unsupported_media_type",
+            ApiError::Conflict(_)=>"conflict",
+            ApiError::PreconditionFailed=>"precondition_failed",
+            ApiError::HistoryExpired=>"gone",
+            ApiError::InjectedFault(_)=>"injected_fault",
+            ApiError::UpstreamFailed(_)=>"bad_gateway",
+            ApiError::ContractDrift(_)=>"contract_drift",
+        }
+    }
+
+    /// The last part of the problem type URI
+    fn kind(&self) -> &'static str {
+        match self {
+            ApiError::RouteNotFound=>"route-not-found",
+            ApiError::MethodNotAllowed=>"method-not-allowed",
+            ApiError::CollectionNotFound=>"collection-not-found",
+            ApiError::SnapshotNotFound(_)=>"snapshot-not-found",
+            ApiError::StubNotFound(_)=>"stub-not-found",
+            ApiError::BadRequest(_)=>"bad-request",
+            ApiError::UnsupportedMediaType(_)=>"unsupported-media-type",
+            ApiError::Conflict(_)=>"conflict",
+            ApiError::PreconditionFailed=>"precondition-failed",
+            ApiError::HistoryExpired=>"history-expired",
+            ApiError::InjectedFault(_)=>"injected-fault",
+            ApiError::UpstreamFailed(_)=>"upstream-failed",
+            ApiError::ContractDrift(_)=>"contract-drift",
+        }
+    }
+
+    fn title(&self) -> &'static str {
+        match self {
+            ApiError::RouteNotFound=>"No such endpoint",
+            ApiError::MethodNotAllowed=>"Method not allowed",
+            ApiError::CollectionNotFound=>"Collection not found",
+            ApiError::SnapshotNotFound(_)=>"Snapshot not found",
+            ApiError::StubNotFound(_)=>"Stub not found",
+            ApiError::BadRequest(_)=>"Bad request",
+            ApiError::UnsupportedMediaType(_)=>"Unsupported media type",
+            ApiError::Conflict(_)=>"Conflict",
+            ApiError::PreconditionFailed=>"Precondition failed",
+            ApiError::HistoryExpired=>"History expired",
+            ApiError::InjectedFault(_)=>"Injected fault",
+            ApiError::UpstreamFailed(_)=>"Upstream failed",
+            ApiError::ContractDrift(_)=>"Response does not match the contract",
+        }
+    }
+
+    fn detail(&self) -> String {
+        match self {
+            ApiError::RouteNotFound=>"Bad URL".into(),
+            ApiError::MethodNotAllowed=>"That endpoint does not take this method".into(),
+            ApiError::CollectionNotFound=>"That collection ID does not exist".into(),
+            ApiError::SnapshotNotFound(name)=>format!("There is no snapshot called {}", name),
+            ApiError::StubNotFound(id)=>format!("There is no stub with ID {}", id),
+            ApiError::BadRequest(detail) | ApiError::UnsupportedMediaType(detail) | ApiError::Conflict(detail)=>detail.to_owned(),
+            ApiError::UpstreamFailed(reason)=>format!("could not reach the upstream server: {}", reason),
+            ApiError::ContractDrift(violations)=>format!("the mock's response does not match the contract: {}", violations),
+            ApiError::PreconditionFailed=>"collection has been modified since the given ETag".into(),
+            ApiError::HistoryExpired=>"changes from that point are no longer kept; fetch the whole collection again".into(),
+            ApiError::InjectedFault(status)=>format!("fault injection made this request fail with {}", status.as_u16()),
+        }
+    }
+
+    /// `instance` is the path of the request that failed, if known
+    pub fn render(&self, format:ErrorFormat, instance:Option<&str>) -> Response {
+        match format {
+            ErrorFormat::Legacy=>(
+                self.status(),
+                Json(GenericResponse{
+                    status: self.legacy_status().into(),
+                    detail: Some(self.detail()),
+                })
+            ).into_response(),
+            ErrorFormat::Problem=>(
+                self.status(),
+                [(header::CONTENT_TYPE, HeaderValue::from_static("application/problem+json"))],
+                serde_json::to_string(&self.problem(instance)).unwrap_or_default()
+            ).into_response(),
+        }
+    }
+
+    /// The error as an RFC 7807 problem, for sending some other way than as a response
+    pub fn problem(&self, instance:Option<&str>) -> ProblemDetails {
+        ProblemDetails{
+            problem_type: format!("{}{}", PROBLEM_TYPE_PREFIX, self.kind()),
+            title: self.title().into(),
+            status: self.status().as_u16(),
+            detail: Some(self.detail()),
+            instance: instance.map(|path| path.to_owned()),
+        }
+    }
+}
+
+/// Renders as a problem without an `instance`. The error is also attached to the response so that
+/// `render_errors` can fill that in and switch format.
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let mut response = self.render(ErrorFormat::Problem, None);
+        response.extensions_mut().insert(self);
+        response
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection:JsonRejection) -> ApiError {
+        match rejection {
+            JsonRejection::MissingJsonContentType(_)=>ApiError::UnsupportedMediaType(rejection.body_text()),
+            _=>ApiError::BadRequest(rejection.body_text()),
+        }
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(rejection:QueryRejection) -> ApiError {
+        ApiError::BadRequest(rejection.body_text())
+    }
+}
+
+/// Re-renders any `ApiError` response in the configured format, with the request path as its `instance`
+pub async fn render_errors(
+    State(format): State<ErrorFormat>,
+    request: Request,
+    next: Next
+) -> Response {
+    let instance = request.uri().path().to_owned();
+    let response = next.run(request).await;
+
+    let Some(error) = response.extensions().get::<ApiError>().cloned() else {
+        return response
+    };
+
+    let (mut parts, _) = response.into_parts();
+    let (rendered_parts, body) = error.render(format, Some(&instance)).into_parts();
+    parts.headers.remove(header::CONTENT_LENGTH);
+    if let Some(content_type) = rendered_parts.headers.get(header::CONTENT_TYPE) {
+        parts.headers.insert(header::CONTENT_TYPE, content_type.to_owned());
+    }
+
+    Response::from_parts(parts, body)
+}
+
+#[cfg(test)]
+mod test {
+    use axum::{middleware, routing::get, Router};
+    use axum_test::TestServer;
+    use serde_json::Value;
+    use super::*;
+
+    fn fake_server(format:ErrorFormat) -> TestServer {
+        let fake_app = Router::new()
+            .route("/collection/{collection_id}/contents", get(|| async { ApiError::CollectionNotFound }))
+            .fallback(|| async { ApiError::RouteNotFound })
+            .layer(middleware::from_fn_with_state(format, render_errors));
+
+        TestServer::new(fake_app).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_problem_details() {
+        let response = fake_server(ErrorFormat::Problem).get("/collection/missing/contents").await;
+        response.assert_status(StatusCode::NOT_FOUND);
+        assert_eq!(response.header(header::CONTENT_TYPE), "application/problem+json");
+
+        let problem:Value = response.json();
+        assert_eq!(problem["type"], "urn:mock-recipe-persistence:problem:collection-not-found");
+        assert_eq!(problem["title"], "Collection not found");
+        assert_eq!(problem["status"], 404);
+        assert_eq!(problem["detail"], "That collection ID does not exist");
+        assert_eq!(problem["instance"], "/collection/missing/contents");
+
+        let unrouted:Value = fake_server(ErrorFormat::Problem).get("/nowhere").await.json();
+        assert_eq!(unrouted["type"], "urn:mock-recipe-persistence:problem:route-not-found");
+    }
+
+    #[tokio::test]
+    async fn test_legacy_errors() {
+        let response = fake_server(ErrorFormat::Legacy).get("/collection/missing/contents").await;
+        response.assert_status(StatusCode::NOT_FOUND);
+        assert_eq!(response.header(header::CONTENT_TYPE), "application/json");
+
+        let legacy:Value = response.json();
+        assert_eq!(legacy, serde_json::json!({"status": "not_found", "detail": "That collection ID does not exist"}));
+    }
+
+    #[test]
+    fn test_legacy_status_matches_http_status() {
+        assert_eq!(ApiError::BadRequest("no recipes to add".into()).legacy_status(), "bad_request");
+        assert_eq!(ApiError::PreconditionFailed.legacy_status(), "precondition_failed");
+    }
+}
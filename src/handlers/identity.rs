@@ -0,0 +1,40 @@
+use std::convert::Infallible;
+use axum::{extract::FromRequestParts, http::{header, request::Parts}};
+
+pub const USER_ID_HEADER:&str = "x-user-id";
+
+/// Requests that don't identify themselves all share this user's collections
+pub const ANONYMOUS_USER:&str = "anonymous";
+
+/// Identifies the caller so that each one gets an isolated set of collections.
+/// An explicit `X-User-Id` header wins; otherwise the bearer token from `Authorization` is used as-is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserId(pub String);
+
+impl UserId {
+    fn from_parts(parts: &Parts) -> Option<UserId> {
+        let explicit = parts.headers.get(USER_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.trim())
+            .filter(|v| !v.is_empty());
+
+        let bearer = || parts.headers.get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer ").or_else(|| v.strip_prefix("bearer ")))
+            .map(|v| v.trim())
+            .filter(|v| !v.is_empty());
+
+        explicit.or_else(bearer).map(|v| UserId(v.to_owned()))
+    }
+}
+
+impl<S> FromRequestParts<S> for UserId
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(UserId::from_parts(parts).unwrap_or_else(|| UserId(ANONYMOUS_USER.into())))
+    }
+}
@@ -1,234 +1,669 @@
-use std::{collections::{HashMap, HashSet}, ops::{Deref, DerefMut}, sync::Arc};
+use std::{collections::{HashMap, HashSet}, ops::DerefMut, sync::Arc};
 use axum::http;
-use axum::{extract::{Path, Query}, http::StatusCode, response::IntoResponse, Extension, Json};
-mod responses;
-use responses::{CollectionContentResponse, GenericResponse};
-use tokio::sync::RwLock;
-use crate::fixture::*;
+use axum::{body::Bytes, extract::{rejection::JsonRejection, OriginalUri, Path, Query}, http::{header, HeaderMap, StatusCode}, response::{IntoResponse, Response}, Extension, Json};
+pub mod admin;
+mod conditional;
+pub mod errors;
+pub mod identity;
+pub mod openapi;
+mod pagination;
+pub mod requests;
+pub mod responses;
+pub mod ws;
+use conditional::IfMatch;
+use errors::ApiError;
+use identity::UserId;
+use requests::{CreateCollectionRequest, RecipeIdsRequest, RenameCollectionRequest};
+use responses::{CollectionChangesResponse, CollectionContentResponse, GenericResponse, ProblemDetails, Tombstone, UpdateResponse};
+use tokio::sync::{RwLock, RwLockReadGuard};
+use uuid::Uuid;
+use crate::events::CollectionEvent;
+use crate::fixture::{*, history::{ContentChange, Since}, models::{CollectionKind, CollectionResponse, CollectionsResponse}};
 
 pub type SharedState = Arc<RwLock<MutableStaticData>>;
 
-pub async fn generic404() -> impl IntoResponse {
-    (
-        StatusCode::NOT_FOUND,
-        Json(GenericResponse{
-            status: "not_found".into(),
-            detail: Some("Bad URL".into())
-        })
+#[utoipa::path(
+    get,
+    path="/collection",
+    tag="collections",
+    params(
+        ("If-None-Match"=Option<String>, Header, description="ETag from an earlier response"),
+        ("If-Modified-Since"=Option<String>, Header, description="HTTP date from an earlier Last-Modified"),
+    ),
+    responses(
+        (status=200, description="Every collection the user has", body=CollectionsResponse,
+            headers(("ETag"=String), ("Last-Modified"=String))),
+        (status=304, description="The client's copy is up to date"),
     )
+)]
+pub async fn get_user_collections(
+    UserId(user_id): UserId,
+    headers: HeaderMap,
+    Extension(shared_state): Extension<SharedState>
+) -> impl IntoResponse {
+    let guarded_data = read_for_user(&shared_state, &user_id, None).await;
+    let user_data = &guarded_data.users[&user_id];
+
+    let etag = (header::ETAG, conditional::etag(user_data.version));
+
+    let last_modified = user_data.last_modified();
+
+    if conditional::is_not_modified(&headers, user_data.version, last_modified) {
+        (
+            StatusCode::NOT_MODIFIED,
+            [etag, (header::LAST_MODIFIED, conditional::http_date(last_modified))]
+        ).into_response()
+    } else {
+        (
+            StatusCode::OK,
+            [etag, (header::LAST_MODIFIED, conditional::http_date(last_modified))],
+            Json(user_data.user_collections())
+        ).into_response()
+    }
 }
 
-pub async fn get_user_collections() -> impl IntoResponse {
-    let now = time::OffsetDateTime::now_utc();
+/// Makes a new, empty collection. Users may have any number of userCreated collections but only one of each other kind.
+#[utoipa::path(
+    post,
+    path="/collection",
+    tag="collections",
+    request_body=CreateCollectionRequest,
+    responses(
+        (status=201, description="The new collection", body=CollectionResponse,
+            headers(("Location"=String, description="Where to find the collection's contents"))),
+        (status=400, description="The body is not a valid request", content((ProblemDetails = "application/problem+json"), (GenericResponse = "application/json"))),
+        (status=409, description="The user already has a collection of that kind", content((ProblemDetails = "application/problem+json"), (GenericResponse = "application/json"))),
+        (status=415, description="The body is not JSON", content((ProblemDetails = "application/problem+json"), (GenericResponse = "application/json"))),
+    )
+)]
+pub async fn create_collection(
+    UserId(user_id): UserId,
+    Extension(shared_state): Extension<SharedState>,
+    request: Result<Json<CreateCollectionRequest>, JsonRejection>,
+) -> Result<Response, ApiError> {
+    let Json(request) = request?;
+
+    let mut guarded_data = shared_state.write().await;
+    let changes = guarded_data.changes.clone();
+    let user_data = guarded_data.user(&user_id);
+
+    if request.kind != CollectionKind::UserCreated && user_data.collections.values().any(|c| c.kind==request.kind) {
+        return Err(ApiError::Conflict(format!("there is already a {:?} collection", request.kind)))
+    }
 
-    let collections = gen_user_collections(now);
+    let collection_id = Uuid::new_v4().to_string().to_uppercase();
+    let collection = CollectionData::new(request.kind, request.name);
+    let description = collection.describe(&collection_id);
 
-    (
+    user_data.collections.insert(collection_id.to_owned(), collection);
+    user_data.touch();
+    changes.notify_one();
+
+    Ok((
+        StatusCode::CREATED,
+        [(header::LOCATION, format!("/collection/{}/contents", collection_id))],
+        Json(description)
+    ).into_response())
+}
+
+#[utoipa::path(
+    patch,
+    path="/collection/{collection_id}",
+    tag="collections",
+    params(("collection_id"=String, Path)),
+    request_body=RenameCollectionRequest,
+    responses(
+        (status=200, description="The renamed collection", body=CollectionResponse),
+        (status=400, description="The body is not a valid request", content((ProblemDetails = "application/problem+json"), (GenericResponse = "application/json"))),
+        (status=404, description="There is no such collection", content((ProblemDetails = "application/problem+json"), (GenericResponse = "application/json"))),
+        (status=415, description="The body is not JSON", content((ProblemDetails = "application/problem+json"), (GenericResponse = "application/json"))),
+    )
+)]
+pub async fn rename_collection(
+    UserId(user_id): UserId,
+    Path(collection_id): Path<String>,
+    Extension(shared_state): Extension<SharedState>,
+    request: Result<Json<RenameCollectionRequest>, JsonRejection>,
+) -> Result<Response, ApiError> {
+    let Json(request) = request?;
+
+    let mut guarded_data = shared_state.write().await;
+    let changes = guarded_data.changes.clone();
+    let user_data = guarded_data.user(&user_id);
+
+    let collection = user_data.collections.get_mut(&collection_id).ok_or(ApiError::CollectionNotFound)?;
+    collection.name = request.name;
+    let description = collection.describe(&collection_id);
+    user_data.touch();
+    changes.notify_one();
+
+    Ok((
         StatusCode::OK,
-        Json(collections)
+        Json(description)
+    ).into_response())
+}
+
+#[utoipa::path(
+    delete,
+    path="/collection/{collection_id}",
+    tag="collections",
+    params(("collection_id"=String, Path)),
+    responses(
+        (status=204, description="The collection is gone"),
+        (status=404, description="There is no such collection", content((ProblemDetails = "application/problem+json"), (GenericResponse = "application/json"))),
     )
+)]
+pub async fn delete_collection(
+    UserId(user_id): UserId,
+    Path(collection_id): Path<String>,
+    Extension(shared_state): Extension<SharedState>,
+) -> Result<Response, ApiError> {
+    let mut guarded_data = shared_state.write().await;
+    let changes = guarded_data.changes.clone();
+    let user_data = guarded_data.user(&user_id);
+
+    user_data.collections.remove(&collection_id).ok_or(ApiError::CollectionNotFound)?;
+    user_data.touch();
+    changes.notify_one();
+
+    Ok(StatusCode::NO_CONTENT.into_response())
 }
 
-fn get_offset_limit(params: &HashMap<String, String>) -> (usize, usize) 
-{
-    let limit:usize = match params.get("limit") {
-        None=>100,
-        Some(limit)=>str::parse(limit).unwrap_or(100),
-    };
+/// Drops anything from a recentlyViewed collection that has been there longer than the policy allows, before it is read
+fn expire_views(data:&mut MutableStaticData, user_id:&str, collection_id:&str) {
+    let changes = data.changes.clone();
+    let events = data.events.clone();
+    let policy = data.recently_viewed.clone();
+    let history_length = data.history_length;
+    let user_data = data.user(user_id);
 
-    let offset:usize = match params.get("offset") {
-        None=>0,
-        Some(offset)=>str::parse(offset).unwrap_or(0),
-    };
+    if let Some(collection) = user_data.collections.get_mut(collection_id) {
+        let expired = collection.expire_views(&policy);
+        if !expired.is_empty() {
+            collection.touch(expired.clone(), history_length);
+            user_data.version += 1;
+            changes.notify_one();
+            events.publish(user_id, collection_id, collection.version, collection.last_modified, expired);
+        }
+    }
+}
+
+/// Takes the read lock, so that users can read at the same time. If the user's collections have to be created first,
+/// or views in the collection have expired, the write lock is taken instead and downgraded once that's done.
+/// Either way the user is there when this returns.
+async fn read_for_user<'a>(shared_state:&'a SharedState, user_id:&str, collection_id:Option<&str>) -> RwLockReadGuard<'a, MutableStaticData> {
+    let guarded_data = shared_state.read().await;
+    let up_to_date = guarded_data.users.get(user_id).is_some_and(|user_data| collection_id
+        .and_then(|id| user_data.collections.get(id))
+        .is_none_or(|collection| !collection.has_expired_views(&guarded_data.recently_viewed)));
+    if up_to_date {
+        return guarded_data
+    }
+    drop(guarded_data);
 
-    (offset, limit)
+    let mut guarded_data = shared_state.write().await;
+    match collection_id {
+        Some(collection_id)=>expire_views(&mut guarded_data, user_id, collection_id),
+        None=>{
+            guarded_data.user(user_id);
+        },
+    }
+    guarded_data.downgrade()
+}
+
+/// The RFC 8288 Link header pointing at the neighbouring pages, if there are any
+fn page_links(path:&str, page:&pagination::Page, limit:usize) -> Option<http::HeaderValue> {
+    let links:Vec<String> = [(page.next, "next"), (page.prev, "prev")].into_iter()
+        .filter_map(|(cursor, rel)| cursor.map(|c| format!("<{}?cursor={}&limit={}>; rel=\"{}\"", path, c.encode(), limit, rel)))
+        .collect();
+
+    if links.is_empty() {
+        None
+    } else {
+        http::HeaderValue::from_str(&links.join(", ")).ok()
+    }
 }
 
-/// TODO - add if-modified-since behaviour
+/// Responds with 304 Not Modified if the client's If-None-Match or If-Modified-Since shows it already has the current content.
+/// Pages are chosen with `limit` and either `offset` or a `cursor` from a previous response.
+#[utoipa::path(
+    get,
+    path="/collection/{collection_id}/contents",
+    tag="collections",
+    params(
+        ("collection_id"=String, Path),
+        ("limit"=Option<usize>, Query, description="How many recipes to return, up to --max-page-size"),
+        ("offset"=Option<usize>, Query, description="How many recipes to skip. Can't be used with cursor."),
+        ("cursor"=Option<String>, Query, description="nextCursor or prevCursor from an earlier page"),
+        ("If-None-Match"=Option<String>, Header, description="ETag from an earlier response"),
+        ("If-Modified-Since"=Option<String>, Header, description="HTTP date from an earlier Last-Modified"),
+    ),
+    responses(
+        (status=200, description="A page of the collection's recipes", body=CollectionContentResponse,
+            headers(("ETag"=String), ("Last-Modified"=String), ("Link"=String, description="RFC 8288 links to the next and previous pages"))),
+        (status=304, description="The client's copy is up to date"),
+        (status=400, description="The paging parameters are not valid", content((ProblemDetails = "application/problem+json"), (GenericResponse = "application/json"))),
+        (status=404, description="There is no such collection", content((ProblemDetails = "application/problem+json"), (GenericResponse = "application/json"))),
+    )
+)]
 pub async fn get_collection_content(
+    UserId(user_id): UserId,
+    headers: HeaderMap,
+    OriginalUri(uri): OriginalUri,
     Query(params): Query<HashMap<String, String>>,
     Path(collection_id): Path<String>,
     Extension(shared_state): Extension<SharedState>
-) -> impl IntoResponse {
-    let now = time::OffsetDateTime::now_utc();
+) -> Result<Response, ApiError> {
+    let guarded_data = read_for_user(&shared_state, &user_id, Some(&collection_id)).await;
 
-    let state_ref = shared_state.clone();
-    let guarded_data = state_ref.read().await;
-    let maybe_collections =  guarded_data.deref().collections.get(collection_id.as_str());
+    let page_request = pagination::parse_page_request(&params, guarded_data.max_page_size).map_err(ApiError::BadRequest)?;
 
-    let (offset, limit) = get_offset_limit(&params);
+    let user_data = &guarded_data.users[&user_id];
 
-    match maybe_collections {
-        None=>(
-            StatusCode::NOT_FOUND,
-            Json(GenericResponse{
-                status: "not_found".into(),
-                detail: Some("That collection ID does not exist".into())
-            })
-        ).into_response(),
-        Some(collections)=>(
-            StatusCode::OK,
-            Json(CollectionContentResponse{
-                content_type: responses::ContentKind::Recipe,
-                content: collections.iter().skip(offset).take(limit).map(|s| s.to_owned()).collect(),
-                last_modified: Some(now),
-            })
-        ).into_response()
+    let collections = user_data.collections.get(collection_id.as_str()).ok_or(ApiError::CollectionNotFound)?;
+
+    if conditional::is_not_modified(&headers, collections.version, collections.last_modified) {
+        return Ok((
+            StatusCode::NOT_MODIFIED,
+            [
+                (header::ETAG, conditional::etag(collections.version)),
+                (header::LAST_MODIFIED, conditional::http_date(collections.last_modified)),
+            ]
+        ).into_response())
+    }
+
+    let page = pagination::paginate(&collections.content, &page_request);
+    let mut response_headers = HeaderMap::new();
+    response_headers.insert(header::ETAG, conditional::etag(collections.version));
+    response_headers.insert(header::LAST_MODIFIED, conditional::http_date(collections.last_modified));
+    if let Some(links) = page_links(uri.path(), &page, page_request.limit) {
+        response_headers.insert(header::LINK, links);
     }
+
+    Ok((
+        StatusCode::OK,
+        response_headers,
+        Json(CollectionContentResponse{
+            content_type: responses::ContentKind::Recipe,
+            last_modified: Some(collections.last_modified),
+            total: page.total,
+            has_more: page.has_more,
+            next_cursor: page.next.map(|c| c.encode()),
+            prev_cursor: page.prev.map(|c| c.encode()),
+            content: page.items,
+        })
+    ).into_response())
+}
+
+/// Lets a client that already has a copy of the collection catch up, rather than fetching the whole thing again.
+/// `since` is the `version` from an earlier response (or an ETag's number), or an RFC 3339 timestamp.
+#[utoipa::path(
+    get,
+    path="/collection/{collection_id}/changes",
+    tag="collections",
+    params(
+        ("collection_id"=String, Path),
+        ("since"=String, Query, description="A version from an earlier response, or an RFC 3339 timestamp"),
+    ),
+    responses(
+        (status=200, description="What has been added and removed since then", body=CollectionChangesResponse,
+            headers(("ETag"=String), ("Last-Modified"=String))),
+        (status=400, description="since is missing or not valid", content((ProblemDetails = "application/problem+json"), (GenericResponse = "application/json"))),
+        (status=404, description="There is no such collection", content((ProblemDetails = "application/problem+json"), (GenericResponse = "application/json"))),
+        (status=410, description="since is older than the history kept, so the whole collection must be fetched again", content((ProblemDetails = "application/problem+json"), (GenericResponse = "application/json"))),
+    )
+)]
+pub async fn get_collection_changes(
+    UserId(user_id): UserId,
+    Query(params): Query<HashMap<String, String>>,
+    Path(collection_id): Path<String>,
+    Extension(shared_state): Extension<SharedState>
+) -> Result<Response, ApiError> {
+    let since = match params.get("since") {
+        None=>return Err(ApiError::BadRequest("since is required: a version number or an RFC 3339 timestamp".into())),
+        Some(value)=>Since::parse(value).ok_or_else(|| ApiError::BadRequest(
+            format!("since must be a version number or an RFC 3339 timestamp, not '{}'", value)
+        ))?,
+    };
+
+    let guarded_data = read_for_user(&shared_state, &user_id, Some(&collection_id)).await;
+    let user_data = &guarded_data.users[&user_id];
+
+    let collection = user_data.collections.get(collection_id.as_str()).ok_or(ApiError::CollectionNotFound)?;
+    let delta = collection.changes_since(since).ok_or(ApiError::HistoryExpired)?;
+
+    Ok((
+        StatusCode::OK,
+        [
+            (header::ETAG, conditional::etag(collection.version)),
+            (header::LAST_MODIFIED, conditional::http_date(collection.last_modified)),
+        ],
+        Json(CollectionChangesResponse{
+            added: delta.added,
+            removed: delta.removed.into_iter().map(|t| Tombstone{
+                id: t.recipe_id,
+                version: t.version,
+                removed_at: t.removed_at,
+            }).collect(),
+            version: collection.version,
+            last_modified: collection.last_modified,
+        })
+    ).into_response())
 }
 
-async fn add_to_state(state:SharedState, collection_id:&str, recipe_id_list:Vec<&str>) -> Result<(), (http::status::StatusCode, String)>{
+/// Streams a `changed` event whenever recipes are added to or removed from any of the user's collections.
+/// Each event has an ID; reconnecting with Last-Event-ID sends what was missed, as long as it's still buffered.
+#[utoipa::path(
+    get,
+    path="/collection/events",
+    tag="collections",
+    params(("Last-Event-ID"=Option<String>, Header, description="The ID of the last event received, to resume from")),
+    responses(
+        (status=200, description="Server-sent events. `changed` events carry this as their data; a `resync` event means some were missed and everything should be fetched again.",
+            content_type="text/event-stream", body=CollectionEvent),
+    )
+)]
+pub async fn get_user_events(
+    UserId(user_id): UserId,
+    headers: HeaderMap,
+    Extension(shared_state): Extension<SharedState>
+) -> Response {
+    let events = shared_state.read().await.events.clone();
+    events.stream(&headers, user_id, None)
+}
+
+/// Like /collection/events, but only for the one collection
+#[utoipa::path(
+    get,
+    path="/collection/{collection_id}/events",
+    tag="collections",
+    params(
+        ("collection_id"=String, Path),
+        ("Last-Event-ID"=Option<String>, Header, description="The ID of the last event received, to resume from"),
+    ),
+    responses(
+        (status=200, description="Server-sent events, as for /collection/events", content_type="text/event-stream", body=CollectionEvent),
+        (status=404, description="There is no such collection", content((ProblemDetails = "application/problem+json"), (GenericResponse = "application/json"))),
+    )
+)]
+pub async fn get_collection_events(
+    UserId(user_id): UserId,
+    headers: HeaderMap,
+    Path(collection_id): Path<String>,
+    Extension(shared_state): Extension<SharedState>
+) -> Result<Response, ApiError> {
+    let guarded_data = read_for_user(&shared_state, &user_id, None).await;
+    let events = guarded_data.events.clone();
+    if !guarded_data.users[&user_id].collections.contains_key(&collection_id) {
+        return Err(ApiError::CollectionNotFound)
+    }
+    drop(guarded_data);
+
+    Ok(events.stream(&headers, user_id, Some(collection_id)))
+}
+
+/// What an add actually did
+#[derive(Debug)]
+struct AddResult {
+    version: u64,
+    added: Vec<String>,
+    already_present: Vec<String>,
+}
+
+/// Adds the given recipes, returning the collection's new version and which recipes were new.
+/// Adding a recipe that is already present does not change the collection, whatever position it is in.
+/// Fails with 412 Precondition Failed if `precondition` does not match the collection's current version.
+async fn add_to_state(state:SharedState, user_id:&str, collection_id:&str, recipe_id_list:Vec<&str>, precondition:&IfMatch) -> Result<AddResult, ApiError>{
     if recipe_id_list.is_empty() {
-        return Err( (StatusCode::BAD_REQUEST, "no recipes to add".into()))
+        return Err(ApiError::BadRequest("no recipes to add".into()))
     }
 
     let state_ref = state.clone();
     let mut guarded_data = state_ref.write().await;
-    
-    match guarded_data.deref_mut().collections.get_mut(collection_id) {
-        None=>Err( (StatusCode::NOT_FOUND, "collection did not exist".into()) ),
+
+    let changes = guarded_data.changes.clone();
+    let events = guarded_data.events.clone();
+    let policy = guarded_data.recently_viewed.clone();
+    let history_length = guarded_data.history_length;
+    let user_data = guarded_data.deref_mut().user(user_id);
+
+    match user_data.collections.get_mut(collection_id) {
+        None=>Err(ApiError::CollectionNotFound),
+        Some(mutable_collection) if !precondition.allows(mutable_collection.version)=>Err(ApiError::PreconditionFailed),
         Some(mutable_collection)=>{
-            recipe_id_list.iter().for_each(|recipe_id| {
-                mutable_collection.push(recipe_id.to_string());
-            });
-            mutable_collection.dedup();
-            Ok( () )
+            let mut seen:HashSet<&str> = HashSet::new();
+            let (already_present, added):(Vec<&str>, Vec<&str>) = recipe_id_list.iter()
+                .filter(|recipe_id| seen.insert(recipe_id))
+                .partition(|recipe_id| mutable_collection.content.contains(recipe_id));
+
+            let change = if mutable_collection.kind==CollectionKind::RecentlyViewed {
+                let mut expired = mutable_collection.expire_views(&policy);
+                let viewed = mutable_collection.record_views(&recipe_id_list, &policy);
+                expired.removed.extend(viewed.removed);
+                ContentChange{
+                    added: viewed.added,
+                    removed: expired.removed,
+                }
+            } else {
+                added.iter().for_each(|recipe_id| {
+                    mutable_collection.content.insert(recipe_id);
+                });
+                ContentChange{
+                    added: added.iter().map(|s| s.to_string()).collect(),
+                    removed: Vec::new(),
+                }
+            };
+            if !change.is_empty() {
+                mutable_collection.touch(change.clone(), history_length);
+                user_data.version += 1;
+                changes.notify_one();
+                events.publish(user_id, collection_id, mutable_collection.version, mutable_collection.last_modified, change);
+            }
+            Ok(AddResult{
+                version: mutable_collection.version,
+                added: added.iter().map(|s| s.to_string()).collect(),
+                already_present: already_present.iter().map(|s| s.to_string()).collect(),
+            })
         }
     }
 }
 
-async fn remove_from_state(state:SharedState, collection_id:&str, recipe_id_list:Vec<&str>) -> Result<(), (http::status::StatusCode, String)> {
-    //yeah this should be much more DRY. So shoot me.
+/// Removes the given recipes, returning the collection's new version.
+/// Fails with 412 Precondition Failed if `precondition` does not match the collection's current version.
+async fn remove_from_state(state:SharedState, user_id:&str, collection_id:&str, recipe_id_list:Vec<&str>, precondition:&IfMatch) -> Result<u64, ApiError> {
     if recipe_id_list.is_empty() {
-        return Err( (StatusCode::BAD_REQUEST, "no recipes to add".into()))
+        return Err(ApiError::BadRequest("no recipes to remove".into()))
     }
-    
+
     let state_ref = state.clone();
     let mut guarded_data = state_ref.write().await;
-    
-    let targets:HashSet<&str> = HashSet::from_iter(recipe_id_list);
-    
-    match guarded_data.deref_mut().collections.get_mut(collection_id) {
-        None=>Err( (StatusCode::NOT_FOUND, "collection did not exist".into()) ),
+
+    let changes = guarded_data.changes.clone();
+    let events = guarded_data.events.clone();
+    let history_length = guarded_data.history_length;
+    let user_data = guarded_data.deref_mut().user(user_id);
+
+    match user_data.collections.get_mut(collection_id) {
+        None=>Err(ApiError::CollectionNotFound),
+        Some(mutable_collection) if !precondition.allows(mutable_collection.version)=>Err(ApiError::PreconditionFailed),
         Some(mutable_collection)=>{
-            mutable_collection.retain(|id| !targets.contains(id.as_str()));
-            Ok( () )
+            let mut removed:Vec<String> = Vec::new();
+            for recipe_id in recipe_id_list {
+                if mutable_collection.content.remove(recipe_id) {
+                    removed.push(recipe_id.to_string());
+                }
+                mutable_collection.viewed_at.remove(recipe_id);
+            }
+            if !removed.is_empty() {
+                let change = ContentChange{ added: Vec::new(), removed };
+                mutable_collection.touch(change.clone(), history_length);
+                user_data.version += 1;
+                changes.notify_one();
+                events.publish(user_id, collection_id, mutable_collection.version, mutable_collection.last_modified, change);
+            }
+            Ok(mutable_collection.version)
         }
     }
 }
 
+/// Works out which recipe IDs a PUT or DELETE is about. They can come from a JSON body of the form `{"ids": [...]}`
+/// or, for older clients, a comma-separated `?id=` query parameter; not both. `verb` is used in the error messages.
+fn requested_ids(headers:&HeaderMap, params:&HashMap<String, String>, body:&Bytes, verb:&str) -> Result<Vec<String>, ApiError> {
+    let from_query:Option<Vec<String>> = params.get("id").map(|s| s.split(",").map(|id| id.to_owned()).collect());
+
+    if body.is_empty() {
+        return from_query.ok_or_else(|| ApiError::BadRequest(
+            format!("you must provide ?id= or a JSON body like {{\"ids\": [...]}} to indicate the ids to {}", verb)
+        ))
+    }
+
+    if from_query.is_some() {
+        return Err(ApiError::BadRequest("provide ids either in ?id= or in the request body, not both".into()))
+    }
+
+    let content_type = headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()).unwrap_or("");
+    let mime = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    if mime != "application/json" && !mime.ends_with("+json") {
+        return Err(ApiError::UnsupportedMediaType(format!("request bodies must be application/json, not '{}'", content_type)))
+    }
+
+    serde_json::from_slice::<RecipeIdsRequest>(body)
+        .map(|request| request.ids)
+        .map_err(|e| ApiError::BadRequest(format!("invalid JSON body: {}", e)))
+}
+
+#[utoipa::path(
+    put,
+    path="/collection/{collection_id}/contents",
+    tag="collections",
+    params(
+        ("collection_id"=String, Path),
+        ("id"=Option<String>, Query, description="Comma-separated recipe IDs, for clients that don't send a body"),
+        ("If-Match"=Option<String>, Header, description="Only make the change if the collection still has this ETag"),
+    ),
+    request_body(content=Option<RecipeIdsRequest>, description="The recipes to add, unless given in ?id="),
+    responses(
+        (status=200, description="What was added", body=UpdateResponse, headers(("ETag"=String))),
+        (status=400, description="No recipe IDs were given, or they were given twice", content((ProblemDetails = "application/problem+json"), (GenericResponse = "application/json"))),
+        (status=404, description="There is no such collection", content((ProblemDetails = "application/problem+json"), (GenericResponse = "application/json"))),
+        (status=412, description="The collection has changed since the If-Match ETag", content((ProblemDetails = "application/problem+json"), (GenericResponse = "application/json"))),
+        (status=415, description="The body is not JSON", content((ProblemDetails = "application/problem+json"), (GenericResponse = "application/json"))),
+    )
+)]
 pub async fn put_to_collection(
+    UserId(user_id): UserId,
+    headers: HeaderMap,
     Query(params): Query<HashMap<String, String>>,
     Path(collection_id):Path<String>,
     Extension(shared_state): Extension<SharedState>,
-) -> impl IntoResponse {
-    //this, too, should be DRYer :shrug:
-    let maybe_id_list:Option<Vec<&str>> = params.get("id").map(|s| s.split(",").collect());
-
-    match maybe_id_list {
-        None=>(
-            StatusCode::BAD_REQUEST,
-            Json(GenericResponse{
-                status: "bad_request".into(),
-                detail: Some("you must provide ?id= to indicate the ids to put".into())
-            })
-        ).into_response(),
-        Some(id_list)=>{
-            match add_to_state(shared_state, &collection_id, id_list).await {
-                Ok(_)=>(
-                    StatusCode::NO_CONTENT,
-                    Json(GenericResponse{
-                        status: "updated".into(),
-                        detail: None,
-                    })
-                ).into_response(),
-                Err((code, e))=>(
-                    code,
-                    Json(GenericResponse{
-                        status: "not_found".into(),
-                        detail: Some(e),
-                    })
-                ).into_response()
-            }
-        }
-    }
+    body: Bytes,
+) -> Result<Response, ApiError> {
+    let id_list = requested_ids(&headers, &params, &body, "put")?;
+    let result = add_to_state(shared_state, &user_id, &collection_id, id_list.iter().map(|s| s.as_str()).collect(), &IfMatch::from_headers(&headers)).await?;
+
+    Ok((
+        StatusCode::OK,
+        [(header::ETAG, conditional::etag(result.version))],
+        Json(UpdateResponse{
+            status: "updated".into(),
+            added: result.added,
+            already_present: result.already_present,
+        })
+    ).into_response())
 }
 
+#[utoipa::path(
+    delete,
+    path="/collection/{collection_id}/contents",
+    tag="collections",
+    params(
+        ("collection_id"=String, Path),
+        ("id"=Option<String>, Query, description="Comma-separated recipe IDs, for clients that don't send a body"),
+        ("If-Match"=Option<String>, Header, description="Only make the change if the collection still has this ETag"),
+    ),
+    request_body(content=Option<RecipeIdsRequest>, description="The recipes to remove, unless given in ?id="),
+    responses(
+        (status=204, description="The recipes are no longer in the collection", headers(("ETag"=String))),
+        (status=400, description="No recipe IDs were given, or they were given twice", content((ProblemDetails = "application/problem+json"), (GenericResponse = "application/json"))),
+        (status=404, description="There is no such collection", content((ProblemDetails = "application/problem+json"), (GenericResponse = "application/json"))),
+        (status=412, description="The collection has changed since the If-Match ETag", content((ProblemDetails = "application/problem+json"), (GenericResponse = "application/json"))),
+        (status=415, description="The body is not JSON", content((ProblemDetails = "application/problem+json"), (GenericResponse = "application/json"))),
+    )
+)]
 pub async fn delete_from_collection(
+    UserId(user_id): UserId,
+    headers: HeaderMap,
     Query(params): Query<HashMap<String, String>>,
     Path(collection_id):Path<String>,
     Extension(shared_state): Extension<SharedState>,
-) -> impl IntoResponse {
-    let maybe_id_list:Option<Vec<&str>> = params.get("id").map(|s| s.split(",").collect());
-
-    match maybe_id_list {
-        None=>(
-            StatusCode::BAD_REQUEST,
-            Json(GenericResponse{
-                status: "bad_request".into(),
-                detail: Some("you must provide ?id= to indicate the ids to remove".into())
-            })
-        ).into_response(),
-        Some(id_list)=>{
-            match remove_from_state(shared_state, &collection_id, id_list).await {
-                Ok(_)=>(
-                    StatusCode::NO_CONTENT,
-                    Json(GenericResponse{
-                        status: "updated".into(),
-                        detail: None,
-                    })
-                ).into_response(),
-                Err((code, e))=>(
-                    code,
-                    Json(GenericResponse{
-                        status: "not_found".into(),
-                        detail: Some(e),
-                    })
-                ).into_response()
-            }
-        }
-    }
+    body: Bytes,
+) -> Result<Response, ApiError> {
+    let id_list = requested_ids(&headers, &params, &body, "remove")?;
+    let version = remove_from_state(shared_state, &user_id, &collection_id, id_list.iter().map(|s| s.as_str()).collect(), &IfMatch::from_headers(&headers)).await?;
+
+    Ok((
+        StatusCode::NO_CONTENT,
+        [(header::ETAG, conditional::etag(version))],
+    ).into_response())
 }
 
+#[cfg(test)]
 mod test {
     use axum::Router;
     use axum_test::TestServer;
-    use axum::routing::get;
+    use axum::routing::{get, patch};
     use serde_json::Value;
+    use time::OffsetDateTime;
+    use crate::fixture::loader::{FixtureCollection, FixtureFile};
 
     use super::*;
 
+    const TEST_USER:&str = "test-user";
+
+    fn test_fixture(fixture:HashMap<String, Vec<String>>) -> FixtureFile {
+        FixtureFile{
+            collections: fixture.into_iter().map(|(id, recipes)| FixtureCollection{
+                id,
+                kind: CollectionKind::Saved,
+                name: None,
+                recipes,
+                last_modified: None,
+            }).collect()
+        }
+    }
+
+    fn test_state(fixture:HashMap<String, Vec<String>>) -> SharedState {
+        Arc::new(
+            RwLock::new(
+                MutableStaticData::from_fixture(&Environment::CODE, &test_fixture(fixture))
+            )
+        )
+    }
+
     #[tokio::test]
     async fn test_add_to_state() -> Result<(), String> {
         let mut fixture:HashMap<String, Vec<String>> = HashMap::new();
         fixture.insert("collection1".into(), vec!["recep1".into(),"recep2".into()]);
         fixture.insert("collection2".into(), vec!["recep3".into(), "recep4".into()]);
 
-        let state = Arc::new(
-            RwLock::new(
-                MutableStaticData{
-                    _env: Environment::CODE,
-                    collections: fixture
-                }
-            )
-        );
+        let state = test_state(fixture);
 
-        let result = add_to_state(state.clone(), "collection2", vec!["recep5"]).await;
+        let result = add_to_state(state.clone(), TEST_USER, "collection2", vec!["recep5"], &IfMatch::default()).await;
         let new_state = state.read().await;
 
         match result {
             Ok(_)=>{
-                assert_eq!(new_state.collections.len(), 2);
-                assert_eq!(new_state.collections.get("collection1").map(|c| c.len()), Some(2));
-                assert_eq!(new_state.collections.get("collection2").map(|c| c.len()), Some(3));
-                assert_eq!(new_state.collections.get("collection2").map(|c| c.contains(&"recep3".to_string())), Some(true));
-                assert_eq!(new_state.collections.get("collection2").map(|c| c.contains(&"recep4".to_string())), Some(true));
-                assert_eq!(new_state.collections.get("collection2").map(|c| c.contains(&"recep5".to_string())), Some(true));
+                assert_eq!(new_state.users[TEST_USER].collections.len(), 2);
+                assert_eq!(new_state.users[TEST_USER].collections.get("collection1").map(|c| c.content.len()), Some(2));
+                assert_eq!(new_state.users[TEST_USER].collections.get("collection2").map(|c| c.content.len()), Some(3));
+                assert_eq!(new_state.users[TEST_USER].collections.get("collection2").map(|c| c.content.contains("recep3")), Some(true));
+                assert_eq!(new_state.users[TEST_USER].collections.get("collection2").map(|c| c.content.contains("recep4")), Some(true));
+                assert_eq!(new_state.users[TEST_USER].collections.get("collection2").map(|c| c.content.contains("recep5")), Some(true));
                 
                 Ok( () )
             },
             Err(e)=>{
-                Err(format!("Unexpected return value {:?}", e)).into()
+                Err(format!("Unexpected return value {:?}", e))
             }
         }
     }
@@ -239,31 +674,24 @@ mod test {
         fixture.insert("collection1".into(), vec!["recep1".into(),"recep2".into()]);
         fixture.insert("collection2".into(), vec!["recep3".into(), "recep4".into(), "recep5".into()]);
 
-        let state = Arc::new(
-            RwLock::new(
-                MutableStaticData{
-                    _env: Environment::CODE,
-                    collections: fixture
-                }
-            )
-        );
+        let state = test_state(fixture);
 
-        let result = remove_from_state(state.clone(), "collection2", vec!["recep3"]).await;
+        let result = remove_from_state(state.clone(), TEST_USER, "collection2", vec!["recep3"], &IfMatch::default()).await;
         let new_state = state.read().await;
 
         match result {
             Ok(_)=>{
-                assert_eq!(new_state.collections.len(), 2);
-                assert_eq!(new_state.collections.get("collection1").map(|c| c.len()), Some(2));
-                assert_eq!(new_state.collections.get("collection2").map(|c| c.len()), Some(2));
-                assert_eq!(new_state.collections.get("collection2").map(|c| c.contains(&"recep3".to_string())), Some(false));
-                assert_eq!(new_state.collections.get("collection2").map(|c| c.contains(&"recep4".to_string())), Some(true));
-                assert_eq!(new_state.collections.get("collection2").map(|c| c.contains(&"recep5".to_string())), Some(true));
+                assert_eq!(new_state.users[TEST_USER].collections.len(), 2);
+                assert_eq!(new_state.users[TEST_USER].collections.get("collection1").map(|c| c.content.len()), Some(2));
+                assert_eq!(new_state.users[TEST_USER].collections.get("collection2").map(|c| c.content.len()), Some(2));
+                assert_eq!(new_state.users[TEST_USER].collections.get("collection2").map(|c| c.content.contains("recep3")), Some(false));
+                assert_eq!(new_state.users[TEST_USER].collections.get("collection2").map(|c| c.content.contains("recep4")), Some(true));
+                assert_eq!(new_state.users[TEST_USER].collections.get("collection2").map(|c| c.content.contains("recep5")), Some(true));
 
                 Ok( () )
             },
             Err(e)=>{
-                Err(format!("Unexpected return value {:?}", e)).into()
+                Err(format!("Unexpected return value {:?}", e))
             }
         }
     }
@@ -273,26 +701,14 @@ mod test {
         let mut fixture:HashMap<String, Vec<String>> = HashMap::new();
         fixture.insert("collection1".into(), vec!["recep1".into(),"recep2".into(),"recep3".into(),"recep4".into()]);
 
-        let state = Arc::new(
-            RwLock::new(
-                MutableStaticData{
-                    _env: Environment::CODE,
-                    collections: fixture
-                }
-            )
-        );
-
-        let mut params:HashMap<String,String> = HashMap::new();
-        params.insert("limit".into(), "2".into());
-        params.insert("offset".into(), "0".into());
+        let app = crate::MockServer::builder()
+            .env(Environment::CODE)
+            .fixture(test_fixture(fixture))
+            .router();
 
-        let fake_app = Router::new()
-            .route(&"/collection/{collection_id}/content", get(get_collection_content))
-            .layer(Extension(state));
+        let fake_server = TestServer::new(app).unwrap();
 
-        let fake_server = TestServer::new(fake_app).unwrap();
-
-        let page_one = fake_server.get("/collection/collection1/content?limit=2&offset=0").await;
+        let page_one = fake_server.get("/collection/collection1/contents?limit=2&offset=0").await;
         page_one.assert_status_ok();
 
         let page_one_data:Value = serde_json::from_str(&page_one.text()).unwrap();
@@ -302,7 +718,7 @@ mod test {
         assert_eq!(content_array[0], "recep1");
         assert_eq!(content_array[1], "recep2"); 
 
-        let page_two = fake_server.get("/collection/collection1/content?limit=2&offset=1").await;
+        let page_two = fake_server.get("/collection/collection1/contents?limit=2&offset=1").await;
         page_two.assert_status_ok();
 
         let page_two_data:Value = serde_json::from_str(&page_two.text()).unwrap();
@@ -313,7 +729,7 @@ mod test {
         assert_eq!(page_two_content_array[1], "recep3"); 
          
 
-        let last_page = fake_server.get("/collection/collection1/content?limit=2&offset=3").await;
+        let last_page = fake_server.get("/collection/collection1/contents?limit=2&offset=3").await;
         last_page.assert_status_ok();
 
         let last_page_data:Value = serde_json::from_str(&last_page.text()).unwrap();
@@ -323,7 +739,7 @@ mod test {
         assert_eq!(last_page_content_array[0], "recep4");
 
 
-        let empty_page = fake_server.get("/collection/collection1/content?limit=2&offset=56").await;
+        let empty_page = fake_server.get("/collection/collection1/contents?limit=2&offset=56").await;
         empty_page.assert_status_ok();
 
         let empty_page_data:Value = serde_json::from_str(&empty_page.text()).unwrap();
@@ -333,4 +749,470 @@ mod test {
 
         Ok( () )
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_get_collection_content_cursors() {
+        let mut fixture:HashMap<String, Vec<String>> = HashMap::new();
+        fixture.insert("collection1".into(), vec!["recep1".into(),"recep2".into(),"recep3".into()]);
+
+        let state = test_state(fixture);
+        state.write().await.max_page_size = 10;
+
+        let fake_app = Router::new()
+            .route("/collection/{collection_id}/contents", get(get_collection_content).put(put_to_collection))
+            .layer(Extension(state));
+
+        let fake_server = TestServer::new(fake_app).unwrap();
+
+        let page_one = fake_server.get("/collection/collection1/contents?limit=2").await;
+        page_one.assert_status_ok();
+        let page_one_data:Value = page_one.json();
+        assert_eq!(page_one_data["content"], serde_json::json!(["recep1", "recep2"]));
+        assert_eq!(page_one_data["total"], 3);
+        assert_eq!(page_one_data["hasMore"], true);
+        assert!(page_one_data.get("prevCursor").is_none());
+
+        let next_cursor = page_one_data["nextCursor"].as_str().unwrap();
+        assert_eq!(
+            page_one.header(header::LINK),
+            format!("</collection/collection1/contents?cursor={}&limit=2>; rel=\"next\"", next_cursor).as_str()
+        );
+
+        //the cursor still picks up after recep2 when something is added in the meantime
+        fake_server.put("/collection/collection1/contents?id=recep4").await.assert_status_ok();
+
+        let page_two:Value = fake_server.get(&format!("/collection/collection1/contents?limit=2&cursor={}", next_cursor)).await.json();
+        assert_eq!(page_two["content"], serde_json::json!(["recep3", "recep4"]));
+        assert_eq!(page_two["total"], 4);
+        assert_eq!(page_two["hasMore"], false);
+        assert!(page_two.get("nextCursor").is_none());
+
+        let back:Value = fake_server.get(&format!("/collection/collection1/contents?limit=2&cursor={}", page_two["prevCursor"].as_str().unwrap())).await.json();
+        assert_eq!(back["content"], serde_json::json!(["recep1", "recep2"]));
+
+        for bad_query in ["limit=0", "limit=11", "limit=two", "offset=-1", "cursor=nonsense", "offset=1&cursor=YTE"] {
+            fake_server.get(&format!("/collection/collection1/contents?{}", bad_query))
+                .await
+                .assert_status(StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_collections_are_per_user() {
+        let mut fixture:HashMap<String, Vec<String>> = HashMap::new();
+        fixture.insert("collection1".into(), vec!["recep1".into()]);
+
+        let state = test_state(fixture);
+
+        let fake_app = Router::new()
+            .route("/collection/{collection_id}/contents", get(get_collection_content).put(put_to_collection))
+            .layer(Extension(state.clone()));
+
+        let fake_server = TestServer::new(fake_app).unwrap();
+
+        fake_server.put("/collection/collection1/contents?id=recep2")
+            .add_header("X-User-Id", "alice")
+            .await
+            .assert_status(StatusCode::OK);
+
+        let alice:Value = fake_server.get("/collection/collection1/contents")
+            .add_header("Authorization", "Bearer alice")
+            .await
+            .json();
+        assert_eq!(alice["content"].as_array().map(|a| a.len()), Some(2));
+
+        let bob:Value = fake_server.get("/collection/collection1/contents")
+            .add_header("X-User-Id", "bob")
+            .await
+            .json();
+        assert_eq!(bob["content"].as_array().map(|a| a.len()), Some(1));
+
+        let anonymous:Value = fake_server.get("/collection/collection1/contents").await.json();
+        assert_eq!(anonymous["content"].as_array().map(|a| a.len()), Some(1));
+
+        let users = &state.read().await.users;
+        assert_eq!(users.len(), 3);
+        assert!(users.contains_key(identity::ANONYMOUS_USER));
+    }
+
+    #[tokio::test]
+    async fn test_reads_share_the_lock() {
+        let mut fixture:HashMap<String, Vec<String>> = HashMap::new();
+        fixture.insert("collection1".into(), vec!["recep1".into()]);
+
+        let state = test_state(fixture);
+        state.write().await.recently_viewed.max_age = Some(std::time::Duration::from_secs(3600));
+
+        let fake_app = Router::new()
+            .route("/collection", get(get_user_collections))
+            .route("/collection/{collection_id}/contents", get(get_collection_content))
+            .route("/collection/{collection_id}/changes", get(get_collection_changes))
+            .layer(Extension(state.clone()));
+
+        let fake_server = TestServer::new(fake_app).unwrap();
+
+        //the first request creates the user, which needs the write lock
+        fake_server.get("/collection").add_header("X-User-Id", "alice").await.assert_status_ok();
+
+        let reading = state.read().await;
+        let requests = async {
+            fake_server.get("/collection").add_header("X-User-Id", "alice").await.assert_status_ok();
+            fake_server.get("/collection/collection1/contents").add_header("X-User-Id", "alice").await.assert_status_ok();
+            fake_server.get("/collection/collection1/changes?since=1").add_header("X-User-Id", "alice").await.assert_status_ok();
+        };
+        let finished = tokio::time::timeout(std::time::Duration::from_secs(5), requests).await;
+        assert!(finished.is_ok(), "reads should not wait for the write lock");
+        drop(reading);
+    }
+
+    #[tokio::test]
+    async fn test_conditional_get() {
+        let mut fixture:HashMap<String, Vec<String>> = HashMap::new();
+        fixture.insert("collection1".into(), vec!["recep1".into()]);
+
+        let state = test_state(fixture);
+        let long_ago = OffsetDateTime::from_unix_timestamp(1737367200).unwrap();
+        {
+            let mut guarded_data = state.write().await;
+            let user_data = guarded_data.user(TEST_USER);
+            user_data.modified = long_ago;
+            user_data.collections.get_mut("collection1").unwrap().last_modified = long_ago;
+        }
+
+        let fake_app = Router::new()
+            .route("/collection", get(get_user_collections))
+            .route("/collection/{collection_id}/contents", get(get_collection_content).put(put_to_collection))
+            .layer(Extension(state.clone()));
+
+        let fake_server = TestServer::new(fake_app).unwrap();
+
+        for url in ["/collection", "/collection/collection1/contents"] {
+            let first = fake_server.get(url).add_header("X-User-Id", TEST_USER).await;
+            first.assert_status_ok();
+            assert_eq!(first.header("Last-Modified"), "Mon, 20 Jan 2025 10:00:00 GMT");
+
+            fake_server.get(url)
+                .add_header("X-User-Id", TEST_USER)
+                .add_header("If-Modified-Since", "Mon, 20 Jan 2025 10:00:00 GMT")
+                .await
+                .assert_status(StatusCode::NOT_MODIFIED);
+
+            fake_server.get(url)
+                .add_header("X-User-Id", TEST_USER)
+                .add_header("If-Modified-Since", "Mon, 20 Jan 2025 09:00:00 GMT")
+                .await
+                .assert_status_ok();
+        }
+
+        fake_server.put("/collection/collection1/contents?id=recep2")
+            .add_header("X-User-Id", TEST_USER)
+            .await
+            .assert_status(StatusCode::OK);
+
+        for url in ["/collection", "/collection/collection1/contents"] {
+            fake_server.get(url)
+                .add_header("X-User-Id", TEST_USER)
+                .add_header("If-Modified-Since", "Mon, 20 Jan 2025 10:00:00 GMT")
+                .await
+                .assert_status_ok();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_etags() {
+        let mut fixture:HashMap<String, Vec<String>> = HashMap::new();
+        fixture.insert("collection1".into(), vec!["recep1".into()]);
+
+        let state = test_state(fixture);
+
+        let fake_app = Router::new()
+            .route("/collection", get(get_user_collections))
+            .route("/collection/{collection_id}/contents", get(get_collection_content).put(put_to_collection).delete(delete_from_collection))
+            .layer(Extension(state.clone()));
+
+        let fake_server = TestServer::new(fake_app).unwrap();
+
+        let listing = fake_server.get("/collection").await;
+        assert_eq!(listing.header("ETag"), "\"1\"");
+
+        let content = fake_server.get("/collection/collection1/contents").await;
+        assert_eq!(content.header("ETag"), "\"1\"");
+
+        fake_server.get("/collection/collection1/contents")
+            .add_header("If-None-Match", "\"1\"")
+            .await
+            .assert_status(StatusCode::NOT_MODIFIED);
+
+        let put = fake_server.put("/collection/collection1/contents?id=recep2")
+            .add_header("If-Match", "\"1\"")
+            .await;
+        put.assert_status(StatusCode::OK);
+        assert_eq!(put.header("ETag"), "\"2\"");
+
+        //a stale If-Match must not change anything
+        fake_server.delete("/collection/collection1/contents?id=recep1")
+            .add_header("If-Match", "\"1\"")
+            .await
+            .assert_status(StatusCode::PRECONDITION_FAILED);
+        assert_eq!(state.read().await.users[identity::ANONYMOUS_USER].collections["collection1"].content.len(), 2);
+
+        fake_server.get("/collection/collection1/contents")
+            .add_header("If-None-Match", "\"1\"")
+            .await
+            .assert_status_ok();
+
+        let listing_after = fake_server.get("/collection")
+            .add_header("If-None-Match", "\"1\"")
+            .await;
+        listing_after.assert_status_ok();
+        assert_eq!(listing_after.header("ETag"), "\"2\"");
+    }
+
+    #[tokio::test]
+    async fn test_create_rename_delete_collection() {
+        let mut fixture:HashMap<String, Vec<String>> = HashMap::new();
+        fixture.insert("collection1".into(), vec!["recep1".into()]);
+
+        let state = test_state(fixture);
+
+        let fake_app = Router::new()
+            .route("/collection", get(get_user_collections).post(create_collection))
+            .route("/collection/{collection_id}", patch(rename_collection).delete(delete_collection))
+            .route("/collection/{collection_id}/contents", get(get_collection_content).put(put_to_collection))
+            .layer(Extension(state.clone()));
+
+        let fake_server = TestServer::new(fake_app).unwrap();
+
+        let created = fake_server.post("/collection")
+            .json(&serde_json::json!({"kind": "userCreated", "name": "Weeknight dinners"}))
+            .await;
+        created.assert_status(StatusCode::CREATED);
+        let created_data:Value = created.json();
+        let new_id = created_data["id"].as_str().unwrap().to_owned();
+        assert!(Uuid::parse_str(&new_id).is_ok());
+        assert_eq!(created_data["collectionType"], "userCreated");
+        assert_eq!(created_data["name"], "Weeknight dinners");
+        assert_eq!(created.header("Location"), format!("/collection/{}/contents", new_id));
+
+        fake_server.put(&format!("/collection/{}/contents?id=recep9", new_id))
+            .await
+            .assert_status(StatusCode::OK);
+
+        //the fixture already has a saved collection
+        fake_server.post("/collection")
+            .json(&serde_json::json!({"kind": "saved"}))
+            .await
+            .assert_status(StatusCode::CONFLICT);
+
+        let renamed:Value = fake_server.patch(&format!("/collection/{}", new_id))
+            .json(&serde_json::json!({"name": "Fakeaways"}))
+            .await
+            .json();
+        assert_eq!(renamed["name"], "Fakeaways");
+
+        let listing:Value = fake_server.get("/collection").await.json();
+        let collections = listing["collections"].as_array().unwrap();
+        assert_eq!(collections.len(), 2);
+        assert_eq!(collections[1]["id"], new_id);
+        assert_eq!(collections[1]["name"], "Fakeaways");
+        assert!(collections[0].get("name").is_none());
+
+        fake_server.delete(&format!("/collection/{}", new_id)).await.assert_status(StatusCode::NO_CONTENT);
+        fake_server.delete(&format!("/collection/{}", new_id)).await.assert_status(StatusCode::NOT_FOUND);
+        fake_server.get(&format!("/collection/{}/contents", new_id)).await.assert_status(StatusCode::NOT_FOUND);
+
+        let listing_after:Value = fake_server.get("/collection").await.json();
+        assert_eq!(listing_after["collections"].as_array().map(|a| a.len()), Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_put_reports_added_and_present() {
+        let mut fixture:HashMap<String, Vec<String>> = HashMap::new();
+        fixture.insert("collection1".into(), vec!["recep1".into(), "recep2".into(), "recep3".into()]);
+
+        let state = test_state(fixture);
+
+        let fake_app = Router::new()
+            .route("/collection/{collection_id}/contents", get(get_collection_content).put(put_to_collection))
+            .layer(Extension(state.clone()));
+
+        let fake_server = TestServer::new(fake_app).unwrap();
+
+        //recep1 is not adjacent to the end of the list, so a dedup() would not have caught it
+        let put = fake_server.put("/collection/collection1/contents?id=recep1,recep4,recep4")
+            .add_header("X-User-Id", TEST_USER)
+            .await;
+        put.assert_status_ok();
+        let put_data:Value = put.json();
+        assert_eq!(put_data["added"], serde_json::json!(["recep4"]));
+        assert_eq!(put_data["alreadyPresent"], serde_json::json!(["recep1"]));
+
+        let repeat:Value = fake_server.put("/collection/collection1/contents?id=recep1,recep4")
+            .add_header("X-User-Id", TEST_USER)
+            .await
+            .json();
+        assert_eq!(repeat["added"], serde_json::json!([]));
+        assert_eq!(repeat["alreadyPresent"], serde_json::json!(["recep1", "recep4"]));
+
+        let content:Value = fake_server.get("/collection/collection1/contents")
+            .add_header("X-User-Id", TEST_USER)
+            .await
+            .json();
+        assert_eq!(content["content"], serde_json::json!(["recep1", "recep2", "recep3", "recep4"]));
+        assert_eq!(state.read().await.users[TEST_USER].collections["collection1"].version, 2);
+    }
+
+    #[tokio::test]
+    async fn test_get_collection_changes() {
+        let mut fixture:HashMap<String, Vec<String>> = HashMap::new();
+        fixture.insert("collection1".into(), vec!["recep1".into(), "recep2".into()]);
+
+        let app = crate::MockServer::builder()
+            .env(Environment::CODE)
+            .fixture(test_fixture(fixture))
+            .history_length(2)
+            .router();
+
+        let fake_server = TestServer::new(app).unwrap();
+
+        let nothing_yet:Value = fake_server.get("/collection/collection1/changes?since=1").await.json();
+        assert_eq!(nothing_yet, serde_json::json!({
+            "added": [],
+            "removed": [],
+            "version": 1,
+            "lastModified": nothing_yet["lastModified"],
+        }));
+
+        fake_server.put("/collection/collection1/contents?id=recep3,recep4").await.assert_status_ok();
+        fake_server.delete("/collection/collection1/contents?id=recep1,recep3").await.assert_status(StatusCode::NO_CONTENT);
+
+        let changes = fake_server.get("/collection/collection1/changes?since=1").await;
+        changes.assert_status_ok();
+        assert_eq!(changes.header(header::ETAG), "\"3\"");
+        let changes_data:Value = changes.json();
+        assert_eq!(changes_data["added"], serde_json::json!(["recep4"]));
+        assert_eq!(changes_data["removed"].as_array().unwrap().iter().map(|t| t["id"].as_str().unwrap()).collect::<Vec<_>>(), vec!["recep1", "recep3"]);
+        assert_eq!(changes_data["removed"][0]["version"], 3);
+        assert_eq!(changes_data["version"], 3);
+
+        let by_time:Value = fake_server.get("/collection/collection1/changes")
+            .add_query_param("since", &changes_data["lastModified"])
+            .await
+            .json();
+        assert_eq!(by_time["added"], serde_json::json!([]));
+
+        //a third change pushes version 1 out of the history
+        fake_server.put("/collection/collection1/contents?id=recep5").await.assert_status_ok();
+        let expired = fake_server.get("/collection/collection1/changes?since=1").await;
+        expired.assert_status(StatusCode::GONE);
+        let expired_data:Value = expired.json();
+        assert_eq!(expired_data["type"], "urn:mock-recipe-persistence:problem:history-expired");
+
+        let latest:Value = fake_server.get("/collection/collection1/changes?since=3").await.json();
+        assert_eq!(latest["added"], serde_json::json!(["recep5"]));
+
+        fake_server.get("/collection/collection1/changes?since=9").await.assert_status(StatusCode::GONE);
+        fake_server.get("/collection/collection1/changes?since=yesterday").await.assert_status(StatusCode::BAD_REQUEST);
+        fake_server.get("/collection/collection1/changes").await.assert_status(StatusCode::BAD_REQUEST);
+        fake_server.get("/collection/missing/changes?since=1").await.assert_status(StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_collection_events() {
+        let mut fixture:HashMap<String, Vec<String>> = HashMap::new();
+        fixture.insert("collection1".into(), vec!["recep1".into()]);
+        fixture.insert("collection2".into(), Vec::new());
+
+        let server = crate::MockServer::builder()
+            .env(Environment::CODE)
+            .fixture(test_fixture(fixture))
+            .start()
+            .await
+            .unwrap();
+        let client = reqwest::Client::new();
+
+        let mut events = client.get(format!("{}/collection/collection1/events", server.url())).send().await.unwrap();
+        assert_eq!(events.status(), 200);
+        assert_eq!(events.headers()[header::CONTENT_TYPE], "text/event-stream");
+
+        client.put(format!("{}/collection/collection2/contents?id=recep2", server.url())).send().await.unwrap();
+        client.put(format!("{}/collection/collection1/contents?id=recep3", server.url())).send().await.unwrap();
+
+        let chunk = tokio::time::timeout(std::time::Duration::from_secs(5), events.chunk()).await.unwrap().unwrap().unwrap();
+        let message = String::from_utf8_lossy(&chunk);
+        assert!(message.starts_with("id: 2\nevent: changed\n"), "{}", message);
+        assert!(message.contains(r#""collectionId":"collection1","version":2"#), "{}", message);
+        assert!(message.contains(r#""added":["recep3"],"removed":[]"#), "{}", message);
+
+        let missing = client.get(format!("{}/collection/missing/events", server.url())).send().await.unwrap();
+        assert_eq!(missing.status(), 404);
+
+        //the open stream mustn't stop the server shutting down
+        tokio::time::timeout(std::time::Duration::from_secs(5), server.shutdown()).await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_json_bodies() {
+        let mut fixture:HashMap<String, Vec<String>> = HashMap::new();
+        fixture.insert("collection1".into(), vec!["recep1".into(), "recep2".into()]);
+
+        let state = test_state(fixture);
+
+        let fake_app = Router::new()
+            .route("/collection/{collection_id}/contents", get(get_collection_content).put(put_to_collection).delete(delete_from_collection))
+            .layer(Extension(state.clone()));
+
+        let fake_server = TestServer::new(fake_app).unwrap();
+
+        let put:Value = fake_server.put("/collection/collection1/contents")
+            .json(&serde_json::json!({"ids": ["recep3", "recep4"]}))
+            .await
+            .json();
+        assert_eq!(put["added"], serde_json::json!(["recep3", "recep4"]));
+
+        fake_server.delete("/collection/collection1/contents")
+            .json(&serde_json::json!({"ids": ["recep1", "recep3"]}))
+            .await
+            .assert_status(StatusCode::NO_CONTENT);
+
+        let content:Value = fake_server.get("/collection/collection1/contents").await.json();
+        assert_eq!(content["content"], serde_json::json!(["recep2", "recep4"]));
+
+        let wrong_type = fake_server.put("/collection/collection1/contents")
+            .text("ids=recep5")
+            .await;
+        wrong_type.assert_status(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        let malformed = fake_server.put("/collection/collection1/contents")
+            .content_type("application/json")
+            .bytes(r#"{"ids": ["recep5"}"#.into())
+            .await;
+        malformed.assert_status(StatusCode::BAD_REQUEST);
+        let malformed_data:Value = malformed.json();
+        assert_eq!(malformed_data["detail"], "invalid JSON body: expected `,` or `]` at line 1 column 18");
+
+        let wrong_shape:Value = fake_server.put("/collection/collection1/contents")
+            .json(&serde_json::json!({"id": "recep5"}))
+            .await
+            .json();
+        assert_eq!(wrong_shape["detail"], "invalid JSON body: unknown field `id`, expected `ids` at line 1 column 5");
+
+        fake_server.put("/collection/collection1/contents?id=recep6")
+            .json(&serde_json::json!({"ids": ["recep5"]}))
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+
+        let nothing_to_remove = fake_server.delete("/collection/collection1/contents")
+            .json(&serde_json::json!({"ids": []}))
+            .await;
+        nothing_to_remove.assert_status(StatusCode::BAD_REQUEST);
+        let nothing_to_remove_data:Value = nothing_to_remove.json();
+        assert_eq!(nothing_to_remove_data["type"], "urn:mock-recipe-persistence:problem:bad-request");
+        assert_eq!(nothing_to_remove_data["detail"], "no recipes to remove");
+
+        //the query string still works on its own
+        fake_server.put("/collection/collection1/contents?id=recep6")
+            .await
+            .assert_status_ok();
+    }
+}
@@ -0,0 +1,99 @@
+use axum::Json;
+use utoipa::{openapi::{path::{Parameter, ParameterBuilder, ParameterIn}, schema::{Object, Type}, security::{Http, HttpAuthScheme, SecurityRequirement, SecurityScheme}, Required}, Modify, OpenApi};
+use super::identity::USER_ID_HEADER;
+
+/// The contract for the collection endpoints. The /__admin API is for tests to drive the mock, so it's left out.
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title="Mock recipe persistence endpoints",
+        description="Errors are application/problem+json (RFC 7807), or `{\"status\": ..., \"detail\": ...}` when the mock is run with --legacy-errors.",
+    ),
+    paths(
+        super::get_user_collections,
+        super::create_collection,
+        super::rename_collection,
+        super::delete_collection,
+        super::get_collection_content,
+        super::put_to_collection,
+        super::delete_from_collection,
+        super::get_collection_changes,
+        super::get_user_events,
+        super::get_collection_events,
+    ),
+    tags((name="collections", description="A user's recipe collections and what's in them")),
+    modifiers(&UserIdentity),
+)]
+struct ApiDoc;
+
+/// Every collection endpoint is per-user, so rather than repeat it on each handler this adds the ways of identifying the user to all of them
+struct UserIdentity;
+
+impl Modify for UserIdentity {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        openapi.components.get_or_insert_with(Default::default).add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+        );
+        // The token is optional, hence the empty requirement alongside it
+        openapi.security = Some(vec![SecurityRequirement::new("bearer", Vec::<String>::new()), SecurityRequirement::default()]);
+
+        let user_id:Parameter = ParameterBuilder::new()
+            .name(USER_ID_HEADER)
+            .parameter_in(ParameterIn::Header)
+            .required(Required::False)
+            .description(Some("Whose collections to use. Falls back to the bearer token, then to a shared anonymous user."))
+            .schema(Some(Object::with_type(Type::String)))
+            .build();
+
+        for path_item in openapi.paths.paths.values_mut() {
+            let operations = [&mut path_item.get, &mut path_item.post, &mut path_item.put, &mut path_item.patch, &mut path_item.delete];
+            for operation in operations.into_iter().flatten() {
+                operation.parameters.get_or_insert_with(Vec::new).insert(0, user_id.clone());
+            }
+        }
+    }
+}
+
+/// The OpenAPI 3.1 document for the collection endpoints
+pub fn document() -> utoipa::openapi::OpenApi {
+    let mut document = ApiDoc::openapi();
+    // Filled in from Cargo.toml, which doesn't name one
+    document.info.license = None;
+    document
+}
+
+pub async fn serve_openapi() -> Json<utoipa::openapi::OpenApi> {
+    Json(document())
+}
+
+#[cfg(test)]
+mod test {
+    use axum::{routing::get, Router};
+    use axum_test::TestServer;
+    use serde_json::Value;
+    use super::*;
+
+    #[tokio::test]
+    async fn test_serve_openapi() {
+        let fake_app = Router::new()
+            .route("/openapi.json", get(serve_openapi));
+
+        let fake_server = TestServer::new(fake_app).unwrap();
+
+        let response = fake_server.get("/openapi.json").await;
+        response.assert_status_ok();
+        let document:Value = response.json();
+
+        assert_eq!(document["openapi"], "3.1.0");
+        assert!(document["paths"]["/collection"]["get"].is_object());
+        assert!(document["paths"]["/collection/{collection_id}/contents"]["put"].is_object());
+        for schema in ["CollectionsResponse", "CollectionResponse", "CollectionContentResponse", "GenericResponse", "ProblemDetails"] {
+            assert!(document["components"]["schemas"][schema].is_object(), "{} should be in the components", schema);
+        }
+
+        let parameters = document["paths"]["/collection/{collection_id}/contents"]["get"]["parameters"].as_array().unwrap();
+        let names:Vec<&str> = parameters.iter().filter_map(|p| p["name"].as_str()).collect();
+        assert_eq!(names, vec![USER_ID_HEADER, "collection_id", "limit", "offset", "cursor", "If-None-Match", "If-Modified-Since"]);
+    }
+}
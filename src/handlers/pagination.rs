@@ -0,0 +1,170 @@
+use std::collections::HashMap;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use crate::fixture::recipe_set::RecipeSet;
+
+pub const DEFAULT_LIMIT:usize = 100;
+
+/// Marks a point in a collection to page from. Cursors refer to recipe positions rather than indices, so recipes
+/// being added or removed elsewhere in the collection don't cause a page to skip or repeat anything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cursor {
+    After(i64),
+    Before(i64),
+}
+
+impl Cursor {
+    /// Cursors are opaque to clients; this just stops them looking like something that can be edited
+    pub fn encode(&self) -> String {
+        let raw = match self {
+            Cursor::After(position)=>format!("a{}", position),
+            Cursor::Before(position)=>format!("b{}", position),
+        };
+        URL_SAFE_NO_PAD.encode(raw)
+    }
+
+    pub fn decode(encoded:&str) -> Option<Cursor> {
+        let raw = String::from_utf8(URL_SAFE_NO_PAD.decode(encoded).ok()?).ok()?;
+        let (direction, position) = raw.split_at_checked(1)?;
+        let position:i64 = position.parse().ok()?;
+
+        match direction {
+            "a"=>Some(Cursor::After(position)),
+            "b"=>Some(Cursor::Before(position)),
+            _=>None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageStart {
+    Offset(usize),
+    Cursor(Cursor),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageRequest {
+    pub start: PageStart,
+    pub limit: usize,
+}
+
+/// Reads `limit` and either `offset` or `cursor` from the query string.
+/// Anything unparseable, a limit of zero or above `max_limit`, or both an offset and a cursor is an error.
+pub fn parse_page_request(params:&HashMap<String, String>, max_limit:usize) -> Result<PageRequest, String> {
+    let limit:usize = match params.get("limit") {
+        None=>DEFAULT_LIMIT.min(max_limit),
+        Some(limit)=>match str::parse::<usize>(limit) {
+            Ok(limit) if limit > 0 && limit <= max_limit=>limit,
+            _=>return Err(format!("limit must be a whole number from 1 to {}, not '{}'", max_limit, limit)),
+        },
+    };
+
+    let start = match (params.get("offset"), params.get("cursor")) {
+        (Some(_), Some(_))=>return Err("give either offset or cursor, not both".into()),
+        (None, None)=>PageStart::Offset(0),
+        (Some(offset), None)=>PageStart::Offset(
+            str::parse(offset).map_err(|_| format!("offset must be a whole number, not '{}'", offset))?
+        ),
+        (None, Some(cursor))=>PageStart::Cursor(
+            Cursor::decode(cursor).ok_or_else(|| format!("'{}' is not a valid cursor", cursor))?
+        ),
+    };
+
+    Ok(PageRequest{
+        start,
+        limit,
+    })
+}
+
+#[derive(Debug)]
+pub struct Page {
+    pub items: Vec<String>,
+    pub total: usize,
+    pub has_more: bool,
+    pub next: Option<Cursor>,
+    pub prev: Option<Cursor>,
+}
+
+pub fn paginate(content:&RecipeSet, request:&PageRequest) -> Page {
+    let entries:Vec<(i64, &String)> = match request.start {
+        PageStart::Offset(offset)=>content.entries().skip(offset).take(request.limit).collect(),
+        PageStart::Cursor(Cursor::After(position))=>content.entries_after(position).take(request.limit).collect(),
+        PageStart::Cursor(Cursor::Before(position))=>{
+            let mut backwards:Vec<(i64, &String)> = content.entries_before(position).rev().take(request.limit).collect();
+            backwards.reverse();
+            backwards
+        },
+    };
+
+    let has_more = entries.last().map(|(last, _)| content.entries_after(*last).next().is_some()).unwrap_or(false);
+    let has_prev = entries.first().map(|(first, _)| content.entries_before(*first).next().is_some()).unwrap_or(false);
+
+    Page{
+        next: if has_more { entries.last().map(|(last, _)| Cursor::After(*last)) } else { None },
+        prev: if has_prev { entries.first().map(|(first, _)| Cursor::Before(*first)) } else { None },
+        items: entries.into_iter().map(|(_, id)| id.to_owned()).collect(),
+        total: content.len(),
+        has_more,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn params(pairs:&[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn test_cursor_roundtrip() {
+        for cursor in [Cursor::After(12), Cursor::Before(-3)] {
+            assert_eq!(Cursor::decode(&cursor.encode()), Some(cursor));
+        }
+        assert_eq!(Cursor::decode("not a cursor"), None);
+        assert_eq!(Cursor::decode(&URL_SAFE_NO_PAD.encode("x12")), None);
+    }
+
+    #[test]
+    fn test_parse_page_request() {
+        assert_eq!(parse_page_request(&params(&[]), 50), Ok(PageRequest{ start: PageStart::Offset(0), limit: 50 }));
+        assert_eq!(parse_page_request(&params(&[("limit", "10"), ("offset", "5")]), 50), Ok(PageRequest{ start: PageStart::Offset(5), limit: 10 }));
+
+        assert!(parse_page_request(&params(&[("limit", "0")]), 50).is_err());
+        assert!(parse_page_request(&params(&[("limit", "51")]), 50).is_err());
+        assert!(parse_page_request(&params(&[("limit", "ten")]), 50).is_err());
+        assert!(parse_page_request(&params(&[("offset", "-1")]), 50).is_err());
+        assert!(parse_page_request(&params(&[("offset", "1"), ("cursor", "YTE")]), 50).is_err());
+    }
+
+    #[test]
+    fn test_cursors_are_stable_under_inserts() {
+        let mut content:RecipeSet = ["r1", "r2", "r3", "r4", "r5"].iter().map(|s| s.to_string()).collect();
+
+        let first = paginate(&content, &PageRequest{ start: PageStart::Offset(0), limit: 2 });
+        assert_eq!(first.items, vec!["r1", "r2"]);
+        assert_eq!(first.total, 5);
+        assert!(first.has_more);
+        assert_eq!(first.prev, None);
+
+        //an insert and a removal before the next page is fetched mustn't make it skip or repeat anything
+        content.remove("r1");
+        content.insert("r6");
+
+        let second = paginate(&content, &PageRequest{ start: PageStart::Cursor(first.next.unwrap()), limit: 2 });
+        assert_eq!(second.items, vec!["r3", "r4"]);
+        assert!(second.has_more);
+
+        let third = paginate(&content, &PageRequest{ start: PageStart::Cursor(second.next.unwrap()), limit: 2 });
+        assert_eq!(third.items, vec!["r5", "r6"]);
+        assert!(!third.has_more);
+        assert_eq!(third.next, None);
+
+        let back = paginate(&content, &PageRequest{ start: PageStart::Cursor(third.prev.unwrap()), limit: 2 });
+        assert_eq!(back.items, vec!["r3", "r4"]);
+        assert!(back.has_more);
+
+        let start = paginate(&content, &PageRequest{ start: PageStart::Cursor(back.prev.unwrap()), limit: 2 });
+        assert_eq!(start.items, vec!["r2"]);
+        assert_eq!(start.prev, None);
+    }
+}
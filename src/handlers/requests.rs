@@ -0,0 +1,22 @@
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::fixture::models::CollectionKind;
+
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct CreateCollectionRequest {
+    pub kind: CollectionKind,
+    pub name: Option<String>,
+}
+
+/// A null or missing name removes the collection's name
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct RenameCollectionRequest {
+    pub name: Option<String>,
+}
+
+/// The body of a PUT or DELETE on a collection's contents
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RecipeIdsRequest {
+    pub ids: Vec<String>,
+}
@@ -1,22 +1,81 @@
 use serde::{Serialize, Deserialize};
+use utoipa::ToSchema;
 
-#[derive(Serialize)]
+/// Errors take this shape with --legacy-errors
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
 pub struct GenericResponse {
     pub status: String,
     pub detail: Option<String>
 }
 
+/// An RFC 7807 problem, sent as application/problem+json
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct ProblemDetails {
+    #[serde(rename="type")]
+    pub problem_type: String,
+    pub title: String,
+    pub status: u16,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub detail: Option<String>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub instance: Option<String>,
+}
+
+/// Returned from a PUT to a collection's contents
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct UpdateResponse {
+    pub status: String,
+    /// IDs that were not in the collection before
+    pub added: Vec<String>,
+    /// IDs that were already in the collection, which are left where they were (or moved to the front, for recentlyViewed)
+    #[serde(rename="alreadyPresent")]
+    pub already_present: Vec<String>,
+}
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
 pub enum ContentKind {
     Recipe,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
 pub struct CollectionContentResponse {
     pub content:Vec<String>,
     #[serde(rename="contentType")]
     pub content_type: ContentKind,
     #[serde(rename="lastModified")]
-    pub last_modified:Option<time::OffsetDateTime>   //also in header
+    #[schema(value_type=Option<String>)]
+    pub last_modified:Option<time::OffsetDateTime>,   //also in header
+    /// How many recipes are in the whole collection, not just this page
+    pub total: usize,
+    #[serde(rename="hasMore")]
+    pub has_more: bool,
+    /// Pass as `cursor` to get the page after this one; absent on the last page
+    #[serde(rename="nextCursor", skip_serializing_if="Option::is_none")]
+    pub next_cursor: Option<String>,
+    /// Pass as `cursor` to get the page before this one; absent on the first page
+    #[serde(rename="prevCursor", skip_serializing_if="Option::is_none")]
+    pub prev_cursor: Option<String>,
+}
+
+/// A recipe that has left a collection
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct Tombstone {
+    pub id: String,
+    /// The collection version the recipe was removed in
+    pub version: u64,
+    #[serde(rename="removedAt", with="time::serde::rfc3339")]
+    pub removed_at: time::OffsetDateTime,
+}
+
+/// What has happened to a collection's contents since a given point. A recipe is listed in `added` or `removed`
+/// according to whatever last happened to it.
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct CollectionChangesResponse {
+    /// In the order they were added. Recipes viewed again in a recentlyViewed collection are listed as added, having moved to the front.
+    pub added: Vec<String>,
+    pub removed: Vec<Tombstone>,
+    /// The collection's current version; pass as `since` to get the changes after this
+    pub version: u64,
+    #[serde(rename="lastModified", with="time::serde::rfc3339")]
+    pub last_modified: time::OffsetDateTime,
 }
@@ -0,0 +1,328 @@
+use std::collections::HashSet;
+use axum::{extract::ws::{Message, WebSocket, WebSocketUpgrade}, response::Response, Extension};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::events::{CollectionEvent, Received};
+use super::{add_to_state, conditional::{self, IfMatch}, errors::ApiError, identity::UserId, read_for_user, remove_from_state, responses::ProblemDetails, SharedState};
+
+/// Something a client asks for over the socket. Every command is answered with an `ack` or an `error` carrying its `requestId`.
+#[derive(Deserialize, Debug)]
+struct CommandMessage {
+    #[serde(rename="requestId")]
+    request_id: String,
+    #[serde(flatten)]
+    command: Command,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag="type")]
+enum Command {
+    /// Start sending `changed` messages for the collection, or for all of the user's collections if none is given
+    #[serde(rename="subscribe")]
+    Subscribe{
+        #[serde(rename="collectionId", default)]
+        collection_id: Option<String>,
+    },
+    #[serde(rename="unsubscribe")]
+    Unsubscribe{
+        #[serde(rename="collectionId", default)]
+        collection_id: Option<String>,
+    },
+    #[serde(rename="add")]
+    Add{
+        #[serde(rename="collectionId")]
+        collection_id: String,
+        ids: Vec<String>,
+        /// An ETag, as for the If-Match header
+        #[serde(rename="ifMatch", default)]
+        if_match: Option<String>,
+    },
+    #[serde(rename="remove")]
+    Remove{
+        #[serde(rename="collectionId")]
+        collection_id: String,
+        ids: Vec<String>,
+        #[serde(rename="ifMatch", default)]
+        if_match: Option<String>,
+    },
+}
+
+/// Everything the server sends
+#[derive(Serialize, Debug)]
+#[serde(tag="type")]
+enum Reply {
+    #[serde(rename="ack")]
+    Ack{
+        #[serde(rename="requestId")]
+        request_id: String,
+        /// For adds and removes, the collection's version afterwards
+        #[serde(skip_serializing_if="Option::is_none")]
+        version: Option<u64>,
+        #[serde(skip_serializing_if="Option::is_none")]
+        etag: Option<String>,
+        #[serde(skip_serializing_if="Option::is_none")]
+        added: Option<Vec<String>>,
+        #[serde(rename="alreadyPresent", skip_serializing_if="Option::is_none")]
+        already_present: Option<Vec<String>>,
+    },
+    /// `requestId` is missing only if the message was too broken to find it in
+    #[serde(rename="error")]
+    Error{
+        #[serde(rename="requestId")]
+        request_id: Option<String>,
+        error: ProblemDetails,
+    },
+    /// The same as the data of an SSE `changed` event
+    #[serde(rename="changed")]
+    Changed{
+        #[serde(rename="eventId")]
+        event_id: u64,
+        #[serde(flatten)]
+        event: CollectionEvent,
+    },
+    /// Some changes couldn't be sent, so everything subscribed to should be fetched again
+    #[serde(rename="resync")]
+    Resync,
+}
+
+impl Reply {
+    fn ack(request_id:String) -> Reply {
+        Reply::Ack{
+            request_id,
+            version: None,
+            etag: None,
+            added: None,
+            already_present: None,
+        }
+    }
+
+    /// For a change that left the collection at `version`
+    fn change_ack(request_id:String, version:u64, added:Option<Vec<String>>, already_present:Option<Vec<String>>) -> Reply {
+        Reply::Ack{
+            request_id,
+            version: Some(version),
+            etag: conditional::etag(version).to_str().ok().map(|etag| etag.to_owned()),
+            added,
+            already_present,
+        }
+    }
+
+    fn error(request_id:Option<String>, error:ApiError) -> Reply {
+        Reply::Error{
+            request_id,
+            error: error.problem(None),
+        }
+    }
+}
+
+/// Which collections a socket wants to hear about
+#[derive(Debug, Default)]
+struct Subscriptions {
+    everything: bool,
+    collections: HashSet<String>,
+}
+
+impl Subscriptions {
+    fn includes(&self, collection_id:&str) -> bool {
+        self.everything || self.collections.contains(collection_id)
+    }
+}
+
+/// A WebSocket for clients that want to make changes and hear about them over the one connection.
+/// Messages are JSON, in both directions; adds and removes go through the same code as the HTTP endpoints.
+pub async fn websocket(
+    UserId(user_id): UserId,
+    Extension(shared_state): Extension<SharedState>,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    upgrade.on_upgrade(move |socket| serve_socket(socket, shared_state, user_id))
+}
+
+async fn serve_socket(mut socket:WebSocket, shared_state:SharedState, user_id:String) {
+    let mut events = shared_state.read().await.events.subscribe();
+    let mut subscriptions = Subscriptions::default();
+
+    loop {
+        let reply = tokio::select! {
+            message = socket.recv()=>match message {
+                Some(Ok(Message::Text(text)))=>run_command(text.as_str(), &shared_state, &user_id, &mut subscriptions).await,
+                Some(Ok(Message::Binary(_)))=>Reply::error(None, ApiError::BadRequest("messages must be JSON text".into())),
+                //pings are answered for us
+                Some(Ok(Message::Ping(_) | Message::Pong(_)))=>continue,
+                Some(Ok(Message::Close(_)) | Err(_)) | None=>return,
+            },
+            received = events.next()=>match received {
+                Some(Received::Event(event)) if event.concerns(&user_id, None) && subscriptions.includes(&event.collection_id)=>Reply::Changed{
+                    event_id: event.id,
+                    event,
+                },
+                Some(Received::Event(_))=>continue,
+                Some(Received::Missed)=>Reply::Resync,
+                None=>{
+                    let _ = socket.send(Message::Close(None)).await;
+                    return
+                },
+            },
+        };
+
+        let Ok(text) = serde_json::to_string(&reply) else {
+            continue
+        };
+        if socket.send(Message::Text(text.into())).await.is_err() {
+            return
+        }
+    }
+}
+
+async fn run_command(text:&str, shared_state:&SharedState, user_id:&str, subscriptions:&mut Subscriptions) -> Reply {
+    let message:CommandMessage = match serde_json::from_str(text) {
+        Ok(message)=>message,
+        Err(e)=>{
+            //answer with the requestId if there is one, so that the client knows which command failed
+            let request_id = serde_json::from_str::<Value>(text).ok()
+                .and_then(|value| value.get("requestId").and_then(|id| id.as_str()).map(|id| id.to_owned()));
+            return Reply::error(request_id, ApiError::BadRequest(format!("invalid command: {}", e)))
+        },
+    };
+    let request_id = message.request_id;
+
+    let result = match message.command {
+        Command::Subscribe{ collection_id: None }=>{
+            subscriptions.everything = true;
+            Ok(Reply::ack(request_id.clone()))
+        },
+        Command::Subscribe{ collection_id: Some(collection_id) }=>{
+            let exists = read_for_user(shared_state, user_id, None).await.users[user_id].collections.contains_key(&collection_id);
+            if exists {
+                subscriptions.collections.insert(collection_id);
+                Ok(Reply::ack(request_id.clone()))
+            } else {
+                Err(ApiError::CollectionNotFound)
+            }
+        },
+        Command::Unsubscribe{ collection_id: None }=>{
+            *subscriptions = Subscriptions::default();
+            Ok(Reply::ack(request_id.clone()))
+        },
+        Command::Unsubscribe{ collection_id: Some(collection_id) }=>{
+            subscriptions.collections.remove(&collection_id);
+            Ok(Reply::ack(request_id.clone()))
+        },
+        Command::Add{ collection_id, ids, if_match }=>{
+            add_to_state(shared_state.clone(), user_id, &collection_id, ids.iter().map(|s| s.as_str()).collect(), &IfMatch::from_value(if_match.as_deref())).await
+                .map(|result| Reply::change_ack(request_id.clone(), result.version, Some(result.added), Some(result.already_present)))
+        },
+        Command::Remove{ collection_id, ids, if_match }=>{
+            remove_from_state(shared_state.clone(), user_id, &collection_id, ids.iter().map(|s| s.as_str()).collect(), &IfMatch::from_value(if_match.as_deref())).await
+                .map(|version| Reply::change_ack(request_id.clone(), version, None, None))
+        },
+    };
+
+    result.unwrap_or_else(|e| Reply::error(Some(request_id), e))
+}
+
+#[cfg(test)]
+mod test {
+    use axum_test::{TestServer, TestWebSocket};
+    use serde_json::json;
+    use crate::fixture::{loader::{FixtureCollection, FixtureFile}, models::CollectionKind, Environment};
+    use super::*;
+
+    fn test_server() -> TestServer {
+        let app = crate::MockServer::builder()
+            .env(Environment::CODE)
+            .fixture(FixtureFile{
+                collections: ["c1", "c2"].iter().map(|id| FixtureCollection{
+                    id: id.to_string(),
+                    kind: if *id=="c1" { CollectionKind::Saved } else { CollectionKind::Cooked },
+                    name: None,
+                    recipes: vec!["r1".into()],
+                    last_modified: None,
+                }).collect(),
+            })
+            .router();
+
+        TestServer::builder().http_transport().build(app).unwrap()
+    }
+
+    async fn connect(server:&TestServer, user_id:&str) -> TestWebSocket {
+        server.get_websocket("/ws").add_header("X-User-Id", user_id).await.into_websocket().await
+    }
+
+    #[tokio::test]
+    async fn test_commands_and_acks() {
+        let server = test_server();
+        let mut socket = connect(&server, "alice").await;
+
+        socket.send_json(&json!({"type": "add", "requestId": "a1", "collectionId": "c1", "ids": ["r2", "r1"]})).await;
+        socket.assert_receive_json(&json!({"type": "ack", "requestId": "a1", "version": 2, "etag": "\"2\"", "added": ["r2"], "alreadyPresent": ["r1"]})).await;
+
+        socket.send_json(&json!({"type": "remove", "requestId": "a2", "collectionId": "c1", "ids": ["r1"], "ifMatch": "\"1\""})).await;
+        let stale:Value = socket.receive_json().await;
+        assert_eq!(stale["type"], "error");
+        assert_eq!(stale["requestId"], "a2");
+        assert_eq!(stale["error"]["status"], 412);
+
+        socket.send_json(&json!({"type": "remove", "requestId": "a3", "collectionId": "c1", "ids": ["r1"], "ifMatch": "\"2\""})).await;
+        socket.assert_receive_json(&json!({"type": "ack", "requestId": "a3", "version": 3, "etag": "\"3\""})).await;
+
+        socket.send_json(&json!({"type": "add", "requestId": "a4", "collectionId": "missing", "ids": ["r1"]})).await;
+        let missing:Value = socket.receive_json().await;
+        assert_eq!(missing["error"]["type"], "urn:mock-recipe-persistence:problem:collection-not-found");
+
+        socket.send_json(&json!({"type": "shout", "requestId": "a5"})).await;
+        let unknown:Value = socket.receive_json().await;
+        assert_eq!(unknown["requestId"], "a5");
+        assert_eq!(unknown["error"]["status"], 400);
+
+        //the HTTP endpoints see the same data
+        let content:Value = server.get("/collection/c1/contents").add_header("X-User-Id", "alice").await.json();
+        assert_eq!(content["content"], json!(["r2"]));
+    }
+
+    #[tokio::test]
+    async fn test_subscriptions() {
+        let server = test_server();
+        let mut socket = connect(&server, "alice").await;
+
+        socket.send_json(&json!({"type": "subscribe", "requestId": "s1", "collectionId": "c2"})).await;
+        socket.assert_receive_json(&json!({"type": "ack", "requestId": "s1"})).await;
+        socket.send_json(&json!({"type": "subscribe", "requestId": "s2", "collectionId": "missing"})).await;
+        let missing:Value = socket.receive_json().await;
+        assert_eq!(missing["error"]["status"], 404);
+
+        //changes made over HTTP, by this user and someone else, and to a collection that isn't subscribed to
+        server.put("/collection/c1/contents?id=r2").add_header("X-User-Id", "alice").await.assert_status_ok();
+        server.put("/collection/c2/contents?id=r3").add_header("X-User-Id", "bob").await.assert_status_ok();
+        server.put("/collection/c2/contents?id=r4").add_header("X-User-Id", "alice").await.assert_status_ok();
+
+        let changed:Value = socket.receive_json().await;
+        assert_eq!(changed["type"], "changed");
+        assert_eq!(changed["collectionId"], "c2");
+        assert_eq!(changed["added"], json!(["r4"]));
+        assert_eq!(changed["version"], 2);
+        assert_eq!(changed["eventId"], 3);
+
+        //a change made over the socket is acknowledged as well as reported, if subscribed to
+        socket.send_json(&json!({"type": "remove", "requestId": "r1", "collectionId": "c2", "ids": ["r4"]})).await;
+        let mut replies:Vec<Value> = vec![socket.receive_json().await, socket.receive_json().await];
+        replies.sort_by_key(|reply| reply["type"].as_str().map(|t| t.to_owned()));
+        assert_eq!(replies[0]["requestId"], "r1");
+        assert_eq!(replies[1]["removed"], json!(["r4"]));
+
+        socket.send_json(&json!({"type": "unsubscribe", "requestId": "u1"})).await;
+        socket.assert_receive_json(&json!({"type": "ack", "requestId": "u1"})).await;
+        server.put("/collection/c2/contents?id=r5").add_header("X-User-Id", "alice").await.assert_status_ok();
+        socket.send_json(&json!({"type": "subscribe", "requestId": "s3", "collectionId": "c1"})).await;
+        socket.assert_receive_json(&json!({"type": "ack", "requestId": "s3"})).await;
+    }
+
+    #[test]
+    fn test_parse_commands() {
+        let parsed:CommandMessage = serde_json::from_str(r#"{"type": "subscribe", "requestId": "1"}"#).unwrap();
+        assert!(matches!(parsed.command, Command::Subscribe{ collection_id: None }));
+
+        let parsed:Result<CommandMessage, _> = serde_json::from_str(r#"{"type": "add", "requestId": "2", "collectionId": "c1"}"#);
+        assert!(parsed.is_err());
+    }
+}
@@ -0,0 +1,236 @@
+use std::{collections::{BTreeMap, HashMap, VecDeque}, sync::Arc};
+use axum::{body::{self, Body}, extract::{MatchedPath, Query, Request}, http::{HeaderMap, Method}, middleware::Next, response::{IntoResponse, Response}, Extension};
+use serde::Serialize;
+use time::OffsetDateTime;
+use tokio::sync::Mutex;
+use crate::{config::{is_admin_path, MAX_BODY_BYTES}, handlers::errors::ApiError, stubs::StubRequest};
+
+pub const DEFAULT_JOURNAL_SIZE:usize = 1000;
+
+/// Which route or stub handled a request. Put on the response by whatever handled it, for the journal to pick up.
+#[derive(Debug, Clone)]
+pub struct MatchedRoute(pub String);
+
+/// One request as the mock saw it, and what it answered
+#[derive(Serialize, Debug, Clone)]
+pub struct JournalEntry {
+    /// Counts up from 1 over the life of the server, so clients can tell which requests they've already seen
+    pub id: u64,
+    #[serde(with="time::serde::rfc3339")]
+    pub timestamp: OffsetDateTime,
+    pub method: String,
+    /// Just the path and query string
+    pub uri: String,
+    /// Repeated headers are joined with ", "
+    pub headers: BTreeMap<String, String>,
+    /// Not valid UTF-8 sequences are replaced
+    pub body: String,
+    #[serde(rename="matchedRoute", skip_serializing_if="Option::is_none")]
+    pub matched_route: Option<String>,
+    pub status: u16,
+    #[serde(skip)]
+    raw_method: Method,
+    #[serde(skip)]
+    raw_headers: HeaderMap,
+}
+
+impl JournalEntry {
+    pub fn path(&self) -> &str {
+        self.uri.split('?').next().unwrap_or("")
+    }
+
+    /// Whether the request fits the same kind of pattern that stubs use
+    pub fn matches(&self, pattern:&StubRequest) -> bool {
+        let query = self.uri.parse().ok()
+            .and_then(|uri| Query::<HashMap<String, String>>::try_from_uri(&uri).ok())
+            .map(|Query(q)| q)
+            .unwrap_or_default();
+        pattern.matches(&self.raw_method, self.path(), &query, &self.raw_headers, &self.body)
+    }
+}
+
+/// The journal has a lock of its own, so that recording a request doesn't hold up handlers waiting on the data
+pub type SharedJournal = Arc<Mutex<Journal>>;
+
+/// The most recent requests, oldest first. Once `capacity` is reached the oldest are dropped.
+#[derive(Debug)]
+pub struct Journal {
+    entries: VecDeque<JournalEntry>,
+    capacity: usize,
+    next_id: u64,
+}
+
+impl Journal {
+    pub fn new(capacity:usize) -> Journal {
+        Journal{
+            entries: VecDeque::new(),
+            capacity,
+            next_id: 1,
+        }
+    }
+
+    pub fn shared(capacity:usize) -> SharedJournal {
+        Arc::new(Mutex::new(Journal::new(capacity)))
+    }
+
+    /// 0 when the journal is turned off
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    fn record(&mut self, mut entry:JournalEntry) {
+        if self.capacity==0 {
+            return
+        }
+
+        entry.id = self.next_id;
+        self.next_id += 1;
+        while self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    pub fn entries(&self) -> impl DoubleEndedIterator<Item=&JournalEntry> {
+        self.entries.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+impl Default for Journal {
+    fn default() -> Journal {
+        Journal::new(DEFAULT_JOURNAL_SIZE)
+    }
+}
+
+/// Route layer that tells the journal which route pattern handled the request
+pub async fn tag_matched_route(
+    request: Request,
+    next: Next
+) -> Response {
+    let route = request.extensions().get::<MatchedPath>().map(|matched| matched.as_str().to_owned());
+    let mut response = next.run(request).await;
+    if let Some(route) = route {
+        response.extensions_mut().insert(MatchedRoute(route));
+    }
+    response
+}
+
+/// Records every request outside the admin API, along with the status it got. Does nothing if the journal is off.
+pub async fn record_requests(
+    Extension(journal): Extension<SharedJournal>,
+    request: Request,
+    next: Next
+) -> Response {
+    if is_admin_path(request.uri().path()) || journal.lock().await.capacity()==0 {
+        return next.run(request).await
+    }
+
+    let timestamp = OffsetDateTime::now_utc();
+    let (parts, body) = request.into_parts();
+    let Ok(body) = body::to_bytes(body, MAX_BODY_BYTES).await else {
+        return ApiError::BadRequest(format!("request bodies must be under {} bytes", MAX_BODY_BYTES)).into_response()
+    };
+
+    let mut headers:BTreeMap<String, String> = BTreeMap::new();
+    for (name, value) in parts.headers.iter() {
+        let value = String::from_utf8_lossy(value.as_bytes());
+        headers.entry(name.to_string())
+            .and_modify(|existing| { existing.push_str(", "); existing.push_str(&value); })
+            .or_insert_with(|| value.into_owned());
+    }
+
+    let mut entry = JournalEntry{
+        id: 0,
+        timestamp,
+        method: parts.method.to_string(),
+        uri: parts.uri.path_and_query().map(|pq| pq.to_string()).unwrap_or_else(|| parts.uri.path().to_owned()),
+        headers,
+        body: String::from_utf8_lossy(&body).into_owned(),
+        matched_route: None,
+        status: 0,
+        raw_method: parts.method.clone(),
+        raw_headers: parts.headers.clone(),
+    };
+
+    let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+
+    entry.status = response.status().as_u16();
+    entry.matched_route = response.extensions().get::<MatchedRoute>().map(|route| route.0.to_owned());
+    journal.lock().await.record(entry);
+
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use axum::{middleware, routing::{delete, put}, Router};
+    use axum_test::TestServer;
+    use crate::stubs::ValueMatcher;
+    use super::*;
+
+    #[tokio::test]
+    async fn test_record_requests() {
+        let journal = Journal::shared(2);
+
+        let fake_app = Router::new()
+            .route("/collection/{collection_id}/contents", delete(|| async { axum::http::StatusCode::NO_CONTENT }))
+            .route_layer(middleware::from_fn(tag_matched_route))
+            .layer(middleware::from_fn(record_requests))
+            .layer(Extension(journal.clone()));
+
+        let fake_server = TestServer::new(fake_app).unwrap();
+
+        fake_server.get("/nowhere").await;
+        fake_server.delete("/collection/saved/contents?dryRun=1")
+            .add_header("X-User-Id", "alice")
+            .json(&serde_json::json!({"ids": ["r1", "r2"]}))
+            .await;
+        fake_server.delete("/collection/saved/contents").await;
+
+        let guarded_journal = journal.lock().await;
+        let entries:Vec<&JournalEntry> = guarded_journal.entries().collect();
+        assert_eq!(entries.len(), 2, "the oldest request should have been dropped");
+
+        assert_eq!(entries[0].id, 2);
+        assert_eq!(entries[0].method, "DELETE");
+        assert_eq!(entries[0].uri, "/collection/saved/contents?dryRun=1");
+        assert_eq!(entries[0].headers["x-user-id"], "alice");
+        assert_eq!(entries[0].body, r#"{"ids":["r1","r2"]}"#);
+        assert_eq!(entries[0].matched_route.as_deref(), Some("/collection/{collection_id}/contents"));
+        assert_eq!(entries[0].status, 204);
+
+        let pattern = StubRequest{
+            method: Some("delete".into()),
+            path: "/collection/saved/contents".into(),
+            query: [("dryRun".to_string(), ValueMatcher::EqualTo("1".into()))].into(),
+            headers: HashMap::new(),
+            body: Some(ValueMatcher::EqualToJson(serde_json::json!({"ids": ["r1", "r2"]}))),
+        };
+        assert!(entries[0].matches(&pattern));
+        assert!(!entries[1].matches(&pattern));
+    }
+
+    #[tokio::test]
+    async fn test_journal_off() {
+        let journal = Journal::shared(0);
+
+        let fake_app = Router::new()
+            .route("/upload", put(|body:Body| async move {
+                body::to_bytes(body, usize::MAX).await.map(|bytes| bytes.len().to_string()).unwrap_or_default()
+            }))
+            .layer(middleware::from_fn(record_requests))
+            .layer(Extension(journal.clone()));
+
+        let fake_server = TestServer::new(fake_app).unwrap();
+
+        let big = vec![b'x'; MAX_BODY_BYTES + 1];
+        let response = fake_server.put("/upload").bytes(big.into()).await;
+        response.assert_status_ok();
+        assert_eq!(response.text(), (MAX_BODY_BYTES + 1).to_string(), "bodies shouldn't be limited when nothing is recorded");
+        assert_eq!(journal.lock().await.entries().count(), 0);
+    }
+}
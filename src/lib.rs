@@ -0,0 +1,32 @@
+//! A mock of the recipe persistence endpoints. As well as the `mock-recipe-persistence-endpoints` binary,
+//! it can be run inside a Rust test harness:
+//!
+//! ```no_run
+//! use mock_recipe_persistence_endpoints::{fixture::Environment, MockServer};
+//!
+//! # async fn example() -> std::io::Result<()> {
+//! let server = MockServer::builder()
+//!     .env(Environment::CODE)
+//!     .max_page_size(50)
+//!     .start()
+//!     .await?;
+//!
+//! let collections = reqwest::get(format!("{}/collection", server.url())).await;
+//! // The server stops when `server` is dropped
+//! # Ok(())
+//! # }
+//! ```
+pub mod client;
+pub mod config;
+pub mod contract;
+pub mod events;
+pub mod faults;
+pub mod fixture;
+pub mod handlers;
+pub mod journal;
+pub mod persistence;
+pub mod proxy;
+mod server;
+pub mod stubs;
+
+pub use server::{MockServer, MockServerBuilder};
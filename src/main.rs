@@ -1,13 +1,18 @@
-use std::{error::Error, sync::Arc};
-use handlers::SharedState;
-use tokio::sync::RwLock;
-use axum::{extract::Request, middleware::{self, Next}, response::Response, routing::{get, put, delete}, Extension, Router};
-use clap::Parser;
-use fixture::MutableStaticData;
-use log;
+use std::{error::Error, path::PathBuf, time::Duration};
+use clap::{Parser, Subcommand};
+use mock_recipe_persistence_endpoints::{
+    contract::Contract,
+    events,
+    faults::FaultConfig,
+    fixture::{self, loader::FixtureFile, RecentlyViewedPolicy},
+    handlers::{self, errors::ErrorFormat},
+    journal,
+    persistence,
+    proxy::{self, Upstream},
+    stubs::StubFile,
+    MockServer,
+};
 use tokio::net::TcpListener;
-mod handlers;
-mod fixture;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about=None)]
@@ -19,32 +24,119 @@ struct Args {
     /// Return IDs useful in this data environment
     #[arg(short, long, default_value_t=fixture::Environment::PROD)]
     env: fixture::Environment,
+
+    /// Load collections from this JSON, TOML or YAML file instead of the built-in data
+    #[arg(short, long)]
+    fixture: Option<PathBuf>,
+
+    /// Save the state to this file whenever it changes, and restore it from here on startup
+    #[arg(short, long)]
+    state_file: Option<PathBuf>,
+
+    /// Wait this long after a change before saving the state file, so that a burst of changes is written out together
+    #[arg(long, default_value_t=0)]
+    state_debounce_ms: u64,
+
+    /// Maximum number of recipes kept in a recently-viewed collection; the oldest views are dropped first
+    #[arg(long, default_value_t=20)]
+    recently_viewed_limit: usize,
+
+    /// Drop recently-viewed entries that are older than this many seconds
+    #[arg(long)]
+    recently_viewed_max_age: Option<u64>,
+
+    /// The largest `limit` a client may ask for when paging through a collection's contents
+    #[arg(long, default_value_t=fixture::DEFAULT_MAX_PAGE_SIZE)]
+    max_page_size: usize,
+
+    /// How many changes to each collection are kept for /collection/{id}/changes. Asking for changes from before then gets 410 Gone.
+    #[arg(long, default_value_t=fixture::history::DEFAULT_HISTORY_LENGTH)]
+    history_length: usize,
+
+    /// How many collection events are kept for event streams that reconnect with Last-Event-ID
+    #[arg(long, default_value_t=events::DEFAULT_EVENT_BUFFER)]
+    event_buffer: usize,
+
+    /// Send errors as `{"status": ..., "detail": ...}` rather than application/problem+json, for older clients
+    #[arg(long)]
+    legacy_errors: bool,
+
+    /// Inject the faults described in this JSON, TOML or YAML file. They can also be changed at runtime through /__admin/faults.
+    #[arg(long)]
+    faults: Option<PathBuf>,
+
+    /// Let each request force its own failure with X-Mock-Status, X-Mock-Delay-Ms and X-Mock-Fail-After-Bytes headers
+    #[arg(long)]
+    mock_headers: bool,
+
+    /// How many of the most recent requests to keep for /__admin/requests; 0 turns the journal off
+    #[arg(long, default_value_t=journal::DEFAULT_JOURNAL_SIZE)]
+    journal_size: usize,
+
+    /// Start with the stubs in this JSON file, e.g. ones saved with --record-to
+    #[arg(long)]
+    stubs: Option<PathBuf>,
+
+    /// Forward requests the mock can't answer to this real server (http:// only), recording the responses
+    #[arg(long)]
+    proxy_to: Option<String>,
+
+    /// Forward every request outside /__admin to the --proxy-to server, not just the ones the mock can't answer
+    #[arg(long, requires="proxy_to")]
+    proxy_all: bool,
+
+    /// On shutdown, save the recorded responses to this JSON file as stubs, for --stubs to replay
+    #[arg(long, requires="proxy_to")]
+    record_to: Option<PathBuf>,
+
+    /// On shutdown, save the recorded collections to this JSON, TOML or YAML file as a fixture, for --fixture to replay
+    #[arg(long, requires="proxy_to")]
+    record_fixture_to: Option<PathBuf>,
+
+    /// Check requests and responses against this OpenAPI 3.x contract (JSON or YAML), logging anything that doesn't fit
+    #[arg(long)]
+    contract: Option<PathBuf>,
+
+    /// Answer 400 to requests that break the --contract, and 500 in place of responses that do
+    #[arg(long, requires="contract")]
+    contract_strict: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Write the OpenAPI document for the collection endpoints and exit, rather than running the server
+    DumpOpenapi {
+        /// Write to this file rather than stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
 }
 
-async fn logging_middleware(
-    request: Request,
-    next: Next
-) -> Response {
-    let method = &request.method().to_owned();
-    let uri = &request.uri().to_string();
-    let headers = &request.headers().to_owned();
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.expect("could not install ctrl-c handler");
+    };
 
-    let next_layer_response = next.run(request).await;
-    
-    log::info!("{} {} -> {}", method, uri, next_layer_response.status().as_u16());
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("could not install SIGTERM handler")
+            .recv()
+            .await;
+    };
 
-    let response_headers = next_layer_response.headers();
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
 
-    log::debug!("Request headers:");
-    for (k,v) in headers {
-        log::debug!("\t{}: {}", k, v.to_str().unwrap_or("invalid string"));
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
     }
 
-    log::debug!("Response headers:");
-    for (k,v) in response_headers {
-        log::debug!("\t{}: {}", k, v.to_str().unwrap_or("invalid string"));
-    }
-    next_layer_response
+    log::info!("Shutting down");
 }
 
 #[tokio::main]
@@ -53,27 +145,141 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     let args = Args::parse();
 
-    let server_state:SharedState = Arc::new(
-        RwLock::new(
-            MutableStaticData::new(&args.env)
-        )
-    );
-
-    let app = Router::new()
-        .route("/collection", get(handlers::get_user_collections))
-        .route("/collection/{collection_id}/contents", get(handlers::get_collection_content))
-        .route("/collection/{collection_id}/contents", put(handlers::put_to_collection))
-        .route("/collection/{collection_id}/contents", delete(handlers::delete_from_collection))
-        .fallback(handlers::generic404)
-        .layer(middleware::from_fn(logging_middleware))
-        .layer(Extension(server_state));
+    if let Some(Command::DumpOpenapi{ output }) = &args.command {
+        let document = handlers::openapi::document().to_pretty_json()?;
+        match output {
+            Some(path)=>std::fs::write(path, document)?,
+            None=>println!("{}", document),
+        }
+        return Ok( () )
+    }
+
+    let error_format = if args.legacy_errors { ErrorFormat::Legacy } else { ErrorFormat::Problem };
+
+    let mut builder = MockServer::builder()
+        .env(args.env.clone())
+        .recently_viewed(RecentlyViewedPolicy{
+            max_length: args.recently_viewed_limit,
+            max_age: args.recently_viewed_max_age.map(Duration::from_secs),
+        })
+        .max_page_size(args.max_page_size)
+        .history_length(args.history_length)
+        .event_buffer(args.event_buffer)
+        .error_format(error_format)
+        .mock_headers(args.mock_headers)
+        .journal_size(args.journal_size);
+
+    if let Some(path) = &args.fixture {
+        let fixture = FixtureFile::load(path).map_err(|e| {
+            log::error!("{}", e);
+            e
+        })?;
+        log::info!("Loaded {} collections from {}", fixture.collections.len(), path.display());
+        builder = builder.fixture(fixture);
+    }
+
+    if let Some(path) = &args.faults {
+        let faults = FaultConfig::load(path).map_err(|e| {
+            log::error!("{}", e);
+            e
+        })?;
+        log::info!("Loaded {} fault rules from {}", faults.rules.len(), path.display());
+        builder = builder.faults(faults);
+    }
+
+    if let Some(path) = &args.stubs {
+        let stubs = StubFile::load(path).map_err(|e| {
+            log::error!("{}", e);
+            e
+        })?.stubs;
+        log::info!("Loaded {} stubs from {}", stubs.len(), path.display());
+        builder = builder.stubs(stubs);
+    }
+
+    if let Some(base) = &args.proxy_to {
+        let upstream = Upstream::new(base, args.proxy_all).map_err(|e| {
+            log::error!("{}", e);
+            e
+        })?;
+        log::info!("Forwarding {} requests to {}", if args.proxy_all { "all" } else { "unhandled" }, base);
+        builder = builder.proxy(upstream);
+    }
+
+    if let Some(path) = &args.contract {
+        let contract = Contract::load(path).map_err(|e| {
+            log::error!("{}", e);
+            e
+        })?;
+        log::info!("Checking requests and responses against the contract in {}", path.display());
+        builder = builder.contract(contract, args.contract_strict);
+    }
+
+    let (app, server_state) = builder.build();
+
+    if let Some(path) = &args.state_file {
+        match persistence::load_state(path).await {
+            Ok(Some(saved))=>{
+                log::info!("Restored state for {} users and {} snapshots from {}", saved.users.len(), saved.snapshots.len(), path.display());
+                let mut guarded_data = server_state.write().await;
+                log::info!("Serving IDs for {} environment, as when the state was saved", saved.env);
+                guarded_data.env = saved.env;
+                if args.fixture.is_some() {
+                    log::warn!("Using the fixture given with --fixture for new users, rather than the one saved in {}", path.display());
+                } else {
+                    guarded_data.template = saved.template;
+                }
+                guarded_data.users = saved.users;
+                guarded_data.generation = saved.generation;
+                guarded_data.snapshots = saved.snapshots;
+            },
+            Ok(None)=>log::info!("No state found at {}, starting from the fixture", path.display()),
+            Err(e)=>{
+                log::error!("Could not restore state from {}: {}", path.display(), e);
+                return Err(e.into());
+            }
+        }
+    }
 
     let bind_addr = format!("0.0.0.0:{}", args.port);
 
     let listener = TcpListener::bind(bind_addr).await?;
     log::info!("Listening for connections on port {}. Serving IDs for {:?} environment", args.port, &args.env);
 
-    axum::serve(listener, app).await?;
+    if let Some(path) = &args.state_file {
+        tokio::spawn(persistence::save_on_change(server_state.clone(), path.to_owned(), Duration::from_millis(args.state_debounce_ms)));
+    }
+
+    let events = server_state.read().await.events.clone();
+    axum::serve(listener, app)
+        .with_graceful_shutdown(async move {
+            shutdown_signal().await;
+            // Event streams never finish by themselves, so would hold up the shutdown
+            events.close();
+        })
+        .await?;
+
+    if let Some(path) = &args.state_file {
+        persistence::save_state(&server_state, path).await?;
+        log::info!("Saved state to {}", path.display());
+    }
+
+    if let Some(path) = &args.record_to {
+        let recordings = StubFile{
+            stubs: server_state.read().await.recordings.clone(),
+        };
+        recordings.save(path)?;
+        log::info!("Saved {} recorded responses to {}", recordings.stubs.len(), path.display());
+    }
+
+    if let Some(path) = &args.record_fixture_to {
+        match proxy::recorded_fixture(&server_state.read().await.recordings) {
+            Some(fixture)=>{
+                fixture.save(path)?;
+                log::info!("Saved {} recorded collections to {}", fixture.collections.len(), path.display());
+            },
+            None=>log::warn!("No collection listing was recorded, so there is no fixture to save to {}", path.display()),
+        }
+    }
 
     log::info!("Exiting");
 
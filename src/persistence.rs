@@ -0,0 +1,140 @@
+use std::{collections::HashMap, io::{self, Write}, path::{Path, PathBuf}, time::Duration};
+use serde::{Deserialize, Serialize};
+use tokio::{fs, sync::Mutex};
+use crate::{fixture::{loader::FixtureFile, Environment, Generation, Snapshot, UserData}, handlers::SharedState};
+
+/// What gets written to the --state-file
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PersistedState {
+    /// The environment and template new users get, which a reset or restore may have changed since startup
+    pub env: Environment,
+    pub template: FixtureFile,
+    pub users: HashMap<String, UserData>,
+    /// Where versions carry on from after the next reset or restore, so that old ETags stay stale across restarts
+    pub generation: Generation,
+    /// Named snapshots taken through the admin API
+    pub snapshots: HashMap<String, Snapshot>,
+}
+
+/// Held for the whole of a save, so that saves can't interleave on the temporary file or finish out of order
+static SAVING:Mutex<()> = Mutex::const_new(());
+
+/// Reads a previously saved state. Returns Ok(None) if there is nothing at `path` yet.
+pub async fn load_state(path:&Path) -> Result<Option<PersistedState>, io::Error> {
+    match fs::read_to_string(path).await {
+        Ok(content)=>{
+            let state = serde_json::from_str(&content)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{} is not a valid state file: {}", path.display(), e)))?;
+            Ok(Some(state))
+        },
+        Err(e) if e.kind()==io::ErrorKind::NotFound=>Ok(None),
+        Err(e)=>Err(e),
+    }
+}
+
+/// Writes the current state to `path`. The content goes to a temporary file alongside it which is then renamed
+/// over the target, so a crash part-way through never leaves a truncated state file behind.
+pub async fn save_state(state:&SharedState, path:&Path) -> Result<(), io::Error> {
+    let _saving = SAVING.lock().await;
+    let content = {
+        let guarded_data = state.read().await;
+        serde_json::to_vec_pretty(&PersistedState{
+            env: guarded_data.env.clone(),
+            template: guarded_data.template.clone(),
+            users: guarded_data.users.clone(),
+            generation: guarded_data.generation,
+            snapshots: guarded_data.snapshots.clone(),
+        })?
+    };
+
+    let mut temp_name = path.as_os_str().to_owned();
+    temp_name.push(".tmp");
+    let temp_path = PathBuf::from(temp_name);
+    let target_path = path.to_owned();
+
+    tokio::task::spawn_blocking(move || {
+        let mut file = std::fs::File::create(&temp_path)?;
+        file.write_all(&content)?;
+        file.sync_all()?;
+        std::fs::rename(&temp_path, &target_path)
+    }).await?
+}
+
+/// Saves the state every time it changes, for as long as the server runs.
+/// If `debounce` is non-zero the save happens that long after a change, picking up anything else changed in the meantime.
+pub async fn save_on_change(state:SharedState, path:PathBuf, debounce:Duration) {
+    let changes = state.read().await.changes.clone();
+
+    loop {
+        changes.notified().await;
+        if !debounce.is_zero() {
+            tokio::time::sleep(debounce).await;
+        }
+
+        match save_state(&state, &path).await {
+            Ok(_)=>log::debug!("Saved state to {}", path.display()),
+            Err(e)=>log::error!("Could not save state to {}: {}", path.display(), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use tokio::sync::RwLock;
+    use crate::fixture::{history::ContentChange, MutableStaticData, CODE_RECIPES_SAVED_SAMPLE};
+    use super::*;
+
+    #[tokio::test]
+    async fn test_save_and_load() {
+        let path = std::env::temp_dir().join(format!("{}-state.json", std::process::id()));
+
+        let mut data = MutableStaticData::new(&Environment::PROD);
+        data.reset(Some(&Environment::CODE));
+        data.user("alice").collections.values_mut().for_each(|c| {
+            c.content.insert("extra");
+            c.touch(ContentChange{ added: vec!["extra".into()], removed: Vec::new() }, 10);
+        });
+        let snapshot = data.snapshot();
+        data.snapshots.insert("checkpoint".into(), snapshot);
+        let state:SharedState = Arc::new(RwLock::new(data));
+
+        save_state(&state, &path).await.unwrap();
+        let loaded = load_state(&path).await.unwrap().unwrap();
+        fs::remove_file(&path).await.unwrap();
+
+        assert_eq!(loaded.env.to_string(), "code");
+        assert_eq!(loaded.template.collections[0].recipes, CODE_RECIPES_SAVED_SAMPLE);
+        assert_eq!(loaded.users.len(), 1);
+        let alice = &loaded.users["alice"];
+        assert_eq!(alice.collections.len(), 3);
+        assert!(alice.collections.values().all(|c| c.version==2 && c.content.iter().last().map(|s| s.as_str())==Some("extra")));
+        assert_eq!(loaded.snapshots["checkpoint"].users.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_saves() {
+        let path = std::env::temp_dir().join(format!("{}-concurrent-state.json", std::process::id()));
+        let state:SharedState = Arc::new(RwLock::new(MutableStaticData::new(&Environment::PROD)));
+        state.write().await.user("alice");
+
+        let saves:Vec<_> = (0..8).map(|_| tokio::spawn({
+            let (state, path) = (state.clone(), path.clone());
+            async move { save_state(&state, &path).await }
+        })).collect();
+        for save in saves {
+            save.await.unwrap().unwrap();
+        }
+
+        let loaded = load_state(&path).await.unwrap().unwrap();
+        fs::remove_file(&path).await.unwrap();
+        assert_eq!(loaded.users.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_load_missing() {
+        let path = std::env::temp_dir().join(format!("{}-does-not-exist.json", std::process::id()));
+
+        assert!(load_state(&path).await.unwrap().is_none());
+    }
+}
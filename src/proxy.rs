@@ -0,0 +1,329 @@
+use std::collections::HashMap;
+use axum::{body::{self, Body, Bytes}, extract::{Query, Request, State}, http::{header, request::Parts, HeaderMap, HeaderName, StatusCode}, middleware::Next, response::{IntoResponse, Response}, Extension};
+use serde::Deserialize;
+use serde_json::Value;
+use uuid::Uuid;
+use crate::{config::{is_admin_path, MAX_BODY_BYTES}, events, fixture::{loader::{FixtureCollection, FixtureFile}, models::CollectionsResponse}, handlers::{errors::ApiError, identity::USER_ID_HEADER, SharedState}, journal::MatchedRoute, stubs::{Stub, StubRequest, StubResponse, ValueMatcher}};
+
+/// Headers that only make sense for a single connection, so are never passed through
+const HOP_BY_HOP_HEADERS:[&str; 9] = ["connection", "keep-alive", "proxy-connection", "te", "trailer", "transfer-encoding", "upgrade", "host", "content-length"];
+
+/// The request headers that tell users apart, and so are part of what a recording matches on
+const IDENTITY_HEADERS:[&str; 2] = [USER_ID_HEADER, "authorization"];
+
+/// Response headers worth keeping in a recording. The rest (dates, server names, ...) would only be noise.
+const RECORDED_HEADERS:[&str; 6] = ["content-type", "etag", "last-modified", "location", "link", "cache-control"];
+
+/// The real server that requests are passed on to
+#[derive(Debug, Clone)]
+pub struct Upstream {
+    /// Without a trailing slash, so the request path can be appended as-is
+    base: String,
+    /// Forward every request, rather than only the ones the mock can't answer itself
+    forward_all: bool,
+    client: reqwest::Client,
+}
+
+/// Just enough of a collection's contents to fill in a fixture
+#[derive(Deserialize, Debug)]
+struct RecordedContents {
+    content: Vec<String>,
+}
+
+impl Upstream {
+    pub fn new(base:&str, forward_all:bool) -> Result<Upstream, String> {
+        let url = reqwest::Url::parse(base).map_err(|e| format!("upstream {} is not a valid URL: {}", base, e))?;
+        if url.scheme()!="http" {
+            return Err(format!("upstream {} must be a plain http:// URL", base))
+        }
+
+        Ok(Upstream{
+            base: base.trim_end_matches('/').to_owned(),
+            forward_all,
+            client: reqwest::Client::new(),
+        })
+    }
+
+    async fn forward(&self, parts:&Parts, body:Bytes) -> Result<reqwest::Response, reqwest::Error> {
+        let path_and_query = parts.uri.path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
+
+        let mut headers = parts.headers.clone();
+        for name in HOP_BY_HOP_HEADERS {
+            headers.remove(name);
+        }
+
+        self.client.request(parts.method.clone(), format!("{}{}", self.base, path_and_query))
+            .headers(headers)
+            .body(body)
+            .send()
+            .await
+    }
+}
+
+/// Event streams and WebSockets never end, so can't be buffered up to forward or record
+fn is_streaming(headers:&HeaderMap) -> bool {
+    let accepts_events = headers.get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("text/event-stream"));
+    accepts_events || headers.contains_key(header::UPGRADE)
+}
+
+/// Passes requests on to the upstream server and records what comes back as stubs. Unless `forward_all`
+/// is set, the mock gets the first go at a request and it's only forwarded if nothing here handled it,
+/// i.e. no route or stub matches its path and method. Streaming requests are always served locally.
+pub async fn forward_requests(
+    State(upstream): State<Upstream>,
+    Extension(shared_state): Extension<SharedState>,
+    request: Request,
+    next: Next
+) -> Response {
+    if is_admin_path(request.uri().path()) || is_streaming(request.headers()) {
+        return next.run(request).await
+    }
+
+    let (parts, body) = request.into_parts();
+    let Ok(body) = body::to_bytes(body, MAX_BODY_BYTES).await else {
+        return ApiError::BadRequest(format!("request bodies must be under {} bytes", MAX_BODY_BYTES)).into_response()
+    };
+
+    if !upstream.forward_all {
+        let response = next.run(Request::from_parts(parts.clone(), Body::from(body.clone()))).await;
+        if !matches!(response.extensions().get::<ApiError>(), Some(ApiError::RouteNotFound | ApiError::MethodNotAllowed)) {
+            return response
+        }
+    }
+
+    let upstream_response = match upstream.forward(&parts, body.clone()).await {
+        Ok(upstream_response)=>upstream_response,
+        Err(e)=>{
+            log::warn!("Could not forward {} {}: {}", parts.method, parts.uri, e);
+            return ApiError::UpstreamFailed(e.to_string()).into_response()
+        }
+    };
+
+    let status = upstream_response.status();
+    let mut headers = upstream_response.headers().clone();
+    if events::is_event_stream(&headers) {
+        log::warn!("Upstream answered {} {} with an event stream, which can't be forwarded", parts.method, parts.uri);
+        return ApiError::UpstreamFailed("event streams can't be forwarded".into()).into_response()
+    }
+    let upstream_body = match upstream_response.bytes().await {
+        Ok(upstream_body)=>upstream_body,
+        Err(e)=>{
+            log::warn!("Upstream response to {} {} was cut short: {}", parts.method, parts.uri, e);
+            return ApiError::UpstreamFailed(e.to_string()).into_response()
+        }
+    };
+    for name in HOP_BY_HOP_HEADERS {
+        headers.remove(name);
+    }
+
+    log::debug!("{} {} forwarded upstream -> {}", parts.method, parts.uri, status.as_u16());
+    shared_state.write().await.recordings.push(recording(&parts, &body, status, &headers, &upstream_body));
+
+    let mut response = Response::new(Body::from(upstream_body));
+    *response.status_mut() = status;
+    *response.headers_mut() = headers;
+    response.extensions_mut().insert(MatchedRoute("proxy".into()));
+    response
+}
+
+/// A stub that answers the same request the same way the upstream server did
+fn recording(parts:&Parts, body:&[u8], status:StatusCode, headers:&HeaderMap, upstream_body:&[u8]) -> Stub {
+    let query = Query::<HashMap<String, String>>::try_from_uri(&parts.uri).map(|Query(q)| q).unwrap_or_default();
+
+    let request_body = String::from_utf8_lossy(body);
+    let request_body = if request_body.is_empty() {
+        None
+    } else {
+        match serde_json::from_str::<Value>(&request_body) {
+            Ok(json)=>Some(ValueMatcher::EqualToJson(json)),
+            Err(_)=>Some(ValueMatcher::EqualTo(request_body.into_owned())),
+        }
+    };
+
+    let is_json = headers.get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("json"));
+    let json_body = if is_json { serde_json::from_slice::<Value>(upstream_body).ok() } else { None };
+    let response_body = String::from_utf8_lossy(upstream_body);
+
+    Stub{
+        id: format!("recorded-{}", Uuid::new_v4()),
+        priority: crate::stubs::DEFAULT_PRIORITY,
+        request: StubRequest{
+            method: Some(parts.method.to_string()),
+            path: parts.uri.path().to_owned(),
+            query: query.into_iter().map(|(name, value)| (name, ValueMatcher::EqualTo(value))).collect(),
+            headers: IDENTITY_HEADERS.iter()
+                .filter_map(|name| parts.headers.get(*name).and_then(|v| v.to_str().ok()).map(|v| (name.to_string(), ValueMatcher::EqualTo(v.to_owned()))))
+                .collect(),
+            body: request_body,
+        },
+        response: StubResponse{
+            status: status.as_u16(),
+            headers: RECORDED_HEADERS.iter()
+                .filter_map(|name| headers.get(HeaderName::from_static(name)).and_then(|v| v.to_str().ok()).map(|v| (name.to_string(), v.to_owned())))
+                .collect(),
+            body: if json_body.is_some() || response_body.is_empty() { None } else { Some(response_body.into_owned()) },
+            json_body,
+            delay_ms: None,
+        },
+    }
+}
+
+/// Turns recorded collection listings and contents into a fixture, so the real routes can replay them.
+/// Uses the first successful `GET /collection` recorded, along with the contents pages recorded for
+/// the same user; recipes from several pages are joined in the order they were recorded.
+pub fn recorded_fixture(recordings:&[Stub]) -> Option<FixtureFile> {
+    let successful_get = |stub:&&Stub, path:&str| stub.request.method.as_deref()==Some("GET") && stub.request.path==path && stub.response.status==200;
+
+    let (listing_stub, listing) = recordings.iter()
+        .filter(|stub| successful_get(stub, "/collection"))
+        .find_map(|stub| {
+            let listing:CollectionsResponse = serde_json::from_value(stub.response.json_body.clone()?).ok()?;
+            Some((stub, listing))
+        })?;
+
+    let collections = listing.collections.into_iter().map(|collection| {
+        let contents_path = format!("/collection/{}/contents", collection.id);
+        let mut recipes:Vec<String> = Vec::new();
+        let pages = recordings.iter()
+            .filter(|stub| successful_get(stub, &contents_path) && stub.request.headers==listing_stub.request.headers)
+            .filter_map(|stub| serde_json::from_value::<RecordedContents>(stub.response.json_body.clone()?).ok());
+        for page in pages {
+            for recipe_id in page.content {
+                if !recipes.contains(&recipe_id) {
+                    recipes.push(recipe_id);
+                }
+            }
+        }
+
+        FixtureCollection{
+            id: collection.id,
+            kind: collection.collection_type,
+            name: collection.name,
+            recipes,
+            last_modified: Some(collection.last_modified),
+        }
+    }).collect();
+
+    Some(FixtureFile{
+        collections,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use axum::{middleware, routing::get, Json, Router};
+    use axum_test::TestServer;
+    use tokio::{net::TcpListener, sync::RwLock};
+    use crate::{fixture::{models::CollectionKind, Environment, MutableStaticData}, stubs::{serve_stub, serve_stub_for_method}};
+    use super::*;
+
+    /// Starts a stand-in for the real server, returning its base URL
+    async fn fake_upstream() -> String {
+        let upstream_app = Router::new()
+            .route("/collection", get(|| async {
+                Json(serde_json::json!({"collections": [
+                    {"id": "c1", "collectionType": "saved", "lastModified": "2025-01-20T10:00:00Z"},
+                    {"id": "c2", "collectionType": "userCreated", "name": "Puddings", "lastModified": "2025-01-21T10:00:00Z"}
+                ]}))
+            }))
+            .route("/collection/{collection_id}/contents", get(|| async {
+                Json(serde_json::json!({"content": ["r1", "r2"], "contentType": "recipe", "total": 2, "hasMore": false}))
+            }).post(|| async { "upstream post" }))
+            .route("/recipe/{recipe_id}/notes", get(|| async {
+                ([(header::ETAG, "\"v1\"")], "upstream notes")
+            }));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, upstream_app).await });
+        base
+    }
+
+    fn fake_server(upstream:Upstream, state:SharedState) -> TestServer {
+        let fake_app = Router::new()
+            .route("/collection/{collection_id}/contents", get(|| async { "local contents" }))
+            .method_not_allowed_fallback(serve_stub_for_method)
+            .fallback(serve_stub)
+            .layer(middleware::from_fn_with_state(upstream, forward_requests))
+            .layer(Extension(state));
+
+        TestServer::new(fake_app).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_forward_unhandled() {
+        let state:SharedState = Arc::new(RwLock::new(MutableStaticData::new(&Environment::PROD)));
+        let fake_server = fake_server(Upstream::new(&fake_upstream().await, false).unwrap(), state.clone());
+
+        fake_server.get("/collection/c1/contents").await.assert_text("local contents");
+        fake_server.post("/collection/c1/contents").await.assert_text("upstream post");
+
+        let forwarded = fake_server.get("/recipe/r1/notes?lang=en").add_header(USER_ID_HEADER, "alice").await;
+        forwarded.assert_status_ok();
+        forwarded.assert_text("upstream notes");
+        assert_eq!(forwarded.header(header::ETAG), "\"v1\"");
+
+        let guarded_data = state.read().await;
+        assert_eq!(guarded_data.recordings.len(), 2, "requests handled locally should not be recorded");
+        let recorded = &guarded_data.recordings[1];
+        assert_eq!(recorded.request.path, "/recipe/r1/notes");
+        assert_eq!(recorded.request.query["lang"], ValueMatcher::EqualTo("en".into()));
+        assert_eq!(recorded.request.headers[USER_ID_HEADER], ValueMatcher::EqualTo("alice".into()));
+        assert_eq!(recorded.response.body.as_deref(), Some("upstream notes"));
+        assert_eq!(recorded.response.headers["etag"], "\"v1\"");
+    }
+
+    #[tokio::test]
+    async fn test_forward_all_and_derive_fixture() {
+        let state:SharedState = Arc::new(RwLock::new(MutableStaticData::new(&Environment::PROD)));
+        let fake_server = fake_server(Upstream::new(&fake_upstream().await, true).unwrap(), state.clone());
+
+        fake_server.get("/collection").await.assert_status_ok();
+        fake_server.get("/collection/c1/contents")
+            .await
+            .assert_json(&serde_json::json!({"content": ["r1", "r2"], "contentType": "recipe", "total": 2, "hasMore": false}));
+
+        let fixture = recorded_fixture(&state.read().await.recordings).unwrap();
+        assert!(fixture.validate().is_empty());
+        assert_eq!(fixture.collections.len(), 2);
+        assert_eq!(fixture.collections[0].recipes, vec!["r1", "r2"]);
+        assert_eq!(fixture.collections[1].kind, CollectionKind::UserCreated);
+        assert_eq!(fixture.collections[1].name.as_deref(), Some("Puddings"));
+        assert!(fixture.collections[1].recipes.is_empty(), "c2's contents were never fetched");
+    }
+
+    #[tokio::test]
+    async fn test_streams_served_locally() {
+        let state:SharedState = Arc::new(RwLock::new(MutableStaticData::new(&Environment::PROD)));
+        let fake_server = fake_server(Upstream::new(&fake_upstream().await, true).unwrap(), state.clone());
+
+        fake_server.get("/collection/c1/contents")
+            .add_header(header::ACCEPT, "text/event-stream")
+            .await
+            .assert_text("local contents");
+        fake_server.get("/collection/c1/contents")
+            .add_header(header::CONNECTION, "upgrade")
+            .add_header(header::UPGRADE, "websocket")
+            .await
+            .assert_text("local contents");
+        assert!(state.read().await.recordings.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_upstream_unreachable() {
+        let unused = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", unused.local_addr().unwrap());
+        drop(unused);
+
+        let state:SharedState = Arc::new(RwLock::new(MutableStaticData::new(&Environment::PROD)));
+        let fake_server = fake_server(Upstream::new(&base, false).unwrap(), state);
+
+        fake_server.get("/recipe/r1/notes").await.assert_status(StatusCode::BAD_GATEWAY);
+        assert!(Upstream::new("https://example.com", false).is_err());
+    }
+}
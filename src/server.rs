@@ -0,0 +1,373 @@
+use std::{io, net::{Ipv4Addr, SocketAddr}, sync::Arc};
+use axum::{extract::Request, middleware::{self, Next}, response::Response, routing::{delete, get, patch, post, put}, Extension, Router};
+use tokio::{net::TcpListener, sync::{oneshot, RwLock}, task::JoinHandle};
+use crate::{
+    contract::{self, Contract, ContractGuard},
+    events::{self, EventBus},
+    faults::{self, FaultConfig},
+    fixture::{self, loader::FixtureFile, Environment, MutableStaticData, RecentlyViewedPolicy},
+    handlers::{self, errors::ErrorFormat, SharedState},
+    journal::{self, Journal, SharedJournal},
+    proxy::{self, Upstream},
+    stubs::{self, Stub},
+};
+
+async fn logging_middleware(
+    request: Request,
+    next: Next
+) -> Response {
+    let method = &request.method().to_owned();
+    let uri = &request.uri().to_string();
+    let headers = &request.headers().to_owned();
+
+    let next_layer_response = next.run(request).await;
+
+    log::info!("{} {} -> {}", method, uri, next_layer_response.status().as_u16());
+
+    let response_headers = next_layer_response.headers();
+
+    log::debug!("Request headers:");
+    for (k,v) in headers {
+        log::debug!("\t{}: {}", k, v.to_str().unwrap_or("invalid string"));
+    }
+
+    log::debug!("Response headers:");
+    for (k,v) in response_headers {
+        log::debug!("\t{}: {}", k, v.to_str().unwrap_or("invalid string"));
+    }
+    next_layer_response
+}
+
+/// Sets up a mock, either as a `Router` to serve or test however you like, or as a server running in the background.
+/// Everything defaults to what the command line defaults to.
+pub struct MockServerBuilder {
+    env: Environment,
+    fixture: Option<FixtureFile>,
+    recently_viewed: RecentlyViewedPolicy,
+    max_page_size: usize,
+    history_length: usize,
+    event_buffer: usize,
+    error_format: ErrorFormat,
+    faults: FaultConfig,
+    mock_headers: bool,
+    journal_size: usize,
+    stubs: Vec<Stub>,
+    upstream: Option<Upstream>,
+    contract: Option<ContractGuard>,
+}
+
+impl MockServerBuilder {
+    /// Which environment's IDs to serve. Also picks the built-in fixture, unless `fixture` is given.
+    pub fn env(mut self, env:Environment) -> Self {
+        self.env = env;
+        self
+    }
+
+    /// Start every user off with these collections rather than the built-in ones
+    pub fn fixture(mut self, fixture:FixtureFile) -> Self {
+        self.fixture = Some(fixture);
+        self
+    }
+
+    pub fn recently_viewed(mut self, policy:RecentlyViewedPolicy) -> Self {
+        self.recently_viewed = policy;
+        self
+    }
+
+    /// The largest `limit` a client may ask for when paging; at least 1
+    pub fn max_page_size(mut self, max_page_size:usize) -> Self {
+        self.max_page_size = max_page_size.max(1);
+        self
+    }
+
+    /// How many changes to each collection /changes can report on
+    pub fn history_length(mut self, history_length:usize) -> Self {
+        self.history_length = history_length;
+        self
+    }
+
+    /// How many events are kept for streams that reconnect with Last-Event-ID
+    pub fn event_buffer(mut self, event_buffer:usize) -> Self {
+        self.event_buffer = event_buffer;
+        self
+    }
+
+    pub fn error_format(mut self, error_format:ErrorFormat) -> Self {
+        self.error_format = error_format;
+        self
+    }
+
+    /// Fault rules to apply. They aren't validated; probabilities outside [0,1] are clamped.
+    pub fn faults(mut self, faults:FaultConfig) -> Self {
+        self.faults = faults;
+        self
+    }
+
+    /// Let requests force their own failures with X-Mock-* headers
+    pub fn mock_headers(mut self, enabled:bool) -> Self {
+        self.mock_headers = enabled;
+        self
+    }
+
+    /// How many requests /__admin/requests remembers; 0 turns the journal off
+    pub fn journal_size(mut self, journal_size:usize) -> Self {
+        self.journal_size = journal_size;
+        self
+    }
+
+    pub fn stubs(mut self, stubs:Vec<Stub>) -> Self {
+        self.stubs = stubs;
+        self
+    }
+
+    /// Forward requests to a real server and record what it says
+    pub fn proxy(mut self, upstream:Upstream) -> Self {
+        self.upstream = Some(upstream);
+        self
+    }
+
+    /// Check traffic against an OpenAPI contract. In strict mode, traffic that breaks it is answered with an error.
+    pub fn contract(mut self, contract:Contract, strict:bool) -> Self {
+        self.contract = Some(ContractGuard{
+            contract: Arc::new(contract),
+            strict,
+            errors: ErrorFormat::default(),
+        });
+        self
+    }
+
+    /// The app, along with the state behind it
+    pub fn build(self) -> (Router, SharedState) {
+        let (app, state, _) = self.assemble();
+        (app, state)
+    }
+
+    fn assemble(self) -> (Router, SharedState, SharedJournal) {
+        let mut initial_data = match &self.fixture {
+            None=>MutableStaticData::new(&self.env),
+            Some(fixture)=>MutableStaticData::from_fixture(&self.env, fixture),
+        };
+        initial_data.recently_viewed = self.recently_viewed;
+        initial_data.max_page_size = self.max_page_size;
+        initial_data.history_length = self.history_length;
+        initial_data.events = Arc::new(EventBus::new(self.event_buffer));
+        initial_data.faults = self.faults;
+        initial_data.stubs = self.stubs;
+
+        let server_state:SharedState = Arc::new(
+            RwLock::new(
+                initial_data
+            )
+        );
+        let journal = Journal::shared(self.journal_size);
+
+        let app = Router::new()
+            .route("/collection", get(handlers::get_user_collections))
+            .route("/collection", post(handlers::create_collection))
+            .route("/collection/events", get(handlers::get_user_events))
+            .route("/collection/{collection_id}", patch(handlers::rename_collection))
+            .route("/collection/{collection_id}", delete(handlers::delete_collection))
+            .route("/collection/{collection_id}/contents", get(handlers::get_collection_content))
+            .route("/collection/{collection_id}/contents", put(handlers::put_to_collection))
+            .route("/collection/{collection_id}/contents", delete(handlers::delete_from_collection))
+            .route("/collection/{collection_id}/changes", get(handlers::get_collection_changes))
+            .route("/collection/{collection_id}/events", get(handlers::get_collection_events))
+            .route("/ws", get(handlers::ws::websocket))
+            .route("/openapi.json", get(handlers::openapi::serve_openapi))
+            .nest("/__admin", handlers::admin::router())
+            .route_layer(middleware::from_fn(journal::tag_matched_route))
+            .method_not_allowed_fallback(stubs::serve_stub_for_method)
+            .fallback(stubs::serve_stub);
+
+        let app = match self.upstream {
+            Some(upstream)=>app.layer(middleware::from_fn_with_state(upstream, proxy::forward_requests)),
+            None=>app,
+        };
+
+        let app = app.layer(middleware::from_fn(faults::inject_faults));
+
+        let app = if self.mock_headers {
+            app.layer(middleware::from_fn(faults::mock_header_faults))
+        } else {
+            app
+        };
+
+        let app = app.layer(middleware::from_fn_with_state(self.error_format, handlers::errors::render_errors));
+
+        let app = match self.contract {
+            Some(guard)=>app.layer(middleware::from_fn_with_state(ContractGuard{ errors: self.error_format, ..guard }, contract::check_contract)),
+            None=>app,
+        };
+
+        let app = app
+            .layer(middleware::from_fn(journal::record_requests))
+            .layer(middleware::from_fn(logging_middleware))
+            .layer(Extension(journal.clone()))
+            .layer(Extension(server_state.clone()));
+
+        (app, server_state, journal)
+    }
+
+    pub fn router(self) -> Router {
+        self.build().0
+    }
+
+    /// Serves the mock on an ephemeral port on 127.0.0.1, in the background
+    pub async fn start(self) -> io::Result<MockServer> {
+        let (app, state, journal) = self.assemble();
+        let events = state.read().await.events.clone();
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let addr = listener.local_addr()?;
+        let (shutdown, shutdown_requested) = oneshot::channel::<()>();
+
+        let task = tokio::spawn(async move {
+            axum::serve(listener, app)
+                .with_graceful_shutdown(async {
+                    let _ = shutdown_requested.await;
+                })
+                .await
+        });
+
+        Ok(MockServer{
+            addr,
+            state,
+            journal,
+            events,
+            shutdown: Some(shutdown),
+            task: Some(task),
+        })
+    }
+}
+
+/// A mock running in the background, for tests. It stops when dropped, letting requests in flight finish.
+pub struct MockServer {
+    addr: SocketAddr,
+    state: SharedState,
+    journal: SharedJournal,
+    /// Closed on shutdown, as open event streams would otherwise keep the server going
+    events: Arc<EventBus>,
+    shutdown: Option<oneshot::Sender<()>>,
+    task: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl MockServer {
+    pub fn builder() -> MockServerBuilder {
+        MockServerBuilder{
+            env: Environment::default(),
+            fixture: None,
+            recently_viewed: RecentlyViewedPolicy::default(),
+            max_page_size: fixture::DEFAULT_MAX_PAGE_SIZE,
+            history_length: fixture::history::DEFAULT_HISTORY_LENGTH,
+            event_buffer: events::DEFAULT_EVENT_BUFFER,
+            error_format: ErrorFormat::default(),
+            faults: FaultConfig::default(),
+            mock_headers: false,
+            journal_size: journal::DEFAULT_JOURNAL_SIZE,
+            stubs: Vec::new(),
+            upstream: None,
+            contract: None,
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// e.g. `http://127.0.0.1:54321`, without a trailing slash
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// For looking at or changing the data directly, rather than through /__admin
+    pub fn state(&self) -> &SharedState {
+        &self.state
+    }
+
+    /// The requests the server has had, as for /__admin/requests
+    pub fn journal(&self) -> &SharedJournal {
+        &self.journal
+    }
+
+    /// Stops the server and waits until it has finished
+    pub async fn shutdown(mut self) -> io::Result<()> {
+        self.events.close();
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        match self.task.take() {
+            Some(task)=>task.await.map_err(io::Error::other)?,
+            None=>Ok( () ),
+        }
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.events.close();
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use serde_json::Value;
+    use tokio::net::TcpStream;
+    use crate::fixture::{loader::FixtureCollection, models::CollectionKind};
+    use super::*;
+
+    #[tokio::test]
+    async fn test_start_and_drop() {
+        let server = MockServer::builder()
+            .env(Environment::CODE)
+            .fixture(FixtureFile{
+                collections: vec![FixtureCollection{
+                    id: "c1".into(),
+                    kind: CollectionKind::Saved,
+                    name: None,
+                    recipes: vec!["r1".into(), "r2".into(), "r3".into()],
+                    last_modified: None,
+                }],
+            })
+            .max_page_size(2)
+            .start()
+            .await
+            .unwrap();
+        let addr = server.addr();
+
+        let response = reqwest::get(format!("{}/collection/c1/contents?limit=2", server.url())).await.unwrap();
+        assert_eq!(response.status(), 200);
+        let page:Value = serde_json::from_slice(&response.bytes().await.unwrap()).unwrap();
+        assert_eq!(page["content"], serde_json::json!(["r1", "r2"]));
+        assert_eq!(page["total"], 3);
+
+        let too_big = reqwest::get(format!("{}/collection/c1/contents?limit=3", server.url())).await.unwrap();
+        assert_eq!(too_big.status(), 400);
+
+        assert_eq!(server.journal().lock().await.entries().count(), 2);
+
+        drop(server);
+        let mut closed = false;
+        for _ in 0..50 {
+            if TcpStream::connect(addr).await.is_err() {
+                closed = true;
+                break
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(closed, "the server should stop listening once dropped");
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let server = MockServer::builder().start().await.unwrap();
+        let url = server.url();
+
+        assert!(reqwest::get(format!("{}/collection", url)).await.unwrap().status().is_success());
+        server.shutdown().await.unwrap();
+        assert!(reqwest::get(format!("{}/collection", url)).await.is_err());
+    }
+}
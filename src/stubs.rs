@@ -0,0 +1,439 @@
+use std::{cmp::Reverse, collections::HashMap, path::Path, time::Duration};
+use axum::{body::{self, Body}, extract::{Query, Request}, http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode}, response::{IntoResponse, Response}, Extension};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::{config::{load_config, save_config, ConfigError, MAX_BODY_BYTES}, faults::path_matches, handlers::{errors::ApiError, SharedState}, journal::MatchedRoute};
+
+/// Stubs with a lower priority number are preferred, as in WireMock
+pub const DEFAULT_PRIORITY:i32 = 5;
+
+fn default_priority() -> i32 {
+    DEFAULT_PRIORITY
+}
+
+fn default_status() -> u16 {
+    200
+}
+
+/// A canned response for requests that none of the real routes handle, modelled on WireMock's stub mappings. That
+/// includes methods a real route doesn't handle, such as POST to /collection/{collection_id}/contents. For example:
+///
+/// ```json
+/// {
+///   "priority": 1,
+///   "request": {
+///     "method": "GET",
+///     "path": "/recipe/{recipe_id}/notes",
+///     "query": {"lang": {"equalTo": "en"}},
+///     "headers": {"Accept": {"contains": "json"}},
+///     "body": {"absent": true}
+///   },
+///   "response": {"status": 200, "headers": {"Cache-Control": "no-store"}, "jsonBody": {"notes": []}, "delayMs": 250}
+/// }
+/// ```
+///
+/// When several stubs match, the one with the lowest priority number wins, and of those the most recently added.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Stub {
+    /// Given out when the stub is created, if the stub doesn't bring its own
+    #[serde(default)]
+    pub id: String,
+    #[serde(default="default_priority")]
+    pub priority: i32,
+    pub request: StubRequest,
+    pub response: StubResponse,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct StubRequest {
+    /// Any method if absent
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub method: Option<String>,
+    /// A route pattern, where `{...}` and `*` each match any one path segment
+    pub path: String,
+    #[serde(default, skip_serializing_if="HashMap::is_empty")]
+    pub query: HashMap<String, ValueMatcher>,
+    /// Header names are case-insensitive
+    #[serde(default, skip_serializing_if="HashMap::is_empty")]
+    pub headers: HashMap<String, ValueMatcher>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub body: Option<ValueMatcher>,
+}
+
+/// A test on one query parameter, header or the body. Written as a single-key object, e.g. `{"contains": "json"}`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all="camelCase")]
+pub enum ValueMatcher {
+    EqualTo(String),
+    Contains(String),
+    /// A regular expression that must match the whole value
+    Matches(Pattern),
+    /// Parses the value as JSON and compares it, so formatting and key order don't matter
+    EqualToJson(Value),
+    /// `true` if the value must be missing, `false` if it must be there (with any value)
+    Absent(bool),
+}
+
+/// A regular expression, compiled as it's read so that a bad one is turned away straight away
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(try_from="String", into="String")]
+pub struct Pattern {
+    source: String,
+    /// Anchored at both ends, as the whole value has to match
+    regex: Regex,
+}
+
+impl Pattern {
+    pub fn new(source:&str) -> Result<Pattern, regex::Error> {
+        Ok(Pattern{
+            source: source.to_owned(),
+            regex: Regex::new(&format!("^(?:{})$", source))?,
+        })
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other:&Pattern) -> bool {
+        self.source==other.source
+    }
+}
+
+impl TryFrom<String> for Pattern {
+    type Error = String;
+
+    fn try_from(source:String) -> Result<Pattern, String> {
+        Pattern::new(&source).map_err(|e| format!("invalid regular expression: {}", e))
+    }
+}
+
+impl From<Pattern> for String {
+    fn from(pattern:Pattern) -> String {
+        pattern.source
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct StubResponse {
+    #[serde(default="default_status")]
+    pub status: u16,
+    #[serde(default, skip_serializing_if="HashMap::is_empty")]
+    pub headers: HashMap<String, String>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub body: Option<String>,
+    /// Sent as application/json, unless `headers` says otherwise
+    #[serde(rename="jsonBody", default, skip_serializing_if="Option::is_none")]
+    pub json_body: Option<Value>,
+    #[serde(rename="delayMs", default, skip_serializing_if="Option::is_none")]
+    pub delay_ms: Option<u64>,
+}
+
+/// A set of stubs on disk, in the same shape as the /__admin/stubs listing. Usually JSON, as that's what bodies are,
+/// but TOML or YAML will do as well, going by the file extension.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct StubFile {
+    pub stubs: Vec<Stub>,
+}
+
+impl StubFile {
+    /// Reads, parses and validates the stubs at the given path
+    pub fn load(path:&Path) -> Result<StubFile, ConfigError> {
+        let parsed:StubFile = load_config(path)?;
+        ConfigError::check(path, parsed.validate())?;
+        Ok(parsed)
+    }
+
+    pub fn save(&self, path:&Path) -> Result<(), ConfigError> {
+        save_config(path, self)
+    }
+
+    /// Checks each stub in turn, prefixing its problems with the stub's position
+    pub fn validate(&self) -> Vec<String> {
+        self.stubs.iter().enumerate()
+            .flat_map(|(idx, stub)| stub.validate().into_iter().map(move |problem| format!("stub #{}: {}", idx, problem)))
+            .collect()
+    }
+}
+
+impl ValueMatcher {
+    fn matches(&self, value:Option<&str>) -> bool {
+        match (self, value) {
+            (ValueMatcher::Absent(absent), value)=>*absent==value.is_none(),
+            (_, None)=>false,
+            (ValueMatcher::EqualTo(expected), Some(value))=>value==expected,
+            (ValueMatcher::Contains(expected), Some(value))=>value.contains(expected.as_str()),
+            (ValueMatcher::Matches(pattern), Some(value))=>pattern.regex.is_match(value),
+            (ValueMatcher::EqualToJson(expected), Some(value))=>serde_json::from_str::<Value>(value).is_ok_and(|actual| &actual==expected),
+        }
+    }
+}
+
+impl Stub {
+    /// Checks the request pattern, and that the response has a real status code, well-formed headers and at most one body
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = self.request.validate();
+
+        if StatusCode::from_u16(self.response.status).is_err() {
+            problems.push(format!("{} is not a valid status code", self.response.status));
+        }
+        for (name, value) in self.response.headers.iter() {
+            if HeaderName::from_bytes(name.as_bytes()).is_err() || HeaderValue::from_str(value).is_err() {
+                problems.push(format!("response header '{}: {}' is not valid", name, value));
+            }
+        }
+        if self.response.body.is_some() && self.response.json_body.is_some() {
+            problems.push("give either body or jsonBody, not both".into());
+        }
+
+        problems
+    }
+}
+
+impl StubRequest {
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems:Vec<String> = Vec::new();
+
+        if let Some(method) = &self.method {
+            if Method::from_bytes(method.to_ascii_uppercase().as_bytes()).is_err() {
+                problems.push(format!("'{}' is not an HTTP method", method));
+            }
+        }
+        if !self.path.starts_with('/') {
+            problems.push(format!("path '{}' must start with /", self.path));
+        }
+        for name in self.headers.keys() {
+            if HeaderName::from_bytes(name.as_bytes()).is_err() {
+                problems.push(format!("'{}' is not a valid header name", name));
+            }
+        }
+
+        problems
+    }
+
+    /// Also used to pick requests out of the journal
+    pub fn matches(&self, method:&Method, path:&str, query:&HashMap<String, String>, headers:&HeaderMap, body:&str) -> bool {
+        self.method.as_ref().is_none_or(|m| m.eq_ignore_ascii_case(method.as_str()))
+            && path_matches(&self.path, path)
+            && self.query.iter().all(|(name, matcher)| matcher.matches(query.get(name).map(|v| v.as_str())))
+            && self.headers.iter().all(|(name, matcher)| matcher.matches(headers.get(name.as_str()).and_then(|v| v.to_str().ok())))
+            && self.body.as_ref().is_none_or(|matcher| matcher.matches(Some(body).filter(|b| !b.is_empty())))
+    }
+}
+
+impl StubResponse {
+    async fn send(&self) -> Response {
+        if let Some(delay_ms) = self.delay_ms {
+            tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+        }
+
+        let mut response = match &self.json_body {
+            Some(json)=>(
+                [(header::CONTENT_TYPE, HeaderValue::from_static("application/json"))],
+                json.to_string()
+            ).into_response(),
+            None=>Response::new(Body::from(self.body.clone().unwrap_or_default())),
+        };
+
+        *response.status_mut() = StatusCode::from_u16(self.status).unwrap_or(StatusCode::OK);
+        for (name, value) in self.headers.iter() {
+            if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(value)) {
+                response.headers_mut().insert(name, value);
+            }
+        }
+
+        response
+    }
+}
+
+/// The stub that should answer a request, if any
+pub fn best_match<'a>(stubs:&'a [Stub], method:&Method, path:&str, query:&HashMap<String, String>, headers:&HeaderMap, body:&str) -> Option<&'a Stub> {
+    stubs.iter().enumerate()
+        .filter(|(_, stub)| stub.request.matches(method, path, query, headers, body))
+        .min_by_key(|(idx, stub)| (stub.priority, Reverse(*idx)))
+        .map(|(_, stub)| stub)
+}
+
+/// The fallback for requests no real route handles: answers from a stub if one matches, otherwise 404
+pub async fn serve_stub(
+    Extension(shared_state): Extension<SharedState>,
+    request: Request,
+) -> Response {
+    match stubbed_response(&shared_state, request).await {
+        Ok(Some(response))=>response,
+        Ok(None)=>ApiError::RouteNotFound.into_response(),
+        Err(e)=>e.into_response(),
+    }
+}
+
+/// The fallback for requests to a real route with a method it doesn't handle: answers from a stub if one
+/// matches, otherwise 405
+pub async fn serve_stub_for_method(
+    Extension(shared_state): Extension<SharedState>,
+    request: Request,
+) -> Response {
+    match stubbed_response(&shared_state, request).await {
+        Ok(Some(response))=>response,
+        Ok(None)=>ApiError::MethodNotAllowed.into_response(),
+        Err(e)=>e.into_response(),
+    }
+}
+
+async fn stubbed_response(shared_state:&SharedState, request:Request) -> Result<Option<Response>, ApiError> {
+    let (parts, body) = request.into_parts();
+    let Ok(body) = body::to_bytes(body, MAX_BODY_BYTES).await else {
+        return Err(ApiError::BadRequest(format!("request bodies must be under {} bytes", MAX_BODY_BYTES)))
+    };
+    let body = String::from_utf8_lossy(&body);
+    let query = Query::<HashMap<String, String>>::try_from_uri(&parts.uri).map(|Query(q)| q).unwrap_or_default();
+
+    let stub = {
+        let guarded_data = shared_state.read().await;
+        best_match(&guarded_data.stubs, &parts.method, parts.uri.path(), &query, &parts.headers, &body).cloned()
+    };
+
+    let Some(stub) = stub else {
+        return Ok(None)
+    };
+    log::debug!("{} {} answered by stub {}", parts.method, parts.uri, stub.id);
+    let mut response = stub.response.send().await;
+    response.extensions_mut().insert(MatchedRoute(format!("stub:{}", stub.id)));
+    Ok(Some(response))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use axum::Router;
+    use axum_test::TestServer;
+    use tokio::sync::RwLock;
+    use crate::fixture::{Environment, MutableStaticData};
+    use super::*;
+
+    fn stub(id:&str, priority:i32, path:&str, status:u16) -> Stub {
+        Stub{
+            id: id.into(),
+            priority,
+            request: StubRequest{
+                method: None,
+                path: path.into(),
+                query: HashMap::new(),
+                headers: HashMap::new(),
+                body: None,
+            },
+            response: StubResponse{
+                status,
+                headers: HashMap::new(),
+                body: None,
+                json_body: None,
+                delay_ms: None,
+            },
+        }
+    }
+
+    #[test]
+    fn test_value_matchers() {
+        assert!(ValueMatcher::EqualTo("en".into()).matches(Some("en")));
+        assert!(!ValueMatcher::EqualTo("en".into()).matches(None));
+        assert!(ValueMatcher::Contains("json".into()).matches(Some("application/json")));
+        assert!(ValueMatcher::Matches(Pattern::new("[0-9]+").unwrap()).matches(Some("123")));
+        assert!(!ValueMatcher::Matches(Pattern::new("[0-9]+").unwrap()).matches(Some("123abc")));
+        assert!(ValueMatcher::EqualToJson(serde_json::json!({"a": 1, "b": [2]})).matches(Some(r#"{ "b": [2], "a": 1 }"#)));
+        assert!(ValueMatcher::Absent(true).matches(None));
+        assert!(!ValueMatcher::Absent(true).matches(Some("")));
+        assert!(ValueMatcher::Absent(false).matches(Some("")));
+    }
+
+    #[test]
+    fn test_best_match() {
+        let mut with_query = stub("with-query", DEFAULT_PRIORITY, "/recipe/{recipe_id}", 201);
+        with_query.request.query.insert("lang".into(), ValueMatcher::EqualTo("en".into()));
+        let stubs = vec![
+            stub("older", DEFAULT_PRIORITY, "/recipe/*", 200),
+            stub("newer", DEFAULT_PRIORITY, "/recipe/*", 200),
+            with_query,
+            stub("urgent", 1, "/recipe/r1", 200),
+        ];
+
+        let no_query = HashMap::new();
+        let english:HashMap<String, String> = [("lang".to_string(), "en".to_string())].into();
+        let headers = HeaderMap::new();
+        let best = |path:&str, query:&HashMap<String, String>| best_match(&stubs, &Method::GET, path, query, &headers, "").map(|s| s.id.as_str());
+
+        assert_eq!(best("/recipe/r1", &no_query), Some("urgent"));
+        assert_eq!(best("/recipe/r2", &no_query), Some("newer"));
+        assert_eq!(best("/recipe/r2", &english), Some("with-query"));
+        assert_eq!(best("/recipes", &no_query), None);
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut bad = stub("bad", DEFAULT_PRIORITY, "recipe", 1000);
+        bad.response.body = Some("text".into());
+        bad.response.json_body = Some(Value::Null);
+
+        assert_eq!(bad.validate().len(), 3);
+        assert!(stub("good", 1, "/recipe", 204).validate().is_empty());
+
+        let bad_pattern = serde_json::json!({"path": "/recipe", "headers": {"Accept": {"matches": "("}}});
+        assert!(serde_json::from_value::<StubRequest>(bad_pattern).is_err());
+        let good_pattern = serde_json::json!({"path": "/recipe", "headers": {"Accept": {"matches": ".*json"}}});
+        let parsed:StubRequest = serde_json::from_value(good_pattern.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&parsed).unwrap(), good_pattern);
+    }
+
+    #[tokio::test]
+    async fn test_serve_stub() {
+        let mut notes = stub("notes", DEFAULT_PRIORITY, "/recipe/{recipe_id}/notes", 200);
+        notes.request.method = Some("POST".into());
+        notes.request.body = Some(ValueMatcher::EqualToJson(serde_json::json!({"text": "tasty"})));
+        notes.response.json_body = Some(serde_json::json!({"saved": true}));
+        notes.response.headers.insert("X-Stubbed".into(), "yes".into());
+
+        let state:SharedState = Arc::new(RwLock::new(MutableStaticData::new(&Environment::PROD)));
+        state.write().await.stubs.push(notes);
+
+        let fake_app = Router::new()
+            .fallback(serve_stub)
+            .layer(Extension(state));
+
+        let fake_server = TestServer::new(fake_app).unwrap();
+
+        let stubbed = fake_server.post("/recipe/r1/notes")
+            .json(&serde_json::json!({"text": "tasty"}))
+            .await;
+        stubbed.assert_status_ok();
+        stubbed.assert_json(&serde_json::json!({"saved": true}));
+        assert_eq!(stubbed.header("X-Stubbed"), "yes");
+
+        fake_server.post("/recipe/r1/notes")
+            .json(&serde_json::json!({"text": "bland"}))
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+        fake_server.get("/recipe/r1/notes").await.assert_status(StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_serve_stub_for_method() {
+        let mut import = stub("import", DEFAULT_PRIORITY, "/collection/{collection_id}/contents", 202);
+        import.request.method = Some("POST".into());
+
+        let state:SharedState = Arc::new(RwLock::new(MutableStaticData::new(&Environment::PROD)));
+        state.write().await.stubs.push(import);
+
+        let fake_app = Router::new()
+            .route("/collection/{collection_id}/contents", axum::routing::get(|| async { "real" }))
+            .method_not_allowed_fallback(serve_stub_for_method)
+            .fallback(serve_stub)
+            .layer(Extension(state));
+
+        let fake_server = TestServer::new(fake_app).unwrap();
+
+        fake_server.post("/collection/c1/contents").await.assert_status(StatusCode::ACCEPTED);
+        fake_server.get("/collection/c1/contents").await.assert_text("real");
+        fake_server.patch("/collection/c1/contents").await.assert_status(StatusCode::METHOD_NOT_ALLOWED);
+    }
+}